@@ -0,0 +1,174 @@
+//! 接続ごとにワーカーを使う方式と、イベントループ方式の負荷試験です。
+//!
+//! Load-tests the thread-per-connection server against the event loop
+//! server while many idle keep-alive connections are open, the situation
+//! where a few `/sleep`-like clients used to lock everyone else out.
+//!
+//! Each run first opens `LOAD_IDLE` connections that make one request and
+//! then stay idle, and then lets `LOAD_CLIENTS` clients make `LOAD_REQUESTS`
+//! requests each on fresh connections. Requests that are refused with 503 or
+//! not answered within a second count as failures.
+//!
+//! Run with `cargo bench --bench modes`.
+
+extern crate hello;
+
+use hello::log::{self, Level};
+use hello::response::Response;
+use hello::router::Router;
+use hello::server::{KeepAlive, Server};
+use hello::{QueuePolicy, ThreadPool};
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKERS: usize = 4;
+const QUEUE: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    ThreadPerConnection,
+    EventLoop,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::ThreadPerConnection => "thread/conn",
+            Mode::EventLoop => "event loop",
+        }
+    }
+}
+
+struct Measurement {
+    requests_per_sec: f64,
+    failures: usize,
+    p50: Duration,
+    p99: Duration,
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Makes one request with `Connection: close` and returns whether it was
+/// answered with 200.
+fn request(addr: SocketAddr) -> bool {
+    let Ok(mut stream) = TcpStream::connect(addr) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    if stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n")
+        .is_err()
+    {
+        return false;
+    }
+    let mut out = Vec::new();
+    stream.read_to_end(&mut out).is_ok() && out.starts_with(b"HTTP/1.1 200 OK\r\n")
+}
+
+/// Opens a connection, makes one keep-alive request on it and leaves it
+/// open.
+fn idle_connection(addr: SocketAddr) -> Option<TcpStream> {
+    let mut stream = TcpStream::connect(addr).ok()?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n")
+        .ok()?;
+    Some(stream)
+}
+
+fn measure(mode: Mode, idle: usize, clients: usize, requests: usize) -> Measurement {
+    let router = Router::new().get("/", |_, _| Response::new(200).with_body("hello"));
+    let pool = ThreadPool::bounded(WORKERS, QUEUE, QueuePolicy::Reject);
+    let mut server = Server::new(TcpListener::bind("127.0.0.1:0").unwrap(), pool, router)
+        .with_keep_alive(KeepAlive {
+            idle_timeout: Duration::from_secs(30),
+            max_requests: 100,
+        })
+        .with_shutdown_timeout(Duration::from_secs(1));
+    if let Mode::EventLoop = mode {
+        server = server.with_event_loop(2);
+    }
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle().unwrap();
+    let running = thread::spawn(move || server.run());
+
+    let idle: Vec<TcpStream> = (0..idle).filter_map(|_| idle_connection(addr)).collect();
+    // 待機中の接続がワーカーやキューに収まるのを待ちます
+    thread::sleep(Duration::from_millis(200));
+
+    let started = Instant::now();
+    let results: Vec<(Duration, bool)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..clients)
+            .map(|_| {
+                scope.spawn(move || {
+                    (0..requests)
+                        .map(|_| {
+                            let sent = Instant::now();
+                            let ok = request(addr);
+                            (sent.elapsed(), ok)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    let elapsed = started.elapsed();
+
+    handle.shutdown();
+    drop(idle);
+    let _ = running.join().unwrap();
+
+    let failures = results.iter().filter(|(_, ok)| !ok).count();
+    let mut latencies: Vec<Duration> = results.iter().map(|(latency, _)| *latency).collect();
+    latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let index = ((latencies.len() as f64 * p) as usize).min(latencies.len() - 1);
+        latencies[index]
+    };
+
+    Measurement {
+        requests_per_sec: (results.len() - failures) as f64 / elapsed.as_secs_f64(),
+        failures,
+        p50: percentile(0.50),
+        p99: percentile(0.99),
+    }
+}
+
+fn main() {
+    let idle = env_or("LOAD_IDLE", 200);
+    let clients = env_or("LOAD_CLIENTS", 8);
+    let requests = env_or("LOAD_REQUESTS", 200);
+    // アクセスログで結果が埋もれないようにします
+    log::set_max_level(Some(Level::Error));
+
+    println!(
+        "{} workers, {} idle connections, {} clients x {} requests",
+        WORKERS, idle, clients, requests
+    );
+    println!(
+        "{:<12} {:>12} {:>10} {:>12} {:>12}",
+        "mode", "requests/s", "failures", "p50", "p99"
+    );
+    for mode in [Mode::ThreadPerConnection, Mode::EventLoop] {
+        let m = measure(mode, idle, clients, requests);
+        println!(
+            "{:<12} {:>12.0} {:>10} {:>12?} {:>12?}",
+            mode.name(),
+            m.requests_per_sec,
+            m.failures,
+            m.p50,
+            m.p99
+        );
+    }
+}
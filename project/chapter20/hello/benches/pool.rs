@@ -0,0 +1,190 @@
+//! ThreadPoolのスループットと待ち時間を、以前の`Mutex<Receiver>`方式と比べます。
+//!
+//! Compares the work-stealing `ThreadPool` with the previous design, where
+//! every worker waited on one `Arc<Mutex<mpsc::Receiver>>`.
+//!
+//! Run with `cargo bench --bench pool`. Set `POOL_BENCH_JOBS` to change the
+//! number of jobs per run.
+
+extern crate hello;
+
+use hello::ThreadPool;
+use std::env;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKERS: [usize; 4] = [1, 4, 16, 64];
+
+trait Pool {
+    fn name() -> &'static str;
+    fn with_workers(size: usize) -> Self;
+    fn run<F: FnOnce() + Send + 'static>(&self, f: F);
+}
+
+impl Pool for ThreadPool {
+    fn name() -> &'static str {
+        "work-stealing"
+    }
+
+    fn with_workers(size: usize) -> ThreadPool {
+        ThreadPool::new(size)
+    }
+
+    fn run<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.execute(f);
+    }
+}
+
+/// 以前の実装をそのまま写したもの。
+///
+/// A copy of the pool as it was before the scheduler rewrite, without the
+/// logging so that printing does not dominate the measurement.
+mod legacy {
+    use super::*;
+
+    type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    enum Message {
+        NewJob(Job),
+        Terminate,
+    }
+
+    pub struct SharedReceiverPool {
+        workers: Vec<thread::JoinHandle<()>>,
+        sender: mpsc::Sender<Message>,
+    }
+
+    impl Pool for SharedReceiverPool {
+        fn name() -> &'static str {
+            "shared receiver"
+        }
+
+        fn with_workers(size: usize) -> SharedReceiverPool {
+            let (sender, receiver) = mpsc::channel();
+            let receiver = Arc::new(Mutex::new(receiver));
+            let workers = (0..size)
+                .map(|_| {
+                    let receiver = Arc::clone(&receiver);
+                    thread::spawn(move || {
+                        loop {
+                            let message = receiver.lock().unwrap().recv().unwrap();
+                            match message {
+                                Message::NewJob(job) => job(),
+                                Message::Terminate => break,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            SharedReceiverPool { workers, sender }
+        }
+
+        fn run<F: FnOnce() + Send + 'static>(&self, f: F) {
+            self.sender.send(Message::NewJob(Box::new(f))).unwrap();
+        }
+    }
+
+    impl Drop for SharedReceiverPool {
+        fn drop(&mut self) {
+            for _ in &self.workers {
+                self.sender.send(Message::Terminate).unwrap();
+            }
+            for worker in self.workers.drain(..) {
+                worker.join().unwrap();
+            }
+        }
+    }
+}
+
+struct Measurement {
+    jobs_per_sec: f64,
+    p50: Duration,
+    p99: Duration,
+    p999: Duration,
+}
+
+/// Submits `jobs` small jobs as fast as possible and measures how long the
+/// pool takes to finish them, and how long each job waited before starting.
+fn measure<P: Pool>(workers: usize, jobs: usize) -> Measurement {
+    let pool = P::with_workers(workers);
+    let origin = Instant::now();
+    let waits: Arc<Vec<AtomicU64>> = Arc::new((0..jobs).map(|_| AtomicU64::new(0)).collect());
+    let remaining = Arc::new(AtomicUsize::new(jobs));
+    let (done_sender, done) = mpsc::channel();
+    let done_sender = Arc::new(Mutex::new(done_sender));
+
+    let started = Instant::now();
+    for i in 0..jobs {
+        let waits = Arc::clone(&waits);
+        let remaining = Arc::clone(&remaining);
+        let done_sender = Arc::clone(&done_sender);
+        let submitted = origin.elapsed();
+
+        pool.run(move || {
+            let wait = origin.elapsed() - submitted;
+            waits[i].store(wait.as_nanos() as u64, Ordering::Relaxed);
+
+            // 少しだけ計算して、空のジョブにならないようにします
+            let mut x = i as u64;
+            for _ in 0..200 {
+                x = x.wrapping_mul(6364136223846793005).wrapping_add(1);
+            }
+            std::hint::black_box(x);
+
+            if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                done_sender.lock().unwrap().send(()).unwrap();
+            }
+        });
+    }
+    done.recv().unwrap();
+    let elapsed = started.elapsed();
+    drop(pool);
+
+    let mut waits: Vec<u64> = waits.iter().map(|w| w.load(Ordering::Relaxed)).collect();
+    waits.sort_unstable();
+    let percentile = |p: f64| {
+        let index = ((waits.len() as f64 * p) as usize).min(waits.len() - 1);
+        Duration::from_nanos(waits[index])
+    };
+
+    Measurement {
+        jobs_per_sec: jobs as f64 / elapsed.as_secs_f64(),
+        p50: percentile(0.50),
+        p99: percentile(0.99),
+        p999: percentile(0.999),
+    }
+}
+
+fn report<P: Pool>(workers: usize, jobs: usize) {
+    // 1回目はスレッドの起動などを含むので捨てます
+    measure::<P>(workers, jobs / 10);
+    let m = measure::<P>(workers, jobs);
+    println!(
+        "{:<16} {:>7} {:>14.0} {:>12?} {:>12?} {:>12?}",
+        P::name(),
+        workers,
+        m.jobs_per_sec,
+        m.p50,
+        m.p99,
+        m.p999
+    );
+}
+
+fn main() {
+    let jobs = env::var("POOL_BENCH_JOBS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(200_000);
+
+    println!(
+        "{:<16} {:>7} {:>14} {:>12} {:>12} {:>12}",
+        "pool", "workers", "jobs/sec", "wait p50", "wait p99", "wait p99.9"
+    );
+    for workers in WORKERS {
+        report::<legacy::SharedReceiverPool>(workers, jobs);
+        report::<ThreadPool>(workers, jobs);
+    }
+}
@@ -1,50 +1,180 @@
 extern crate hello;
-use hello::ThreadPool;
-use std::fs::File;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use hello::cgi::Cgi;
+use hello::config::{Args, Config, USAGE};
+use hello::log;
+use hello::pages::Pages;
+use hello::proxy::Proxy;
+use hello::router::Router;
+use hello::server::{ReloadHandle, Server};
+use hello::signal::{self, Signal};
+use hello::static_files::StaticFiles;
+use hello::tls::TlsConfig;
+use hello::{QueuePolicy, ThreadPoolBuilder, error, info, warn};
+use std::env;
+use std::fmt::Display;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::process;
 use std::thread;
-use std::time::Duration;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| exit(2, e));
+    if args.help {
+        print!("{}", USAGE);
+        return;
+    }
+    let config = Config::load(&args).unwrap_or_else(|e| exit(2, e));
+    log::set_max_level(config.log_level);
+
+    let router = routes(&config).unwrap_or_else(|e| exit(1, e));
+    let pool = ThreadPoolBuilder::new()
+        .min_workers(config.min_workers)
+        .max_workers(config.max_workers)
+        .thread_name_prefix("worker-")
+        .queue_capacity(config.queue_capacity)
+        .queue_policy(QueuePolicy::Reject)
+        .on_worker_start(|id| hello::debug!("Worker {} started.", id))
+        .on_worker_stop(|id| hello::debug!("Worker {} stopped.", id))
+        .build()
+        .unwrap_or_else(|e| exit(1, format!("Could not start the thread pool: {}", e)));
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    // 設定の検証で、待ち受けが1つ以上あることは確かめてあります
+    let mut listeners = config.listen.iter().map(|addr| bind(*addr));
+    let mut server = Server::new(listeners.next().unwrap(), pool, router)
+        .with_keep_alive(config.keep_alive)
+        .with_compression(config.compression)
+        .with_http2(config.http2)
+        .with_limits(config.limits)
+        .with_shutdown_timeout(config.shutdown_timeout);
+    for listener in listeners {
+        server = server.with_listener(listener);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(threads) = config.event_loop_threads {
+        server = server.with_event_loop(threads);
+    }
+    for addr in &config.listen {
+        info!("Serving HTTP on {}.", addr);
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    if let Some(settings) = &config.tls {
+        let tls = TlsConfig::from_pem_files(&settings.cert, &settings.key)
+            .unwrap_or_else(|e| exit(1, format!("Cannot load the TLS certificate: {}", e)));
+        for addr in &settings.listen {
+            server = server.with_tls_listener(bind(*addr), tls.clone());
+            info!("Serving HTTPS on {}.", addr);
+        }
     }
 
-    println!("Shutting down.");
+    // Ctrl-CかSIGTERMで受け付けを止め、SIGHUPで設定を読み直します
+    let handle = server
+        .shutdown_handle()
+        .unwrap_or_else(|e| exit(1, format!("Cannot set up shutdown: {}", e)));
+    let reload_handle = server.reload_handle();
+    let signals = signal::listen(&[Signal::Interrupt, Signal::Terminate, Signal::Hangup]);
+    thread::spawn(move || {
+        let mut config = config;
+        for signal in signals {
+            if signal == Signal::Hangup {
+                config = reload(&args, config, &reload_handle);
+                continue;
+            }
+            info!("Received {:?}; shutting down.", signal);
+            handle.shutdown();
+            break;
+        }
+    });
+
+    if let Err(e) = server.run() {
+        error!("Shutdown incomplete: {}", e);
+        process::exit(1);
+    }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+fn exit<E: Display, T>(code: i32, e: E) -> T {
+    error!("{}", e);
+    process::exit(code)
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+fn bind(addr: SocketAddr) -> TcpListener {
+    TcpListener::bind(addr).unwrap_or_else(|e| exit(1, format!("Cannot listen on {}: {}", addr, e)))
+}
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+/// 設定ファイルを読み直し、再起動せずに変えられる設定を反映します。
+///
+/// Applies what can change without a restart and returns the configuration
+/// now in effect. A configuration that fails to load is ignored.
+fn reload(args: &Args, current: Config, handle: &ReloadHandle) -> Config {
+    let new = match Config::load(args) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Keeping the current configuration: {}", e);
+            return current;
+        }
+    };
+    let router = match routes(&new) {
+        Ok(router) => router,
+        Err(e) => {
+            error!("Keeping the current configuration: {}", e);
+            return current;
+        }
     };
 
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
-
-    file.read_to_string(&mut contents).unwrap();
+    log::set_max_level(new.log_level);
+    handle.set_router(router);
+    handle.set_keep_alive(new.keep_alive);
+    handle.set_compression(new.compression);
+    handle.set_http2(new.http2);
+    handle.set_limits(new.limits);
+    let restart = current.needs_restart(&new);
+    if !restart.is_empty() {
+        warn!(
+            "Restart the server to apply changes to {}.",
+            restart.join(", ")
+        );
+    }
+    info!("Configuration reloaded.");
+    new
+}
 
-    let response = format!("{}{}", status_line, contents);
+/// 設定からルーターを組み立てます。
+///
+/// Builds the router for `config`: the proxy and CGI routes first, then the
+/// files under the document root, or the built-in pages without one.
+fn routes(config: &Config) -> io::Result<Router> {
+    // 転送するルートとCGIのルートは、ファイルより先に照合します
+    let mut router = Router::new();
+    for settings in &config.proxies {
+        let mut proxy = Proxy::new(settings.upstreams.iter().copied());
+        if let Some(timeout) = settings.timeout {
+            proxy = proxy.with_timeout(timeout);
+        }
+        if let Some((path, interval)) = &settings.health_check {
+            proxy = proxy.with_health_check(path, *interval);
+        }
+        router = router.any(&settings.route, proxy.handler());
+    }
+    for settings in &config.cgi {
+        let mut cgi = Cgi::new(&settings.script);
+        if let Some(timeout) = settings.timeout {
+            cgi = cgi.with_timeout(timeout);
+        }
+        // 末尾のワイルドカードが、スクリプトのPATH_INFOになります
+        if let Some(param) = settings
+            .route
+            .rsplit('/')
+            .next()
+            .and_then(|s| s.strip_prefix('*'))
+        {
+            cgi = cgi.with_path_info(param);
+        }
+        router = router.any(&settings.route, cgi.handler());
+    }
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let Some(root) = &config.document_root else {
+        return Ok(Pages::new(".").routes(router));
+    };
+    let files = StaticFiles::new(root)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot serve {}: {}", root.display(), e)))?;
+    Ok(router.get("/*path", files.with_listing(true).handler("path")))
 }
@@ -0,0 +1,575 @@
+//! ローカルのスクリプトを実行して応答するCGIハンドラー。
+//!
+//! Runs local scripts to answer requests, following CGI/1.1 (RFC 3875).
+
+use crate::SERVER_SOFTWARE;
+use crate::proxy;
+use crate::request::{Headers, Request};
+use crate::response::Response;
+use crate::router::Params;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// スクリプトが終わったかどうかを確かめる間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// スクリプトを実行して応答するハンドラー。
+///
+/// リクエストの情報は環境変数で、本文は標準入力で渡し、標準出力から
+/// ヘッダーと本文を読み取ります。
+///
+/// Answers requests by running a script, which gets the request's metadata
+/// in the CGI environment variables and its body on stdin, and writes
+/// header lines, a blank line and the body to stdout.
+///
+/// The script runs in its own directory with a clean environment holding
+/// only `PATH`, the CGI variables and any added with `with_env`. Its stderr
+/// is logged. Headers other than the CGI `Status` field are sent to the
+/// client as they are; a `Location` without a `Status` redirects with 302.
+///
+/// The handler runs on the worker handling the request and waits for the
+/// script to exit, reading its whole output before answering. A script that
+/// cannot be started gets 500; one that writes an invalid response or more
+/// than the output limit gets 502, and one that runs past the timeout is
+/// killed and gets 504.
+///
+/// ```no_run
+/// use hello::cgi::Cgi;
+/// use hello::router::Router;
+/// use std::time::Duration;
+///
+/// let wiki = Cgi::new("cgi-bin/wiki.py")
+///     .with_timeout(Duration::from_secs(5))
+///     .with_path_info("page");
+/// let router = Router::new().any("/wiki/*page", wiki.handler());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    script: PathBuf,
+    timeout: Duration,
+    max_output: usize,
+    path_info: Option<String>,
+    env: Vec<(String, String)>,
+}
+
+impl Cgi {
+    pub fn new<P: Into<PathBuf>>(script: P) -> Cgi {
+        Cgi {
+            script: script.into(),
+            timeout: Duration::from_secs(30),
+            max_output: 16 * 1024 * 1024,
+            path_info: None,
+            env: Vec::new(),
+        }
+    }
+
+    /// How long the script may run; 30 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most the script may write to stdout; 16 MiB by default.
+    pub fn with_max_output(mut self, bytes: usize) -> Cgi {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Takes `PATH_INFO` from the route parameter `param`, e.g. `page` for
+    /// the route `/wiki/*page`. The rest of the path is the `SCRIPT_NAME`.
+    pub fn with_path_info(mut self, param: &str) -> Cgi {
+        self.path_info = Some(param.to_string());
+        self
+    }
+
+    /// Sets an extra environment variable for the script.
+    pub fn with_env(mut self, name: &str, value: &str) -> Cgi {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn handler(self) -> impl Fn(&Request, &Params) -> Response + Send + Sync + 'static {
+        move |request, params| {
+            let path_info = match &self.path_info {
+                Some(param) => params.get(param).unwrap_or(""),
+                None => "",
+            };
+            self.run(request, path_info)
+        }
+    }
+
+    /// Runs the script for `request`. `path_info` is the part of the path
+    /// after the script's own, already percent-decoded.
+    pub fn run(&self, request: &Request, path_info: &str) -> Response {
+        let started = Instant::now();
+        let (output, status) = match self.execute(request, path_info) {
+            Ok(result) => result,
+            Err(Failure::Spawn(e)) => {
+                error!("Cannot run {}: {}", self.script.display(), e);
+                return Response::new(500).with_body("Internal Server Error");
+            }
+            Err(Failure::Timeout) => {
+                warn!(
+                    "{} ran longer than {:?}; killed it.",
+                    self.script.display(),
+                    self.timeout
+                );
+                return Response::new(504).with_body("Gateway Timeout");
+            }
+            Err(Failure::TooLarge) => {
+                warn!(
+                    "{} wrote more than {} bytes; killed it.",
+                    self.script.display(),
+                    self.max_output
+                );
+                return Response::new(502).with_body("Bad Gateway");
+            }
+        };
+        debug!(
+            "{} exited with {} after {:?}.",
+            self.script.display(),
+            status,
+            started.elapsed()
+        );
+
+        match parse_output(&output) {
+            Ok(response) => response,
+            Err(reason) => {
+                warn!(
+                    "{} sent an invalid response ({}); it exited with {}.",
+                    self.script.display(),
+                    reason,
+                    status
+                );
+                Response::new(502).with_body("Bad Gateway")
+            }
+        }
+    }
+
+    /// Runs the script and returns what it wrote to stdout.
+    fn execute(
+        &self,
+        request: &Request,
+        path_info: &str,
+    ) -> Result<(Vec<u8>, ExitStatus), Failure> {
+        // スクリプトのディレクトリで動かすので、相対パスは先に今の場所から解決します
+        let script = env::current_dir()
+            .map_err(Failure::Spawn)?
+            .join(&self.script);
+        let mut command = Command::new(&script);
+        // 止めるときに孫のプロセスも一緒に止められるよう、グループを分けます
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command
+            .env_clear()
+            .envs(env::var_os("PATH").map(|path| (OsString::from("PATH"), path)))
+            .envs(variables(request, path_info))
+            .envs(self.env.iter().map(|(n, v)| (n, v)))
+            .current_dir(script.parent().unwrap_or(Path::new("/")))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Failure::Spawn)?;
+
+        // 出力が大きいとスクリプトが入力を読まずに止まるので、別々のスレッドで読み書きします
+        let stdin = child.stdin.take().map(|mut stdin| {
+            let body = request.body.clone();
+            thread::spawn(move || {
+                // 本文を読まずに終わるスクリプトもあるので、失敗は気にしません
+                let _ = stdin.write_all(&body);
+            })
+        });
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let stdout = child.stdout.take().map(|stdout| {
+            let limit = self.max_output;
+            let overflowed = Arc::clone(&overflowed);
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                let read = stdout.take(limit as u64 + 1).read_to_end(&mut output);
+                if output.len() > limit {
+                    overflowed.store(true, Ordering::SeqCst);
+                }
+                read.map(|_| output)
+            })
+        });
+        let stderr = child.stderr.take().map(|stderr| {
+            let script = self.script.display().to_string();
+            thread::spawn(move || log_stderr(&script, stderr))
+        });
+
+        // 孫のプロセスがパイプを開いたままのこともあるので、どれも閉じるまで待ちます
+        let deadline = Instant::now() + self.timeout;
+        let mut status = None;
+        let failure = loop {
+            match child.try_wait() {
+                Ok(exited) => status = status.or(exited),
+                Err(e) => break Some(Failure::Spawn(e)),
+            }
+            if overflowed.load(Ordering::SeqCst) {
+                break Some(Failure::TooLarge);
+            }
+            let finished = stdin.as_ref().is_none_or(|t| t.is_finished())
+                && stdout.as_ref().is_none_or(|t| t.is_finished())
+                && stderr.as_ref().is_none_or(|t| t.is_finished());
+            if status.is_some() && finished {
+                break None;
+            }
+            if Instant::now() >= deadline {
+                break Some(Failure::Timeout);
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        // 止めるとパイプが閉じるので、読み書きするスレッドも終わります
+        if failure.is_some() {
+            kill(&mut child);
+        }
+
+        if let Some(stdin) = stdin {
+            let _ = stdin.join();
+        }
+        let output = match stdout.map(thread::JoinHandle::join) {
+            Some(Ok(Ok(output))) => output,
+            _ => Vec::new(),
+        };
+        if let Some(stderr) = stderr {
+            let _ = stderr.join();
+        }
+        match (failure, status) {
+            (Some(failure), _) => Err(failure),
+            _ if overflowed.load(Ordering::SeqCst) => Err(Failure::TooLarge),
+            (None, Some(status)) => Ok((output, status)),
+            (None, None) => unreachable!("the loop ends once the script has exited"),
+        }
+    }
+}
+
+enum Failure {
+    Spawn(io::Error),
+    Timeout,
+    TooLarge,
+}
+
+/// Kills the script and the processes it started.
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    let killed = match unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    #[cfg(not(unix))]
+    let killed = child.kill();
+    if let Err(e) = killed {
+        debug!("Cannot kill a CGI script: {}", e);
+    }
+    let _ = child.wait();
+}
+
+/// Logs each line the script writes to stderr.
+fn log_stderr<R: Read>(script: &str, stderr: R) {
+    let mut lines = io::BufReader::new(stderr);
+    let mut line = Vec::new();
+    while let Ok(1..) = io::BufRead::read_until(&mut lines, b'\n', &mut line) {
+        warn!("{}: {}", script, String::from_utf8_lossy(&line).trim_end());
+        line.clear();
+    }
+}
+
+/// CGIの環境変数 (RFC 3875 4.1)。
+///
+/// The CGI meta-variables for `request`.
+fn variables(request: &Request, path_info: &str) -> Vec<(String, String)> {
+    let path = request.path();
+    let script_name = if path_info.is_empty() {
+        path.to_string()
+    } else {
+        // PATH_INFOはデコード済みなので、パスの区切りの数で切り分けます
+        let depth = path_info.split('/').filter(|s| !s.is_empty()).count();
+        let mut parts: Vec<&str> = path.split('/').collect();
+        parts.truncate(parts.len().saturating_sub(depth));
+        parts.join("/")
+    };
+    let host = request.headers.get("host").unwrap_or("localhost");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => (name, port),
+        _ => (host, "80"),
+    };
+
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+        ("SERVER_PROTOCOL", request.version.as_str().to_string()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.as_str().to_string()),
+        ("REQUEST_URI", request.target.clone()),
+        ("SCRIPT_NAME", script_name),
+        ("QUERY_STRING", request.query().unwrap_or("").to_string()),
+    ];
+    if !path_info.is_empty() {
+        vars.push(("PATH_INFO", format!("/{}", path_info)));
+    }
+    if let Some(peer) = request.peer {
+        vars.push(("REMOTE_ADDR", peer.ip().to_string()));
+        vars.push(("REMOTE_PORT", peer.port().to_string()));
+    }
+    if !request.body.is_empty() || request.headers.contains("content-length") {
+        vars.push(("CONTENT_LENGTH", request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.headers.get("content-type") {
+        vars.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for (name, value) in request.headers.iter() {
+        let name = name.to_ascii_lowercase();
+        // 資格情報はスクリプトに渡さず、ProxyはHTTP_PROXYとして悪用されるので渡しません
+        if matches!(
+            name.as_str(),
+            "content-length" | "content-type" | "authorization" | "proxy"
+        ) {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match vars.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => vars.push((name, value.to_string())),
+        }
+    }
+    vars
+}
+
+/// スクリプトの出力をレスポンスにします (RFC 3875 6)。
+///
+/// Turns the script's output into a response.
+fn parse_output(output: &[u8]) -> Result<Response, &'static str> {
+    // 行の終わりはLFだけのこともあります
+    let (head, body) = match (find(output, b"\r\n\r\n"), find(output, b"\n\n")) {
+        (Some(crlf), Some(lf)) if lf < crlf => (&output[..lf], &output[lf + 2..]),
+        (Some(crlf), _) => (&output[..crlf], &output[crlf + 4..]),
+        (None, Some(lf)) => (&output[..lf], &output[lf + 2..]),
+        (None, None) => return Err("no blank line after the headers"),
+    };
+    let head = std::str::from_utf8(head).map_err(|_| "headers are not UTF-8")?;
+
+    let mut status = None;
+    let mut headers = Headers::new();
+    for line in head.split('\n') {
+        let line = line.trim_end_matches('\r');
+        let (name, value) = line.split_once(':').ok_or("a header line has no colon")?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() || name.contains(' ') {
+            return Err("invalid header name");
+        }
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split(' ').next().unwrap_or("");
+            status = match code.parse() {
+                Ok(status @ 100..=599) if code.len() == 3 => Some(status),
+                _ => return Err("invalid Status"),
+            };
+        } else {
+            headers.append(name, value);
+        }
+    }
+
+    // 本文の区切り方はスクリプトに任せず、Responseが決めます
+    let mut response = Response::new(200);
+    let listed = proxy::connection_options(&headers);
+    for (name, value) in headers.iter() {
+        if !proxy::is_hop_by_hop(name, &listed) && !name.eq_ignore_ascii_case("content-length") {
+            response.headers.append(name, value);
+        }
+    }
+
+    response.status = match status {
+        Some(status) => status,
+        None if response.headers.contains("location") => 302,
+        None => 200,
+    };
+    Ok(response.with_body(body.to_vec()))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::request::{Headers, Method, Version};
+    use crate::testing::TempDir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes an executable shell script into a fresh directory, which is
+    /// removed when the returned `TempDir` is dropped.
+    fn script(name: &str, body: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new();
+        let path = dir.path().join(format!("{}.sh", name));
+        fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        (dir, path)
+    }
+
+    fn request(method: Method, target: &str, body: &[u8]) -> Request {
+        let mut headers = Headers::new();
+        headers.append("Host", "example.com:8080");
+        headers.append("User-Agent", "test");
+        headers.append("Authorization", "Basic c2VjcmV0");
+        if !body.is_empty() {
+            headers.append("Content-Type", "text/plain");
+            headers.append("Content-Length", &body.len().to_string());
+        }
+        Request {
+            method,
+            target: target.to_string(),
+            version: Version::Http11,
+            headers,
+            body: body.to_vec(),
+            peer: Some("192.0.2.7:4321".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn passes_the_request_to_the_script() {
+        let (_dir, path) = script(
+            "env",
+            r#"printf 'Content-Type: text/plain\r\nX-Script: yes\r\n\r\n'
+echo "$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING"
+echo "$SERVER_NAME $SERVER_PORT $REMOTE_ADDR $CONTENT_LENGTH $CONTENT_TYPE"
+echo "$HTTP_USER_AGENT [$HTTP_AUTHORIZATION] $GATEWAY_INTERFACE $GREETING"
+basename "$(pwd)"
+cat
+"#,
+        );
+        let cgi = Cgi::new(&path).with_env("GREETING", "hi");
+        let response = cgi.run(
+            &request(Method::Post, "/wiki/Main/Page?edit=1", b"body text"),
+            "Main/Page",
+        );
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("content-type"), Some("text/plain"));
+        assert_eq!(response.headers.get("x-script"), Some("yes"));
+        let dir = path
+            .parent()
+            .unwrap()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let expected = format!(
+            "POST /wiki /Main/Page edit=1\n\
+             example.com 8080 192.0.2.7 9 text/plain\n\
+             test [] CGI/1.1 hi\n\
+             {}\n\
+             body text",
+            dir
+        );
+        assert_eq!(response.body.into_bytes().unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn uses_status_and_location_headers() {
+        let (_dir, path) = script(
+            "status",
+            "printf 'Status: 404 Not Here\\nContent-Type: text/plain\\n\\ngone'",
+        );
+        let response = Cgi::new(path).run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 404);
+        assert!(!response.headers.contains("status"));
+        assert_eq!(response.body, b"gone");
+
+        let (_dir, path) = script("redirect", "printf 'Location: /elsewhere\\r\\n\\r\\n'");
+        let response = Cgi::new(path).run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 302);
+        assert_eq!(response.headers.get("location"), Some("/elsewhere"));
+    }
+
+    #[test]
+    fn answers_errors_for_failing_scripts() {
+        let missing = Cgi::new("/no/such/script").run(&request(Method::Get, "/", b""), "");
+        assert_eq!(missing.status, 500);
+
+        let (_dir, path) = script("garbage", "echo 'no headers here'");
+        let response = Cgi::new(path).run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 502);
+
+        let (_dir, path) = script("chatty", "printf 'Content-Type: text/plain\\n\\n'; yes");
+        let response = Cgi::new(path)
+            .with_max_output(1024)
+            .run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 502);
+    }
+
+    #[test]
+    fn kills_scripts_that_time_out() {
+        let (_dir, path) = script(
+            "slow",
+            "sleep 5; printf 'Content-Type: text/plain\\n\\nlate'",
+        );
+        let started = Instant::now();
+        let response = Cgi::new(path)
+            .with_timeout(Duration::from_millis(200))
+            .run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 終わったスクリプトの子が標準出力を開いたままでも、期限で諦めます
+        let (_dir, path) = script(
+            "orphan",
+            "sleep 30 & printf 'Content-Type: text/plain\\n\\nearly'",
+        );
+        let started = Instant::now();
+        let response = Cgi::new(path)
+            .with_timeout(Duration::from_millis(200))
+            .run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // 標準エラー出力と標準入力だけを開いたままの子も同じです
+        let (_dir, path) = script(
+            "quiet_orphan",
+            "sleep 30 >/dev/null & printf 'Content-Type: text/plain\\n\\nearly'",
+        );
+        let started = Instant::now();
+        let response = Cgi::new(path)
+            .with_timeout(Duration::from_millis(200))
+            .run(&request(Method::Get, "/", b""), "");
+        assert_eq!(response.status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn parses_output() {
+        let response = parse_output(b"Content-Type: text/html\n\n<p>hi</p>").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"<p>hi</p>");
+        assert!(parse_output(b"Status: abc\n\n").is_err());
+        assert!(parse_output(b"Status: 2000\n\n").is_err());
+        assert!(parse_output(b"Bad Header: x\n\n").is_err());
+        assert!(parse_output(b"Content-Type: text/html").is_err());
+
+        let response = parse_output(
+            b"Content-Length: 99\nTransfer-Encoding: chunked\nConnection: close, X-Hop\n\
+              X-Hop: 1\nX-Kept: 2\n\nhi",
+        )
+        .unwrap();
+        let names: Vec<&str> = response.headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["X-Kept"]);
+        assert_eq!(response.body.len(), Some(2));
+    }
+}
@@ -0,0 +1,314 @@
+//! `Accept-Encoding`に応じたレスポンスの圧縮。
+//!
+//! Compresses responses with gzip or deflate, as negotiated from the
+//! request's `Accept-Encoding` header.
+
+use crate::response::{Body, Chunks, Response};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Write};
+
+/// これより短い本文は、圧縮しても得になりにくいのでそのまま送ります。
+///
+/// Bodies shorter than this are sent as they are.
+pub const MIN_LENGTH: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// `Accept-Encoding`から使う圧縮方式を選びます。
+///
+/// Picks the encoding the client prefers by quality value, gzip on a tie.
+/// Returns `None` if the client accepts neither.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let mut quality = 1.0;
+        for param in parts {
+            if let Some((key, value)) = param.split_once('=')
+                && key.trim().eq_ignore_ascii_case("q")
+            {
+                quality = value.trim().parse().unwrap_or(0.0);
+            }
+        }
+
+        let encoding = match name.as_str() {
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            "*" => {
+                wildcard = Some(quality);
+                continue;
+            }
+            _ => continue,
+        };
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+
+    match (best, wildcard) {
+        (Some((encoding, _)), _) => Some(encoding),
+        // `*`は、名前を挙げていない方式をすべて受け付けるという意味です
+        (None, Some(q)) if q > 0.0 => [Encoding::Gzip, Encoding::Deflate]
+            .into_iter()
+            .find(|e| !mentions(accept_encoding, e.as_str())),
+        _ => None,
+    }
+}
+
+fn mentions(accept_encoding: &str, name: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        item.split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .eq_ignore_ascii_case(name)
+    })
+}
+
+/// Whether a body of this `Content-Type` is worth compressing. Images,
+/// audio, video and archives are usually compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime,
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+/// レスポンスを、クライアントが受け付ける方式で圧縮します。
+///
+/// Compresses `response` if the client accepts gzip or deflate and the body
+/// is worth compressing. A body in memory is compressed at once; any other
+/// body is compressed as it is written and sent chunked.
+///
+/// Strong `ETag`s are made weak, since the bytes sent differ from the
+/// identity encoding, and `Vary: Accept-Encoding` is added.
+pub fn compress(response: &mut Response, accept_encoding: &str) {
+    let compressible = response
+        .headers
+        .get("content-type")
+        .is_some_and(is_compressible);
+    if !compressible
+        || response.status == 206
+        || response.headers.contains("content-encoding")
+        || response.body.len().is_some_and(|len| len < MIN_LENGTH)
+    {
+        return;
+    }
+    response.headers.insert("Vary", "Accept-Encoding");
+
+    let encoding = match negotiate(accept_encoding) {
+        Some(encoding) => encoding,
+        None => return,
+    };
+
+    let body = std::mem::replace(&mut response.body, Body::empty());
+    response.body = match body {
+        Body::Bytes(bytes) => {
+            let mut encoder = Encoder::new(encoding);
+            let compressed = encoder
+                .write(&bytes)
+                .and_then(|mut out| {
+                    out.extend(encoder.finish()?);
+                    Ok(out)
+                })
+                .expect("compressing into memory does not fail");
+            Body::Bytes(compressed)
+        }
+        body => Body::Chunked(Box::new(Encode {
+            chunks: body.into_chunks(),
+            encoder: Some(Encoder::new(encoding)),
+        })),
+    };
+
+    response
+        .headers
+        .insert("Content-Encoding", encoding.as_str());
+    response.headers.remove("content-length");
+    // 範囲は圧縮前のバイトを指すので、圧縮した本文では受け付けません
+    response.headers.remove("accept-ranges");
+    if let Some(etag) = response.headers.get("etag")
+        && !etag.starts_with("W/")
+    {
+        let weak = format!("W/{}", etag);
+        response.headers.insert("ETag", &weak);
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    /// Compresses `data` and returns the output produced so far.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Encoder::Deflate(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+/// Compresses a body chunk by chunk while it is written.
+struct Encode {
+    chunks: Chunks,
+    encoder: Option<Encoder>,
+}
+
+impl Iterator for Encode {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        loop {
+            let encoder = self.encoder.as_mut()?;
+            match self.chunks.next() {
+                Some(Ok(chunk)) => match encoder.write(&chunk) {
+                    // 圧縮器が出力をためている間は、次の塊を読みます
+                    Ok(out) if out.is_empty() => continue,
+                    result => return Some(result),
+                },
+                Some(Err(e)) => {
+                    self.encoder = None;
+                    return Some(Err(e));
+                }
+                None => return self.encoder.take().map(Encoder::finish),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn text(len: usize) -> String {
+        "hello, compression! ".repeat(len / 20 + 1)[..len].to_string()
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(
+            negotiate("gzip;q=0, deflate;q=0.1"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("br, *"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0, *"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn compresses_bytes_in_memory() {
+        let body = text(1000);
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_header("ETag", "\"abc\"")
+            .with_body(body.clone());
+        compress(&mut response, "gzip");
+
+        assert_eq!(response.headers.get("content-encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("etag"), Some("W/\"abc\""));
+        let compressed = response.body.as_bytes().unwrap();
+        assert!(compressed.len() < 200);
+
+        let mut decoded = String::new();
+        GzDecoder::new(compressed)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn compresses_streams_as_chunked() {
+        let body = text(50_000);
+        let chunks: Vec<io::Result<Vec<u8>>> = body
+            .as_bytes()
+            .chunks(1000)
+            .map(|c| Ok(c.to_vec()))
+            .collect();
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(Body::stream(chunks.into_iter(), body.len() as u64));
+        compress(&mut response, "deflate");
+
+        assert!(response.body.is_chunked());
+        let compressed = std::mem::replace(&mut response.body, Body::empty())
+            .into_bytes()
+            .unwrap();
+        let mut decoded = String::new();
+        ZlibDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn leaves_small_or_binary_bodies_alone() {
+        let mut small = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body("tiny");
+        compress(&mut small, "gzip");
+        assert!(!small.headers.contains("content-encoding"));
+
+        let mut image = Response::new(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(vec![0; 1000]);
+        compress(&mut image, "gzip");
+        assert!(!image.headers.contains("content-encoding"));
+        assert!(!image.headers.contains("vary"));
+    }
+}
@@ -0,0 +1,882 @@
+//! `hello`の設定。
+//!
+//! The configuration of the `hello` binary, read from an optional TOML file
+//! and the command line. Command-line options override the file, and the
+//! file overrides the defaults.
+//!
+//! ```toml
+//! listen = ["127.0.0.1:7878", "[::1]:7878"]
+//! document_root = "public"
+//! log_level = "info"
+//! compression = true
+//! http2 = true
+//!
+//! [workers]
+//! min = 4
+//! max = 16
+//! queue = 64
+//!
+//! [timeouts]
+//! keep_alive = 5
+//! shutdown = 10
+//! header = 10
+//! body = 30
+//! write = 30
+//!
+//! [limits]
+//! max_header_bytes = 8192
+//! max_body_bytes = 1048576
+//! max_connections_per_ip = 32
+//!
+//! [tls]
+//! listen = ["127.0.0.1:7879"]
+//! cert = "cert.pem"
+//! key = "key.pem"
+//!
+//! [[proxy]]
+//! route = "/api/*rest"
+//! upstreams = ["127.0.0.1:9000", "127.0.0.1:9001"]
+//! timeout = 30
+//! health_check = "/health"
+//! health_interval = 10
+//!
+//! [[cgi]]
+//! route = "/wiki/*page"
+//! script = "cgi-bin/wiki.py"
+//! timeout = 5
+//! ```
+//!
+//! Relative paths in the file are relative to the file's directory.
+
+use crate::log::Level;
+use crate::server::{ConnectionLimits, KeepAlive};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// `--help`で表示する説明。
+pub const USAGE: &str = "\
+Usage: hello [OPTIONS] [DOCUMENT_ROOT]
+
+Serves the files under DOCUMENT_ROOT, or the built-in pages without one.
+
+Options:
+  -c, --config FILE      read settings from a TOML file (reloaded on SIGHUP)
+  -l, --listen ADDR      accept connections on ADDR, e.g. 0.0.0.0:8080 or
+                         [::]:8080; repeat for several addresses
+  -r, --root DIR         serve the files under DIR, like DOCUMENT_ROOT
+  -w, --workers N        keep at least N worker threads
+      --max-workers N    start at most N worker threads
+      --queue N          queue at most N connections for the workers
+      --keep-alive SECS  close idle connections after SECS seconds
+      --max-connections-per-ip N
+                         accept at most N connections from one client
+      --log-level LEVEL  error, warn, info, debug or off
+      --no-compress      do not compress responses with gzip or deflate
+      --http2            also accept cleartext HTTP/2 (h2c) connections
+      --event-loop N     serve plain HTTP from N epoll event loops (Linux)
+  -h, --help             print this help
+";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+const DEFAULT_MIN_WORKERS: usize = 4;
+const DEFAULT_MAX_WORKERS: usize = 16;
+const DEFAULT_QUEUE: usize = 64;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 設定を読み込めなかった理由。
+///
+/// Why the configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(PathBuf, io::Error),
+    /// The configuration file is not valid TOML, or has unknown or
+    /// mistyped keys.
+    Parse(PathBuf, toml::de::Error),
+    /// A command-line argument was unknown, missing its value or malformed.
+    Usage(String),
+    /// A setting has a value the server cannot use.
+    Invalid {
+        field: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config in {}: {}", path.display(), e),
+            ConfigError::Usage(message) => write!(f, "{} (see --help)", message),
+            ConfigError::Invalid { field, message } => write!(f, "invalid {}: {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(field: &'static str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        message: message.into(),
+    }
+}
+
+/// 検証済みの設定。
+///
+/// A complete, validated configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Addresses to accept plain HTTP connections on.
+    pub listen: Vec<SocketAddr>,
+    /// Serve the files under this directory instead of the built-in pages.
+    pub document_root: Option<PathBuf>,
+    /// `None` turns logging off.
+    pub log_level: Option<Level>,
+    /// Compress responses when clients accept it. On unless turned off,
+    /// unlike `Server::with_compression`, since the binary serves pages
+    /// that compress well.
+    pub compression: bool,
+    /// Accept cleartext HTTP/2 from clients that start with its preface.
+    /// Off unless turned on, like `Server::with_http2`.
+    pub http2: bool,
+    /// Serve plain HTTP from this many event loops instead of a worker per
+    /// connection.
+    pub event_loop_threads: Option<usize>,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    pub queue_capacity: usize,
+    pub keep_alive: KeepAlive,
+    pub shutdown_timeout: Duration,
+    pub limits: ConnectionLimits,
+    pub tls: Option<TlsSettings>,
+    /// Routes forwarded to upstream servers, matched before the files or
+    /// built-in pages.
+    pub proxies: Vec<ProxySettings>,
+    /// Routes answered by CGI scripts, matched after the proxies.
+    pub cgi: Vec<CgiSettings>,
+}
+
+/// HTTPSの待ち受けの設定。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub listen: Vec<SocketAddr>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// 上流のサーバーに転送するルートの設定。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// The route pattern to forward, e.g. `/api/*rest`.
+    pub route: String,
+    pub upstreams: Vec<SocketAddr>,
+    pub timeout: Option<Duration>,
+    /// The path to probe on each upstream, and how often.
+    pub health_check: Option<(String, Duration)>,
+}
+
+/// CGIのスクリプトで応答するルートの設定。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgiSettings {
+    /// The route pattern, e.g. `/wiki/*page`. A trailing wildcard becomes
+    /// the script's `PATH_INFO`.
+    pub route: String,
+    pub script: PathBuf,
+    pub timeout: Option<Duration>,
+}
+
+impl Config {
+    /// Reads the configuration file named by `args`, if any, and applies
+    /// the command-line options on top of it. Called again on reload.
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let file = match &args.config {
+            Some(path) => File::read(path)?,
+            None => File::default(),
+        };
+        args.overrides.clone().or(file).resolve()
+    }
+
+    /// Parses a configuration file's contents on their own, without any
+    /// command-line options. Relative paths are kept as they are.
+    pub fn from_toml(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str::<File>(contents)
+            .map_err(|e| ConfigError::Parse(PathBuf::from("<string>"), e))?
+            .resolve()
+    }
+
+    /// The settings that only take effect after a restart and differ
+    /// between `self` and `new`.
+    pub fn needs_restart(&self, new: &Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.listen != new.listen {
+            fields.push("listen");
+        }
+        if self.event_loop_threads != new.event_loop_threads {
+            fields.push("event_loop_threads");
+        }
+        if (self.min_workers, self.max_workers, self.queue_capacity)
+            != (new.min_workers, new.max_workers, new.queue_capacity)
+        {
+            fields.push("workers");
+        }
+        if self.shutdown_timeout != new.shutdown_timeout {
+            fields.push("timeouts.shutdown");
+        }
+        if self.tls != new.tls {
+            fields.push("tls");
+        }
+        fields
+    }
+}
+
+/// コマンドラインの引数。
+///
+/// The parsed command line: where the configuration file is, and the
+/// settings that override it.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub help: bool,
+    pub config: Option<PathBuf>,
+    overrides: File,
+}
+
+impl Args {
+    /// Parses the arguments after the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        let mut listen = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", name)))
+            };
+            let overrides = &mut parsed.overrides;
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "-c" | "--config" => parsed.config = Some(PathBuf::from(value(&arg)?)),
+                "-l" | "--listen" => listen.push(value(&arg)?),
+                "-w" | "--workers" => overrides.workers.min = Some(number(&arg, value(&arg)?)?),
+                "--max-workers" => overrides.workers.max = Some(number(&arg, value(&arg)?)?),
+                "--queue" => overrides.workers.queue = Some(number(&arg, value(&arg)?)?),
+                "--keep-alive" => overrides.timeouts.keep_alive = Some(number(&arg, value(&arg)?)?),
+                "--max-connections-per-ip" => {
+                    overrides.limits.max_connections_per_ip = Some(number(&arg, value(&arg)?)?)
+                }
+                "--log-level" => overrides.log_level = Some(value(&arg)?),
+                "--no-compress" => overrides.compression = Some(false),
+                "--http2" => overrides.http2 = Some(true),
+                "--event-loop" => overrides.event_loop_threads = Some(number(&arg, value(&arg)?)?),
+                "-r" | "--root" => overrides.document_root = Some(PathBuf::from(value(&arg)?)),
+                other if other.starts_with('-') => {
+                    return Err(ConfigError::Usage(format!("unknown option {}", other)));
+                }
+                // 以前と同じく、最初の引数だけでも公開するディレクトリを指定できます
+                _ if overrides.document_root.is_none() => {
+                    overrides.document_root = Some(PathBuf::from(arg));
+                }
+                _ => return Err(ConfigError::Usage(format!("unexpected argument {}", arg))),
+            }
+        }
+
+        if !listen.is_empty() {
+            parsed.overrides.listen = Some(listen);
+        }
+        Ok(parsed)
+    }
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: String) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("{} needs a number, not {:?}", option, value)))
+}
+
+/// 設定ファイルの内容。どの項目も省略できます。
+///
+/// The configuration file as written, and the command-line overrides in the
+/// same shape. Every setting is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    listen: Option<Vec<String>>,
+    document_root: Option<PathBuf>,
+    log_level: Option<String>,
+    compression: Option<bool>,
+    http2: Option<bool>,
+    event_loop_threads: Option<usize>,
+    #[serde(default)]
+    workers: Workers,
+    #[serde(default)]
+    timeouts: Timeouts,
+    #[serde(default)]
+    limits: RequestLimits,
+    tls: Option<Tls>,
+    proxy: Option<Vec<ProxyRoute>>,
+    cgi: Option<Vec<CgiRoute>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Workers {
+    min: Option<usize>,
+    max: Option<usize>,
+    queue: Option<usize>,
+}
+
+/// Timeouts in seconds.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Timeouts {
+    keep_alive: Option<f64>,
+    shutdown: Option<f64>,
+    header: Option<f64>,
+    body: Option<f64>,
+    write: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RequestLimits {
+    max_header_bytes: Option<usize>,
+    max_body_bytes: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Tls {
+    listen: Vec<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// A `[[proxy]]` table; timeouts in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyRoute {
+    route: String,
+    upstreams: Vec<String>,
+    timeout: Option<f64>,
+    health_check: Option<String>,
+    health_interval: Option<f64>,
+}
+
+/// A `[[cgi]]` table; the timeout in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CgiRoute {
+    route: String,
+    script: PathBuf,
+    timeout: Option<f64>,
+}
+
+impl File {
+    fn read(path: &Path) -> Result<File, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let mut file: File =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if let Some(root) = &mut file.document_root {
+            *root = dir.join(&*root);
+        }
+        if let Some(tls) = &mut file.tls {
+            tls.cert = dir.join(&tls.cert);
+            tls.key = dir.join(&tls.key);
+        }
+        for cgi in file.cgi.iter_mut().flatten() {
+            cgi.script = dir.join(&cgi.script);
+        }
+        Ok(file)
+    }
+
+    /// Takes each setting from `self`, or from `base` where `self` has none.
+    fn or(self, base: File) -> File {
+        File {
+            listen: self.listen.or(base.listen),
+            document_root: self.document_root.or(base.document_root),
+            log_level: self.log_level.or(base.log_level),
+            compression: self.compression.or(base.compression),
+            http2: self.http2.or(base.http2),
+            event_loop_threads: self.event_loop_threads.or(base.event_loop_threads),
+            workers: Workers {
+                min: self.workers.min.or(base.workers.min),
+                max: self.workers.max.or(base.workers.max),
+                queue: self.workers.queue.or(base.workers.queue),
+            },
+            timeouts: Timeouts {
+                keep_alive: self.timeouts.keep_alive.or(base.timeouts.keep_alive),
+                shutdown: self.timeouts.shutdown.or(base.timeouts.shutdown),
+                header: self.timeouts.header.or(base.timeouts.header),
+                body: self.timeouts.body.or(base.timeouts.body),
+                write: self.timeouts.write.or(base.timeouts.write),
+            },
+            limits: RequestLimits {
+                max_header_bytes: self
+                    .limits
+                    .max_header_bytes
+                    .or(base.limits.max_header_bytes),
+                max_body_bytes: self.limits.max_body_bytes.or(base.limits.max_body_bytes),
+                max_connections_per_ip: self
+                    .limits
+                    .max_connections_per_ip
+                    .or(base.limits.max_connections_per_ip),
+            },
+            tls: self.tls.or(base.tls),
+            proxy: self.proxy.or(base.proxy),
+            cgi: self.cgi.or(base.cgi),
+        }
+    }
+
+    /// Fills in the defaults and checks every setting.
+    fn resolve(self) -> Result<Config, ConfigError> {
+        let listen = match self.listen {
+            Some(addrs) if addrs.is_empty() => {
+                return Err(invalid("listen", "at least one address is required"));
+            }
+            Some(addrs) => socket_addrs("listen", &addrs)?,
+            None => socket_addrs("listen", &[DEFAULT_LISTEN.to_string()])?,
+        };
+
+        if let Some(root) = &self.document_root
+            && !root.is_dir()
+        {
+            return Err(invalid(
+                "document_root",
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+
+        let log_level = match self.log_level.as_deref() {
+            None => Some(Level::Info),
+            Some(level) => parse_level(level)?,
+        };
+
+        let event_loop_threads = match self.event_loop_threads {
+            Some(0) => return Err(invalid("event_loop_threads", "must be at least 1")),
+            Some(_) if !cfg!(target_os = "linux") => {
+                return Err(invalid("event_loop_threads", "only supported on Linux"));
+            }
+            threads => threads,
+        };
+
+        let min_workers = self.workers.min.unwrap_or(DEFAULT_MIN_WORKERS);
+        let max_workers = self
+            .workers
+            .max
+            .unwrap_or(DEFAULT_MAX_WORKERS.max(min_workers));
+        if min_workers == 0 {
+            return Err(invalid("workers.min", "must be at least 1"));
+        }
+        if max_workers < min_workers {
+            return Err(invalid(
+                "workers.max",
+                format!("{} is less than workers.min ({})", max_workers, min_workers),
+            ));
+        }
+        let queue_capacity = self.workers.queue.unwrap_or(DEFAULT_QUEUE);
+        if queue_capacity == 0 {
+            return Err(invalid("workers.queue", "must be at least 1"));
+        }
+
+        let keep_alive = KeepAlive {
+            idle_timeout: match self.timeouts.keep_alive {
+                Some(secs) => seconds("timeouts.keep_alive", secs)?,
+                None => KeepAlive::default().idle_timeout,
+            },
+            ..KeepAlive::default()
+        };
+        let shutdown_timeout = match self.timeouts.shutdown {
+            Some(secs) => seconds("timeouts.shutdown", secs)?,
+            None => DEFAULT_SHUTDOWN_TIMEOUT,
+        };
+        let limits = connection_limits(&self.timeouts, &self.limits)?;
+
+        let tls = match self.tls {
+            Some(tls) if tls.listen.is_empty() => {
+                return Err(invalid("tls.listen", "at least one address is required"));
+            }
+            Some(tls) => Some(TlsSettings {
+                listen: socket_addrs("tls.listen", &tls.listen)?,
+                cert: tls.cert,
+                key: tls.key,
+            }),
+            None => None,
+        };
+
+        let proxies = self
+            .proxy
+            .unwrap_or_default()
+            .into_iter()
+            .map(ProxyRoute::resolve)
+            .collect::<Result<_, _>>()?;
+        let cgi = self
+            .cgi
+            .unwrap_or_default()
+            .into_iter()
+            .map(CgiRoute::resolve)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Config {
+            listen,
+            document_root: self.document_root,
+            log_level,
+            compression: self.compression.unwrap_or(true),
+            http2: self.http2.unwrap_or(false),
+            event_loop_threads,
+            min_workers,
+            max_workers,
+            queue_capacity,
+            keep_alive,
+            shutdown_timeout,
+            limits,
+            tls,
+            proxies,
+            cgi,
+        })
+    }
+}
+
+impl ProxyRoute {
+    fn resolve(self) -> Result<ProxySettings, ConfigError> {
+        route_pattern("proxy.route", &self.route)?;
+        if self.upstreams.is_empty() {
+            return Err(invalid(
+                "proxy.upstreams",
+                "at least one address is required",
+            ));
+        }
+
+        let timeout = match self.timeout {
+            Some(secs) => Some(seconds("proxy.timeout", secs)?),
+            None => None,
+        };
+        let health_check = match (self.health_check, self.health_interval) {
+            (Some(path), interval) => {
+                if !path.starts_with('/') {
+                    return Err(invalid("proxy.health_check", "must start with '/'"));
+                }
+                let interval = match interval {
+                    Some(secs) => seconds("proxy.health_interval", secs)?,
+                    None => DEFAULT_HEALTH_INTERVAL,
+                };
+                Some((path, interval))
+            }
+            (None, Some(_)) => {
+                return Err(invalid(
+                    "proxy.health_interval",
+                    "needs a health_check path",
+                ));
+            }
+            (None, None) => None,
+        };
+
+        Ok(ProxySettings {
+            upstreams: socket_addrs("proxy.upstreams", &self.upstreams)?,
+            route: self.route,
+            timeout,
+            health_check,
+        })
+    }
+}
+
+impl CgiRoute {
+    fn resolve(self) -> Result<CgiSettings, ConfigError> {
+        route_pattern("cgi.route", &self.route)?;
+        if !self.script.is_file() {
+            return Err(invalid(
+                "cgi.script",
+                format!("{} is not a file", self.script.display()),
+            ));
+        }
+        let timeout = match self.timeout {
+            Some(secs) => Some(seconds("cgi.timeout", secs)?),
+            None => None,
+        };
+
+        Ok(CgiSettings {
+            route: self.route,
+            script: self.script,
+            timeout,
+        })
+    }
+}
+
+/// Checks that `route` is a pattern the router accepts, rather than let it
+/// panic.
+fn route_pattern(field: &'static str, route: &str) -> Result<(), ConfigError> {
+    let segments: Vec<&str> = route.split('/').collect();
+    if !route.starts_with('/') {
+        return Err(invalid(field, "must start with '/'"));
+    }
+    if segments[..segments.len() - 1]
+        .iter()
+        .any(|s| s.starts_with('*'))
+    {
+        return Err(invalid(field, "a wildcard must be the last segment"));
+    }
+    Ok(())
+}
+
+/// Applies the `[timeouts]` and `[limits]` settings to the server's defaults.
+fn connection_limits(
+    timeouts: &Timeouts,
+    file: &RequestLimits,
+) -> Result<ConnectionLimits, ConfigError> {
+    let mut limits = ConnectionLimits::default();
+    if let Some(secs) = timeouts.header {
+        limits.request.header_timeout = Some(seconds("timeouts.header", secs)?);
+    }
+    if let Some(secs) = timeouts.body {
+        limits.request.body_timeout = Some(seconds("timeouts.body", secs)?);
+    }
+    if let Some(secs) = timeouts.write {
+        limits.write_timeout = seconds("timeouts.write", secs)?;
+    }
+    // リクエスト行さえ収まらない上限は使えません
+    match file.max_header_bytes {
+        Some(bytes) if bytes < 64 => {
+            return Err(invalid("limits.max_header_bytes", "must be at least 64"));
+        }
+        Some(bytes) => limits.request.max_header_bytes = bytes,
+        None => {}
+    }
+    if let Some(bytes) = file.max_body_bytes {
+        limits.request.max_body_bytes = bytes;
+    }
+    match file.max_connections_per_ip {
+        Some(0) => {
+            return Err(invalid(
+                "limits.max_connections_per_ip",
+                "must be at least 1",
+            ));
+        }
+        Some(max) => limits.max_connections_per_ip = Some(max),
+        None => {}
+    }
+    Ok(limits)
+}
+
+/// Resolves `host:port` strings, such as `0.0.0.0:80`, `[::1]:8080` or
+/// `localhost:7878`, to the first address each names.
+fn socket_addrs(field: &'static str, addrs: &[String]) -> Result<Vec<SocketAddr>, ConfigError> {
+    addrs
+        .iter()
+        .map(|addr| {
+            if let Ok(addr) = addr.parse() {
+                return Ok(addr);
+            }
+            addr.to_socket_addrs()
+                .map_err(|e| invalid(field, format!("{:?}: {}", addr, e)))?
+                .next()
+                .ok_or_else(|| invalid(field, format!("{:?} has no addresses", addr)))
+        })
+        .collect()
+}
+
+fn parse_level(level: &str) -> Result<Option<Level>, ConfigError> {
+    match level.to_ascii_lowercase().as_str() {
+        "off" => Ok(None),
+        "error" => Ok(Some(Level::Error)),
+        "warn" => Ok(Some(Level::Warn)),
+        "info" => Ok(Some(Level::Info)),
+        "debug" => Ok(Some(Level::Debug)),
+        _ => Err(invalid(
+            "log_level",
+            format!("{:?} is not one of error, warn, info, debug or off", level),
+        )),
+    }
+}
+
+fn seconds(field: &'static str, secs: f64) -> Result<Duration, ConfigError> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(invalid(
+            field,
+            format!("{} is not a positive number of seconds", secs),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn args(list: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(list.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn reads_a_config_file() {
+        let config = Config::from_toml(
+            r#"
+            listen = ["127.0.0.1:8080", "[::1]:8081"]
+            log_level = "debug"
+
+            [workers]
+            min = 2
+            max = 3
+
+            [timeouts]
+            keep_alive = 0.5
+            header = 2
+
+            [limits]
+            max_connections_per_ip = 8
+
+            [[proxy]]
+            route = "/api/*rest"
+            upstreams = ["127.0.0.1:9000"]
+            health_check = "/health"
+
+            [[cgi]]
+            route = "/hello"
+            script = "hello.html"
+            timeout = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            [
+                "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
+                "[::1]:8081".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.log_level, Some(Level::Debug));
+        assert!(config.compression);
+        assert!(!config.http2);
+        assert_eq!((config.min_workers, config.max_workers), (2, 3));
+        assert_eq!(config.queue_capacity, DEFAULT_QUEUE);
+        assert_eq!(config.keep_alive.idle_timeout, Duration::from_millis(500));
+        assert_eq!(
+            config.limits.request.header_timeout,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            config.limits.request.body_timeout,
+            ConnectionLimits::default().request.body_timeout
+        );
+        assert_eq!(config.limits.max_connections_per_ip, Some(8));
+        assert_eq!(
+            config.proxies,
+            [ProxySettings {
+                route: "/api/*rest".to_string(),
+                upstreams: vec!["127.0.0.1:9000".parse().unwrap()],
+                timeout: None,
+                health_check: Some(("/health".to_string(), DEFAULT_HEALTH_INTERVAL)),
+            }]
+        );
+        assert_eq!(
+            config.cgi,
+            [CgiSettings {
+                route: "/hello".to_string(),
+                script: PathBuf::from("hello.html"),
+                timeout: Some(Duration::from_secs(2)),
+            }]
+        );
+        assert_eq!(config.tls, None);
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let temp = TempDir::new();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("public")).unwrap();
+        let path = dir.join("hello.toml");
+        fs::write(
+            &path,
+            "listen = [\"127.0.0.1:8080\"]\ndocument_root = \"public\"\n[workers]\nmin = 2\n",
+        )
+        .unwrap();
+
+        let parsed = args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "-l",
+            "[::]:9000",
+            "-l",
+            "0.0.0.0:9001",
+            "--workers",
+            "32",
+            "--http2",
+            "--no-compress",
+        ])
+        .unwrap();
+        let config = Config::load(&parsed).unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.listen[0].port(), 9000);
+        assert!(config.listen[0].is_ipv6());
+        assert_eq!((config.min_workers, config.max_workers), (32, 32));
+        assert_eq!(config.document_root, Some(dir.join("public")));
+        assert!(config.http2);
+        assert!(!config.compression);
+
+        let plain = Config::load(&args(&["-c", path.to_str().unwrap()]).unwrap()).unwrap();
+        assert_eq!(plain.needs_restart(&config), ["listen", "workers"]);
+    }
+
+    #[test]
+    fn reports_invalid_settings() {
+        let field = |toml: &str| match Config::from_toml(toml) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(field("listen = []"), "listen");
+        assert_eq!(field("listen = [\"nowhere\"]"), "listen");
+        assert_eq!(field("log_level = \"loud\""), "log_level");
+        assert_eq!(field("[workers]\nmin = 8\nmax = 4"), "workers.max");
+        assert_eq!(field("[timeouts]\nkeep_alive = -1"), "timeouts.keep_alive");
+        assert_eq!(field("[timeouts]\nheader = 0"), "timeouts.header");
+        assert_eq!(
+            field("[limits]\nmax_connections_per_ip = 0"),
+            "limits.max_connections_per_ip"
+        );
+        assert_eq!(
+            field("[[proxy]]\nroute = \"/*a/b\"\nupstreams = [\"127.0.0.1:1\"]"),
+            "proxy.route"
+        );
+        assert_eq!(
+            field("[[proxy]]\nroute = \"/a\"\nupstreams = []"),
+            "proxy.upstreams"
+        );
+        assert_eq!(
+            field("[[cgi]]\nroute = \"wiki\"\nscript = \"hello.html\""),
+            "cgi.route"
+        );
+        assert_eq!(
+            field("[[cgi]]\nroute = \"/wiki\"\nscript = \"missing.py\""),
+            "cgi.script"
+        );
+
+        assert!(matches!(
+            Config::from_toml("worker = 4"),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            args(&["--workers", "many"]),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(args(&["--listen"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(args(&["--verbose"]), Err(ConfigError::Usage(_))));
+    }
+}
@@ -0,0 +1,198 @@
+//! 時刻の書式。
+//!
+//! Formats and parses UTC dates, without a date library.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// UTCの日時。
+///
+/// A UTC calendar date and time of day, to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12.
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0 for Sunday to 6 for Saturday.
+    pub weekday: u32,
+}
+
+impl DateTime {
+    /// Times before 1970 are clamped to the epoch.
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        DateTime::from_unix(secs)
+    }
+
+    pub fn from_unix(secs: i64) -> DateTime {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+            // 1970-01-01は木曜日です
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    /// Parses an HTTP date in the preferred IMF-fixdate format, e.g.
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`. The weekday is not checked.
+    pub fn parse_http_date(s: &str) -> Option<DateTime> {
+        let (_, rest) = s.trim().split_once(", ")?;
+        let parts: Vec<&str> = rest.split(' ').collect();
+        let [day, month, year, time, "GMT"] = parts[..] else {
+            return None;
+        };
+        let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+        let day: u32 = day.parse().ok()?;
+        let year: i64 = year.parse().ok()?;
+        let mut hms = time.split(':').map(|n| n.parse::<u32>().ok());
+        let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+        if hms.next().is_some() || day == 0 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        let days = days_from_civil(year, month, day);
+        let secs = days * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+        Some(DateTime::from_unix(secs))
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * 86_400
+            + i64::from(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+
+    /// Times before 1970 are clamped to the epoch.
+    pub fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.unix().max(0) as u64)
+    }
+
+    /// The IMF-fixdate format used in HTTP headers, e.g.
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`.
+    pub fn http_date(&self) -> impl fmt::Display + '_ {
+        HttpDate(self)
+    }
+
+    /// The timestamp of a Common Log Format line, e.g.
+    /// `10/Oct/2000:13:55:36 +0000`.
+    pub fn clf(&self) -> impl fmt::Display + '_ {
+        Clf(self)
+    }
+
+    fn month_name(&self) -> &'static str {
+        MONTHS[(self.month - 1) as usize]
+    }
+}
+
+struct HttpDate<'a>(&'a DateTime);
+
+impl fmt::Display for HttpDate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.0;
+        write!(
+            f,
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[d.weekday as usize],
+            d.day,
+            d.month_name(),
+            d.year,
+            d.hour,
+            d.minute,
+            d.second
+        )
+    }
+}
+
+struct Clf<'a>(&'a DateTime);
+
+impl fmt::Display for Clf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.0;
+        write!(
+            f,
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            d.day,
+            d.month_name(),
+            d.year,
+            d.hour,
+            d.minute,
+            d.second
+        )
+    }
+}
+
+// Howard Hinnantのアルゴリズムで、1970-01-01からの日数を年月日にします
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_common_log_format_timestamps() {
+        assert_eq!(
+            DateTime::from_unix(971_186_136).clf().to_string(),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+        assert_eq!(
+            DateTime::from_unix(951_782_400).clf().to_string(),
+            "29/Feb/2000:00:00:00 +0000"
+        );
+    }
+
+    #[test]
+    fn formats_and_parses_http_dates() {
+        let date = DateTime::from_unix(784_111_777);
+        assert_eq!(
+            date.http_date().to_string(),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            DateTime::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(date)
+        );
+        assert_eq!(date.unix(), 784_111_777);
+        assert_eq!(
+            DateTime::parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(DateTime::parse_http_date("Sun, 06 Nov 1994 08:49:37"), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! HTTP/2のフレーム (RFC 9113 4, 6)。
+//!
+//! HTTP/2 frames: the 9-byte header, the payload, and constructors for the
+//! frames a server sends.
+
+use super::{Error, ErrorCode, protocol};
+use std::io::{self, Read, Write};
+
+/// DATA and HEADERS: the last frame the sender sends on the stream.
+pub const END_STREAM: u8 = 0x1;
+/// SETTINGS and PING: an acknowledgement.
+pub const ACK: u8 = 0x1;
+/// HEADERS and CONTINUATION: the header block is complete.
+pub const END_HEADERS: u8 = 0x4;
+/// DATA and HEADERS: the payload is padded.
+pub const PADDED: u8 = 0x8;
+/// HEADERS: the payload starts with priority fields.
+pub const PRIORITY: u8 = 0x20;
+
+const HEADER_LEN: usize = 9;
+
+/// フレームの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    /// A frame type this implementation does not know, which receivers
+    /// must ignore.
+    Unknown(u8),
+}
+
+impl Kind {
+    fn from_byte(byte: u8) -> Kind {
+        match byte {
+            0x0 => Kind::Data,
+            0x1 => Kind::Headers,
+            0x2 => Kind::Priority,
+            0x3 => Kind::RstStream,
+            0x4 => Kind::Settings,
+            0x5 => Kind::PushPromise,
+            0x6 => Kind::Ping,
+            0x7 => Kind::GoAway,
+            0x8 => Kind::WindowUpdate,
+            0x9 => Kind::Continuation,
+            other => Kind::Unknown(other),
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            Kind::Data => 0x0,
+            Kind::Headers => 0x1,
+            Kind::Priority => 0x2,
+            Kind::RstStream => 0x3,
+            Kind::Settings => 0x4,
+            Kind::PushPromise => 0x5,
+            Kind::Ping => 0x6,
+            Kind::GoAway => 0x7,
+            Kind::WindowUpdate => 0x8,
+            Kind::Continuation => 0x9,
+            Kind::Unknown(other) => other,
+        }
+    }
+}
+
+/// HTTP/2のフレーム。
+///
+/// One HTTP/2 frame. `stream` is 0 for frames about the whole connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: Kind,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: Kind, flags: u8, stream: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind,
+            flags,
+            stream,
+            payload,
+        }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Reads one frame. A frame whose payload is longer than `max_size` is
+    /// refused with `FRAME_SIZE_ERROR` before the payload is read.
+    pub fn read_from<R: Read>(r: &mut R, max_size: u32) -> Result<Frame, Error> {
+        let mut head = [0; HEADER_LEN];
+        r.read_exact(&mut head)?;
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]);
+        if len > max_size {
+            return Err(Error::Connection(
+                ErrorCode::FRAME_SIZE_ERROR,
+                "frame too large",
+            ));
+        }
+        // 予約ビットは読み捨てます
+        let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7FFF_FFFF;
+        let mut payload = vec![0; len as usize];
+        r.read_exact(&mut payload)?;
+        Ok(Frame {
+            kind: Kind::from_byte(head[3]),
+            flags: head[4],
+            stream,
+            payload,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let len = (self.payload.len() as u32).to_be_bytes();
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&len[1..]);
+        out.push(self.kind.byte());
+        out.push(self.flags);
+        out.extend_from_slice(&self.stream.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        w.write_all(&out)
+    }
+
+    /// The data of a DATA frame, or the header block fragment of a HEADERS
+    /// frame, without padding or priority fields.
+    pub fn fragment(&self) -> Result<&[u8], Error> {
+        let mut payload = &self.payload[..];
+        let mut padding = 0;
+        if self.has(PADDED) {
+            let (&len, rest) = payload
+                .split_first()
+                .ok_or(protocol("missing pad length"))?;
+            payload = rest;
+            padding = len as usize;
+        }
+        if self.kind == Kind::Headers && self.has(PRIORITY) {
+            payload = payload
+                .get(5..)
+                .ok_or(protocol("missing priority fields"))?;
+        }
+        if padding > payload.len() {
+            return Err(protocol("padding longer than the payload"));
+        }
+        Ok(&payload[..payload.len() - padding])
+    }
+
+    pub fn data(stream: u32, data: Vec<u8>, end_stream: bool) -> Frame {
+        let flags = if end_stream { END_STREAM } else { 0 };
+        Frame::new(Kind::Data, flags, stream, data)
+    }
+
+    pub fn headers(stream: u32, block: Vec<u8>, end_stream: bool, end_headers: bool) -> Frame {
+        let mut flags = 0;
+        if end_stream {
+            flags |= END_STREAM;
+        }
+        if end_headers {
+            flags |= END_HEADERS;
+        }
+        Frame::new(Kind::Headers, flags, stream, block)
+    }
+
+    pub fn continuation(stream: u32, block: Vec<u8>, end_headers: bool) -> Frame {
+        let flags = if end_headers { END_HEADERS } else { 0 };
+        Frame::new(Kind::Continuation, flags, stream, block)
+    }
+
+    /// A SETTINGS frame with `(identifier, value)` parameters.
+    pub fn settings(parameters: &[(u16, u32)]) -> Frame {
+        let mut payload = Vec::with_capacity(parameters.len() * 6);
+        for (id, value) in parameters {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        Frame::new(Kind::Settings, 0, 0, payload)
+    }
+
+    pub fn settings_ack() -> Frame {
+        Frame::new(Kind::Settings, ACK, 0, Vec::new())
+    }
+
+    pub fn ping(data: [u8; 8], ack: bool) -> Frame {
+        let flags = if ack { ACK } else { 0 };
+        Frame::new(Kind::Ping, flags, 0, data.to_vec())
+    }
+
+    pub fn rst_stream(stream: u32, code: ErrorCode) -> Frame {
+        Frame::new(Kind::RstStream, 0, stream, code.0.to_be_bytes().to_vec())
+    }
+
+    /// A GOAWAY frame: streams after `last_stream` were not processed.
+    pub fn goaway(last_stream: u32, code: ErrorCode, debug: &str) -> Frame {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.0.to_be_bytes());
+        payload.extend_from_slice(debug.as_bytes());
+        Frame::new(Kind::GoAway, 0, 0, payload)
+    }
+
+    pub fn window_update(stream: u32, increment: u32) -> Frame {
+        Frame::new(
+            Kind::WindowUpdate,
+            0,
+            stream,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+
+    /// The error code of a RST_STREAM or GOAWAY frame.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        let at = match self.kind {
+            Kind::RstStream => 0,
+            Kind::GoAway => 4,
+            _ => return None,
+        };
+        let bytes = self.payload.get(at..at + 4)?;
+        Some(ErrorCode(u32::from_be_bytes(bytes.try_into().ok()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = [
+            Frame::data(1, b"hello".to_vec(), true),
+            Frame::headers(3, vec![0x82], false, true),
+            Frame::window_update(0, 1 << 20),
+            Frame::goaway(7, ErrorCode::PROTOCOL_ERROR, "bad"),
+            Frame::new(Kind::Unknown(0xFA), 0xFF, 9, vec![1, 2]),
+        ];
+        let mut bytes = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut bytes).unwrap();
+        }
+        assert_eq!(&bytes[..9], [0, 0, 5, 0, 1, 0, 0, 0, 1]);
+
+        let mut reader = &bytes[..];
+        for frame in &frames {
+            assert_eq!(&Frame::read_from(&mut reader, 16_384).unwrap(), frame);
+        }
+        assert!(reader.is_empty());
+        assert_eq!(frames[3].error_code(), Some(ErrorCode::PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn strips_padding_and_priority() {
+        let mut frame = Frame::new(Kind::Headers, PADDED | PRIORITY, 1, vec![2]);
+        frame.payload.extend_from_slice(&[0, 0, 0, 0, 16]);
+        frame.payload.extend_from_slice(b"block\0\0");
+        assert_eq!(frame.fragment().unwrap(), b"block");
+
+        let data = Frame::new(Kind::Data, PADDED, 1, vec![9, 1, 2]);
+        assert!(data.fragment().is_err());
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let mut bytes = Vec::new();
+        Frame::data(1, vec![0; 100], false)
+            .write_to(&mut bytes)
+            .unwrap();
+        assert!(matches!(
+            Frame::read_from(&mut &bytes[..], 99),
+            Err(Error::Connection(ErrorCode::FRAME_SIZE_ERROR, _))
+        ));
+    }
+}
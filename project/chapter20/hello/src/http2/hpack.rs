@@ -0,0 +1,800 @@
+//! HPACKによるヘッダーの圧縮 (RFC 7541)。
+//!
+//! HPACK header compression (RFC 7541): the static and dynamic tables,
+//! integer and string literals, and the Huffman code.
+//!
+//! Each direction of a connection has its own dynamic table, kept in step
+//! by decoding every header block in the order it was sent. An `Encoder`
+//! must therefore encode blocks in the order they are written, and a
+//! `Decoder` must see every block the peer sends, even for streams it
+//! refuses.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// The size of each dynamic table until SETTINGS change it.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// 各エントリーが表の大きさに加える分 (RFC 7541 4.1)
+const ENTRY_OVERHEAD: usize = 32;
+
+// 静的表 (RFC 7541 付録A)。番号は1から始まります
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// 中間者に覚えられないよう、表に入れずに送るヘッダー (RFC 7541 7.1.3)
+const SENSITIVE: [&str; 4] = [
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+];
+
+/// ヘッダーブロックを復号できなかった理由。
+///
+/// Why a header block could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The block is malformed; the peer's table can no longer be followed,
+    /// so the connection must be closed with `COMPRESSION_ERROR`.
+    Compression(&'static str),
+    /// The decoded headers exceed the decoder's list size limit. The whole
+    /// block was still decoded, so the connection can go on.
+    ListTooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Compression(reason) => write!(f, "compression error: {}", reason),
+            Error::ListTooLarge => f.write_str("header list too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// 動的表。新しいエントリーほど前にあります。
+struct Table {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Table {
+    fn new() -> Table {
+        Table {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    /// The entry at `index`, counting the static table first.
+    fn get(&self, index: usize) -> Option<(&str, &str)> {
+        match index {
+            0 => None,
+            1..=61 => Some(STATIC_TABLE[index - 1]),
+            _ => self
+                .entries
+                .get(index - 62)
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        }
+    }
+
+    /// The index of an entry matching `name` and `value`, and whether the
+    /// value matched too.
+    fn find(&self, name: &str, value: &str) -> Option<(usize, bool)> {
+        let dynamic = self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let mut name_match = None;
+        for (i, (n, v)) in STATIC_TABLE.iter().copied().chain(dynamic).enumerate() {
+            if n == name {
+                if v == value {
+                    return Some((i + 1, true));
+                }
+                name_match.get_or_insert(i + 1);
+            }
+        }
+        name_match.map(|index| (index, false))
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = entry_size(&name, &value);
+        // 表より大きいエントリーは、表を空にするだけです (RFC 7541 4.4)
+        self.evict(self.max_size.saturating_sub(size));
+        if size <= self.max_size {
+            self.size += size;
+            self.entries.push_front((name, value));
+        }
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict(max_size);
+    }
+
+    /// Drops the oldest entries until the table takes at most `size` bytes.
+    fn evict(&mut self, size: usize) {
+        while self.size > size {
+            match self.entries.pop_back() {
+                Some((name, value)) => self.size -= entry_size(&name, &value),
+                None => break,
+            }
+        }
+    }
+}
+
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// ヘッダーブロックの復号器。
+///
+/// Decodes the header blocks of one direction of a connection.
+pub struct Decoder {
+    table: Table,
+    /// The largest table the peer may ask for, as advertised in our
+    /// SETTINGS.
+    max_table_size: usize,
+    max_list_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: Table::new(),
+            max_table_size: DEFAULT_TABLE_SIZE,
+            max_list_size: usize::MAX,
+        }
+    }
+
+    /// Limits the decoded size of a header list, counted as in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE: each header's name and value plus 32
+    /// bytes. Larger lists are decoded but answered with `ListTooLarge`.
+    pub fn with_max_list_size(mut self, bytes: usize) -> Decoder {
+        self.max_list_size = bytes;
+        self
+    }
+
+    /// Decodes one complete header block into its headers, in order.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, Error> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut first = true;
+
+        while let Some(&byte) = block.first() {
+            let (name, value) = if byte & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                let (name, value) = self
+                    .table
+                    .get(index)
+                    .ok_or(Error::Compression("invalid index"))?;
+                (name.to_string(), value.to_string())
+            } else if byte & 0xE0 == 0x20 {
+                // 表の大きさの変更は、ブロックの先頭にしか置けません
+                let size = decode_integer(&mut block, 5)?;
+                if !first || size > self.max_table_size {
+                    return Err(Error::Compression("invalid table size update"));
+                }
+                self.table.set_max_size(size);
+                continue;
+            } else {
+                let indexing = byte & 0x40 != 0;
+                let prefix = if indexing { 6 } else { 4 };
+                let name = match decode_integer(&mut block, prefix)? {
+                    0 => decode_string(&mut block)?,
+                    index => self
+                        .table
+                        .get(index)
+                        .ok_or(Error::Compression("invalid index"))?
+                        .0
+                        .to_string(),
+                };
+                let value = decode_string(&mut block)?;
+                if indexing {
+                    self.table.insert(name.clone(), value.clone());
+                }
+                (name, value)
+            };
+            first = false;
+
+            // 表を相手と揃えておくため、上限を超えても最後まで復号します
+            list_size += entry_size(&name, &value);
+            if list_size <= self.max_list_size {
+                headers.push((name, value));
+            }
+        }
+
+        if list_size > self.max_list_size {
+            return Err(Error::ListTooLarge);
+        }
+        Ok(headers)
+    }
+}
+
+/// ヘッダーブロックの符号化器。
+///
+/// Encodes the header blocks of one direction of a connection. Headers are
+/// added to the dynamic table as they are sent, except for credentials and
+/// cookies, which are marked never to be indexed.
+pub struct Encoder {
+    table: Table,
+    /// The smallest and the latest table size set since the last block,
+    /// which the next block must announce.
+    size_update: Option<(usize, usize)>,
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            table: Table::new(),
+            size_update: None,
+        }
+    }
+
+    /// Follows the peer's SETTINGS_HEADER_TABLE_SIZE. The table never grows
+    /// past `DEFAULT_TABLE_SIZE`.
+    pub fn set_max_table_size(&mut self, size: usize) {
+        let size = size.min(DEFAULT_TABLE_SIZE);
+        if size == self.table.max_size && self.size_update.is_none() {
+            return;
+        }
+        let smallest = match self.size_update {
+            Some((smallest, _)) => smallest.min(size),
+            None => size.min(self.table.max_size),
+        };
+        self.size_update = Some((smallest, size));
+        self.table.set_max_size(size);
+    }
+
+    /// Appends the header block for `headers` to `out`. Names must already
+    /// be lowercase.
+    pub fn encode<'a, I>(&mut self, headers: I, out: &mut Vec<u8>)
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        if let Some((smallest, size)) = self.size_update.take() {
+            if smallest < size {
+                encode_integer(smallest, 5, 0x20, out);
+            }
+            encode_integer(size, 5, 0x20, out);
+        }
+
+        for (name, value) in headers {
+            let found = self.table.find(name, value);
+            if let Some((index, true)) = found {
+                encode_integer(index, 7, 0x80, out);
+                continue;
+            }
+            let name_index = found.map_or(0, |(index, _)| index);
+            if SENSITIVE.contains(&name) {
+                encode_integer(name_index, 4, 0x10, out);
+            } else {
+                encode_integer(name_index, 6, 0x40, out);
+            }
+            if name_index == 0 {
+                encode_string(name, out);
+            }
+            encode_string(value, out);
+            if !SENSITIVE.contains(&name) {
+                self.table.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+}
+
+/// Appends `value` as an integer with an `prefix`-bit prefix, the bits
+/// above which are `flags` (RFC 7541 5.1).
+fn encode_integer(mut value: usize, prefix: u32, flags: u8, out: &mut Vec<u8>) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push(0x80 | (value & 0x7F) as u8);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_integer(block: &mut &[u8], prefix: u32) -> Result<usize, Error> {
+    let truncated = Error::Compression("truncated integer");
+    let (&first, rest) = block.split_first().ok_or(truncated.clone())?;
+    *block = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = (first & max as u8) as usize;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(truncated.clone())?;
+        *block = rest;
+        // 表の番号や長さに、28ビットを超える値はありえません
+        if shift > 21 {
+            return Err(Error::Compression("integer too large"));
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Appends `s` as a string literal, Huffman-coded if that is shorter.
+fn encode_string(s: &str, out: &mut Vec<u8>) {
+    let huffman_len = huffman_len(s.as_bytes());
+    if huffman_len < s.len() {
+        encode_integer(huffman_len, 7, 0x80, out);
+        huffman_encode(s.as_bytes(), out);
+    } else {
+        encode_integer(s.len(), 7, 0, out);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, Error> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_integer(block, 7)?;
+    if len > block.len() {
+        return Err(Error::Compression("truncated string"));
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    // 値は本来バイト列ですが、ヘッダーは文字列として扱います
+    Ok(match String::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    })
+}
+
+// ハフマン符号 (RFC 7541 付録B) の、符号の長さごとの記号。
+// 正準符号なので、短い符号から順に、同じ長さでは記号の順に割り当てられます
+const HUFFMAN_LENGTHS: [(u32, &[u16]); 21] = [
+    (5, &[48, 49, 50, 97, 99, 101, 105, 111, 115, 116]),
+    (
+        6,
+        &[
+            32, 37, 45, 46, 47, 51, 52, 53, 54, 55, 56, 57, 61, 65, 95, 98, 100, 102, 103, 104,
+            108, 109, 110, 112, 114, 117,
+        ],
+    ),
+    (
+        7,
+        &[
+            58, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86,
+            87, 89, 106, 107, 113, 118, 119, 120, 121, 122,
+        ],
+    ),
+    (8, &[38, 42, 44, 59, 88, 90]),
+    (10, &[33, 34, 40, 41, 63]),
+    (11, &[39, 43, 124]),
+    (12, &[35, 62]),
+    (13, &[0, 36, 64, 91, 93, 126]),
+    (14, &[94, 125]),
+    (15, &[60, 96, 123]),
+    (19, &[92, 195, 208]),
+    (20, &[128, 130, 131, 162, 184, 194, 224, 226]),
+    (
+        21,
+        &[
+            153, 161, 167, 172, 176, 177, 179, 209, 216, 217, 227, 229, 230,
+        ],
+    ),
+    (
+        22,
+        &[
+            129, 132, 133, 134, 136, 146, 154, 156, 160, 163, 164, 169, 170, 173, 178, 181, 185,
+            186, 187, 189, 190, 196, 198, 228, 232, 233,
+        ],
+    ),
+    (
+        23,
+        &[
+            1, 135, 137, 138, 139, 140, 141, 143, 147, 149, 150, 151, 152, 155, 157, 158, 165, 166,
+            168, 174, 175, 180, 182, 183, 188, 191, 197, 231, 239,
+        ],
+    ),
+    (
+        24,
+        &[9, 142, 144, 145, 148, 159, 171, 206, 215, 225, 236, 237],
+    ),
+    (25, &[199, 207, 234, 235]),
+    (
+        26,
+        &[
+            192, 193, 200, 201, 202, 205, 210, 213, 218, 219, 238, 240, 242, 243, 255,
+        ],
+    ),
+    (
+        27,
+        &[
+            203, 204, 211, 212, 214, 221, 222, 223, 241, 244, 245, 246, 247, 248, 250, 251, 252,
+            253, 254,
+        ],
+    ),
+    (
+        28,
+        &[
+            2, 3, 4, 5, 6, 7, 8, 11, 12, 14, 15, 16, 17, 18, 19, 20, 21, 23, 24, 25, 26, 27, 28,
+            29, 30, 31, 127, 220, 249,
+        ],
+    ),
+    (30, &[10, 13, 22, 256]),
+];
+
+// 文字列の終わりを表す記号。文字列の中に現れてはいけません
+const EOS: u16 = 256;
+
+/// The Huffman code, built from `HUFFMAN_LENGTHS`.
+struct Huffman {
+    /// The code and its length in bits for each symbol.
+    codes: [(u32, u32); 257],
+    /// For each length, the first code of that length and where its
+    /// symbols start in `symbols`.
+    lengths: Vec<(u32, u32, usize)>,
+    symbols: Vec<u16>,
+}
+
+fn huffman() -> &'static Huffman {
+    static HUFFMAN: OnceLock<Huffman> = OnceLock::new();
+    HUFFMAN.get_or_init(|| {
+        let mut codes = [(0, 0); 257];
+        let mut lengths = Vec::new();
+        let mut symbols = Vec::new();
+        let (mut code, mut previous) = (0, HUFFMAN_LENGTHS[0].0);
+        for &(len, group) in &HUFFMAN_LENGTHS {
+            code <<= len - previous;
+            previous = len;
+            lengths.push((len, code, symbols.len()));
+            for &symbol in group {
+                codes[symbol as usize] = (code, len);
+                symbols.push(symbol);
+                code += 1;
+            }
+        }
+        Huffman {
+            codes,
+            lengths,
+            symbols,
+        }
+    })
+}
+
+fn huffman_len(bytes: &[u8]) -> usize {
+    let codes = &huffman().codes;
+    let bits: usize = bytes.iter().map(|&b| codes[b as usize].1 as usize).sum();
+    bits.div_ceil(8)
+}
+
+fn huffman_encode(bytes: &[u8], out: &mut Vec<u8>) {
+    let codes = &huffman().codes;
+    let (mut acc, mut bits) = (0u64, 0);
+    for &b in bytes {
+        let (code, len) = codes[b as usize];
+        acc = acc << len | code as u64;
+        bits += len;
+        while bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // 最後のバイトの残りは、EOSの先頭 (すべて1) で埋めます
+    if bits > 0 {
+        out.push((acc << (8 - bits)) as u8 | (0xFF >> bits));
+    }
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let huffman = huffman();
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0u32);
+    let mut group = 0;
+
+    for i in 0..bytes.len() * 8 {
+        let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+        code = code << 1 | bit as u32;
+        len += 1;
+        // 同じ長さの符号は連続しているので、範囲に入ったかどうかで分かります
+        while group < huffman.lengths.len() && huffman.lengths[group].0 < len {
+            group += 1;
+        }
+        let Some(&(group_len, first, start)) = huffman.lengths.get(group) else {
+            return Err(Error::Compression("invalid Huffman code"));
+        };
+        if group_len != len {
+            continue;
+        }
+        let end = huffman
+            .lengths
+            .get(group + 1)
+            .map_or(huffman.symbols.len(), |&(_, _, end)| end);
+        let offset = code.wrapping_sub(first) as usize;
+        if offset < end - start {
+            let symbol = huffman.symbols[start + offset];
+            if symbol == EOS {
+                return Err(Error::Compression("EOS in Huffman string"));
+            }
+            out.push(symbol as u8);
+            code = 0;
+            len = 0;
+            group = 0;
+        }
+    }
+
+    // 残りは7ビット以下で、すべて1でなければなりません
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(Error::Compression("invalid Huffman padding"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn codes_integers() {
+        // RFC 7541 C.1
+        let mut out = Vec::new();
+        encode_integer(10, 5, 0, &mut out);
+        encode_integer(1337, 5, 0, &mut out);
+        encode_integer(42, 8, 0, &mut out);
+        assert_eq!(out, [0x0A, 0x1F, 0x9A, 0x0A, 0x2A]);
+
+        let mut block = &out[..];
+        assert_eq!(decode_integer(&mut block, 5), Ok(10));
+        assert_eq!(decode_integer(&mut block, 5), Ok(1337));
+        assert_eq!(decode_integer(&mut block, 8), Ok(42));
+        assert!(decode_integer(&mut &[0x1F, 0xFF][..], 5).is_err());
+        assert!(decode_integer(&mut &[0x1F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01][..], 5).is_err());
+    }
+
+    #[test]
+    fn huffman_code_is_complete() {
+        let huffman = huffman();
+        let mut symbols = huffman.symbols.clone();
+        symbols.sort();
+        assert_eq!(symbols, (0..=256).collect::<Vec<u16>>());
+        assert_eq!(huffman.codes[EOS as usize], (0x3FFF_FFFF, 30));
+        assert_eq!(huffman.codes[b'a' as usize], (0x3, 5));
+        assert_eq!(huffman.codes[b'z' as usize], (0x7B, 7));
+
+        let all: Vec<u8> = (0..=255).collect();
+        let mut encoded = Vec::new();
+        huffman_encode(&all, &mut encoded);
+        assert_eq!(encoded.len(), huffman_len(&all));
+        assert_eq!(huffman_decode(&encoded).unwrap(), all);
+
+        // 8ビット以上の埋め草と、1でない埋め草は誤りです
+        assert!(huffman_decode(&[0xFF, 0xFF]).is_err());
+        assert!(huffman_decode(&[0x00]).is_err());
+    }
+
+    #[test]
+    fn decodes_requests_without_huffman() {
+        // RFC 7541 C.3
+        let mut decoder = Decoder::new();
+        let first = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert_eq!(
+            decoder.decode(&first).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.table.size, 57);
+
+        let second = hex("8286 84be 5808 6e6f 2d63 6163 6865");
+        assert_eq!(
+            decoder.decode(&second).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(decoder.table.size, 110);
+    }
+
+    #[test]
+    fn decodes_requests_with_huffman() {
+        // RFC 7541 C.4
+        let mut decoder = Decoder::new();
+        let blocks = [
+            hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+            hex("8286 84be 5886 a8eb 1064 9cbf"),
+            hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+        ];
+        decoder.decode(&blocks[0]).unwrap();
+        decoder.decode(&blocks[1]).unwrap();
+        assert_eq!(
+            decoder.decode(&blocks[2]).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table.size, 164);
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let mut decoder = Decoder::new();
+        assert!(matches!(
+            decoder.decode(&[0x80]),
+            Err(Error::Compression(_))
+        ));
+        assert!(matches!(
+            decoder.decode(&[0xBE]),
+            Err(Error::Compression(_))
+        ));
+        // 表の大きさの変更は先頭だけで、広告した大きさまでです
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+        assert!(decoder.decode(&hex("3fe2 1f")).is_err());
+        assert!(decoder.decode(&[0x20, 0x82]).is_ok());
+        assert!(decoder.decode(&[0x04, 0x05, b'/']).is_err());
+    }
+
+    #[test]
+    fn limits_the_header_list_size() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new().with_max_list_size(100);
+        let mut block = Vec::new();
+        encoder.encode([("x-big", "a".repeat(100).as_str())], &mut block);
+        assert_eq!(decoder.decode(&block), Err(Error::ListTooLarge));
+
+        // 大きすぎたブロックのエントリーも、表には入っています
+        let mut block = Vec::new();
+        encoder.encode([("x-big", "a".repeat(100).as_str())], &mut block);
+        assert_eq!(block, [0xBE]);
+        assert_eq!(decoder.decode(&block), Err(Error::ListTooLarge));
+    }
+
+    #[test]
+    fn encoded_blocks_round_trip() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let responses = [
+            headers(&[
+                (":status", "200"),
+                ("content-type", "text/html; charset=utf-8"),
+                ("content-length", "1234"),
+                ("set-cookie", "session=secret"),
+            ]),
+            headers(&[
+                (":status", "404"),
+                ("content-type", "text/html; charset=utf-8"),
+                ("x-custom", "Ünïcödé"),
+            ]),
+        ];
+        let mut sizes = Vec::new();
+        for response in &responses {
+            let mut block = Vec::new();
+            encoder.encode(response.iter().map(|(n, v)| (&n[..], &v[..])), &mut block);
+            sizes.push(block.len());
+            assert_eq!(&decoder.decode(&block).unwrap(), response);
+        }
+        // 2つ目のcontent-typeは表から参照されます
+        assert!(sizes[1] < sizes[0]);
+        assert_eq!(encoder.table.size, decoder.table.size);
+        assert!(!encoder.table.entries.iter().any(|(n, _)| n == "set-cookie"));
+
+        // 表を小さくしたことは、次のブロックの先頭で知らせます
+        encoder.set_max_table_size(0);
+        encoder.set_max_table_size(100);
+        let mut block = Vec::new();
+        encoder.encode([(":status", "200")], &mut block);
+        assert_eq!(&block[..1], [0x20]);
+        assert_eq!(
+            decoder.decode(&block).unwrap(),
+            headers(&[(":status", "200")])
+        );
+        assert_eq!(decoder.table.max_size, 100);
+    }
+}
@@ -0,0 +1,278 @@
+//! HTTP/2 (RFC 9113)。
+//!
+//! The parts of HTTP/2 that `Server` speaks: frames, HPACK, SETTINGS and
+//! error codes. `Server::with_http2` serves cleartext HTTP/2 (h2c) to
+//! clients that start their connections with the HTTP/2 preface, known as
+//! prior knowledge; upgrading from HTTP/1.1 and TLS with ALPN are not
+//! supported.
+//!
+//! Each request stream is answered by the router on a `ThreadPool` worker
+//! of its own, while the worker that accepted the connection keeps reading
+//! frames, so one slow handler does not hold up the other streams.
+
+pub mod frame;
+pub mod hpack;
+
+pub use self::frame::{Frame, Kind};
+
+use std::fmt;
+use std::io;
+
+/// クライアントが接続の最初に送る文字列。
+///
+/// The connection preface every HTTP/2 client sends first.
+pub const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The flow-control window of new connections and streams.
+pub const DEFAULT_WINDOW: u32 = 65_535;
+/// The largest a flow-control window may grow.
+pub const MAX_WINDOW: u32 = (1 << 31) - 1;
+/// The largest frame payload either side accepts until told otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE_LIMIT: u32 = (1 << 24) - 1;
+
+/// RST_STREAMやGOAWAYで送るエラーコード。
+///
+/// An error code, as sent in RST_STREAM and GOAWAY frames. Unknown codes
+/// are kept as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode(pub u32);
+
+impl ErrorCode {
+    pub const NO_ERROR: ErrorCode = ErrorCode(0x0);
+    pub const PROTOCOL_ERROR: ErrorCode = ErrorCode(0x1);
+    pub const INTERNAL_ERROR: ErrorCode = ErrorCode(0x2);
+    pub const FLOW_CONTROL_ERROR: ErrorCode = ErrorCode(0x3);
+    pub const SETTINGS_TIMEOUT: ErrorCode = ErrorCode(0x4);
+    pub const STREAM_CLOSED: ErrorCode = ErrorCode(0x5);
+    pub const FRAME_SIZE_ERROR: ErrorCode = ErrorCode(0x6);
+    pub const REFUSED_STREAM: ErrorCode = ErrorCode(0x7);
+    pub const CANCEL: ErrorCode = ErrorCode(0x8);
+    pub const COMPRESSION_ERROR: ErrorCode = ErrorCode(0x9);
+    pub const CONNECT_ERROR: ErrorCode = ErrorCode(0xA);
+    pub const ENHANCE_YOUR_CALM: ErrorCode = ErrorCode(0xB);
+    pub const INADEQUATE_SECURITY: ErrorCode = ErrorCode(0xC);
+    pub const HTTP_1_1_REQUIRED: ErrorCode = ErrorCode(0xD);
+
+    fn name(self) -> Option<&'static str> {
+        const NAMES: [&str; 14] = [
+            "NO_ERROR",
+            "PROTOCOL_ERROR",
+            "INTERNAL_ERROR",
+            "FLOW_CONTROL_ERROR",
+            "SETTINGS_TIMEOUT",
+            "STREAM_CLOSED",
+            "FRAME_SIZE_ERROR",
+            "REFUSED_STREAM",
+            "CANCEL",
+            "COMPRESSION_ERROR",
+            "CONNECT_ERROR",
+            "ENHANCE_YOUR_CALM",
+            "INADEQUATE_SECURITY",
+            "HTTP_1_1_REQUIRED",
+        ];
+        NAMES.get(self.0 as usize).copied()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "error code {:#x}", self.0),
+        }
+    }
+}
+
+/// HTTP/2の接続で失敗した理由。
+///
+/// Why an HTTP/2 connection or stream failed.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer broke the protocol in a way that affects the whole
+    /// connection, which is closed with a GOAWAY carrying the code.
+    Connection(ErrorCode, &'static str),
+    /// The peer broke the protocol on one stream, which is reset with the
+    /// code; the connection goes on.
+    Stream(u32, ErrorCode, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Connection(code, reason) => write!(f, "connection error {}: {}", code, reason),
+            Error::Stream(id, code, reason) => {
+                write!(f, "stream {} error {}: {}", id, code, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+fn protocol(reason: &'static str) -> Error {
+    Error::Connection(ErrorCode::PROTOCOL_ERROR, reason)
+}
+
+/// SETTINGSで伝える、接続の設定。
+///
+/// The settings one side of a connection announces in its SETTINGS frames.
+/// `Default` gives the values that hold before any SETTINGS arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    /// `None` for no limit.
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    /// `None` for no limit.
+    pub max_header_list_size: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            header_table_size: hpack::DEFAULT_TABLE_SIZE as u32,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None,
+        }
+    }
+}
+
+// SETTINGSのパラメーターの識別子
+const HEADER_TABLE_SIZE: u16 = 0x1;
+const ENABLE_PUSH: u16 = 0x2;
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+impl Settings {
+    /// Applies the parameters in the payload of a SETTINGS frame, in order.
+    /// Unknown parameters are ignored.
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(
+                ErrorCode::FRAME_SIZE_ERROR,
+                "SETTINGS length not a multiple of 6",
+            ));
+        }
+        for parameter in payload.chunks(6) {
+            let id = u16::from_be_bytes([parameter[0], parameter[1]]);
+            let value =
+                u32::from_be_bytes([parameter[2], parameter[3], parameter[4], parameter[5]]);
+            match id {
+                HEADER_TABLE_SIZE => self.header_table_size = value,
+                ENABLE_PUSH if value > 1 => return Err(protocol("invalid ENABLE_PUSH")),
+                ENABLE_PUSH => self.enable_push = value == 1,
+                MAX_CONCURRENT_STREAMS => self.max_concurrent_streams = Some(value),
+                INITIAL_WINDOW_SIZE if value > MAX_WINDOW => {
+                    return Err(Error::Connection(
+                        ErrorCode::FLOW_CONTROL_ERROR,
+                        "INITIAL_WINDOW_SIZE too large",
+                    ));
+                }
+                INITIAL_WINDOW_SIZE => self.initial_window_size = value,
+                MAX_FRAME_SIZE
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) =>
+                {
+                    return Err(protocol("invalid MAX_FRAME_SIZE"));
+                }
+                MAX_FRAME_SIZE => self.max_frame_size = value,
+                MAX_HEADER_LIST_SIZE => self.max_header_list_size = Some(value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// A SETTINGS frame with the parameters that differ from the defaults.
+    pub fn to_frame(&self) -> Frame {
+        let defaults = Settings::default();
+        let mut parameters = Vec::new();
+        if self.header_table_size != defaults.header_table_size {
+            parameters.push((HEADER_TABLE_SIZE, self.header_table_size));
+        }
+        if self.enable_push != defaults.enable_push {
+            parameters.push((ENABLE_PUSH, self.enable_push as u32));
+        }
+        if let Some(max) = self.max_concurrent_streams {
+            parameters.push((MAX_CONCURRENT_STREAMS, max));
+        }
+        if self.initial_window_size != defaults.initial_window_size {
+            parameters.push((INITIAL_WINDOW_SIZE, self.initial_window_size));
+        }
+        if self.max_frame_size != defaults.max_frame_size {
+            parameters.push((MAX_FRAME_SIZE, self.max_frame_size));
+        }
+        if let Some(max) = self.max_header_list_size {
+            parameters.push((MAX_HEADER_LIST_SIZE, max));
+        }
+        Frame::settings(&parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = Settings {
+            enable_push: false,
+            max_concurrent_streams: Some(100),
+            initial_window_size: 1 << 20,
+            max_header_list_size: Some(8192),
+            ..Settings::default()
+        };
+        let frame = settings.to_frame();
+        assert_eq!(frame.kind, Kind::Settings);
+        assert_eq!(frame.payload.len(), 4 * 6);
+
+        let mut applied = Settings::default();
+        applied.apply(&frame.payload).unwrap();
+        assert_eq!(applied, settings);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let parameter = |id: u16, value: u32| {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend_from_slice(&value.to_be_bytes());
+            payload
+        };
+        let mut settings = Settings::default();
+        assert!(settings.apply(&parameter(ENABLE_PUSH, 2)).is_err());
+        assert!(matches!(
+            settings.apply(&parameter(INITIAL_WINDOW_SIZE, 1 << 31)),
+            Err(Error::Connection(ErrorCode::FLOW_CONTROL_ERROR, _))
+        ));
+        assert!(settings.apply(&parameter(MAX_FRAME_SIZE, 100)).is_err());
+        assert!(settings.apply(&[0, 1, 0]).is_err());
+        // 知らないパラメーターは無視します
+        assert!(settings.apply(&parameter(0xFF, 1)).is_ok());
+        assert_eq!(settings, Settings::default());
+
+        assert_eq!(ErrorCode::REFUSED_STREAM.to_string(), "REFUSED_STREAM");
+        assert_eq!(ErrorCode(0x42).to_string(), "error code 0x42");
+    }
+}
@@ -1,117 +1,44 @@
-use std::sync::mpsc;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
-}
-trait FnBox {
-    fn call_box(self: Box<Self>);
-}
-
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)()
-    }
-}
-enum Message {
-    NewJob(Job),
-    Terminate,
-}
-type Job = Box<dyn FnBox + Send + 'static>;
-impl ThreadPool {
-    /// 新しいThreadPoolを生成する。
-    ///
-    /// sizeがプールのスレッド数です。
-    ///
-    /// # パニック
-    ///
-    /// sizeが0なら、`new`関数はパニックします。
-    ///
-    /// Create a new ThreadPool.
-    ///
-    /// The size is the number of threads in the pool.
-    ///
-    /// # Panics
-    ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool { workers, sender }
-    }
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-
-        self.sender.send(Message::NewJob(job)).unwrap();
-    }
-}
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                let message = receiver.lock().unwrap().recv().unwrap();
-
-                match message {
-                    Message::NewJob(job) => {
-                        println!("Worker {} got a job; executing.", id);
-
-                        job.call_box();
-                    }
-                    Message::Terminate => {
-                        // ワーカー{}は停止するよう指示された
-                        println!("Worker {} was told to terminate.", id);
-
-                        break;
-                    }
-                }
-            }
-        });
-
-        Worker {
-            id,
-            thread: Some(thread),
-        }
-    }
-}
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
-
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
-        }
-
-        // 全ワーカーを閉じます
-        println!("Shutting down all workers.");
-
-        for worker in &mut self.workers {
-            // ワーカー{}を閉じます
-            println!("Shutting down worker {}", worker.id);
-
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
-    }
+#[macro_use]
+pub mod log;
+pub mod cgi;
+pub mod compression;
+pub mod config;
+pub mod date;
+pub mod http2;
+pub mod metrics;
+pub mod middleware;
+pub mod pages;
+mod pool;
+pub mod proxy;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+#[cfg(unix)]
+pub mod signal;
+pub mod static_files;
+pub mod template;
+pub mod testing;
+pub mod tls;
+pub mod websocket;
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+pub use pool::{
+    Executor, JobError, JobHandle, PoolCreationError, PoolStats, QueueFull, QueuePolicy,
+    ShutdownTimeout, StatsHandle, ThreadPool, ThreadPoolBuilder, TimerHandle, panic_message,
+};
+
+/// サーバーの名前とバージョン。
+///
+/// The name and version the server reports, e.g. to CGI scripts and on
+/// its pages.
+pub const SERVER_SOFTWARE: &str = concat!("hello/", env!("CARGO_PKG_VERSION"));
+
+/// ロックを取ります。
+///
+/// Locks `mutex`, carrying on with its data even if a thread panicked
+/// while holding it.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
@@ -0,0 +1,165 @@
+//! サーバーとスレッドプールのログ出力。
+//!
+//! Logging for the server and the thread pool.
+//!
+//! Messages go through the `error!`, `warn!`, `info!` and `debug!` macros to
+//! one process-wide `Logger`. The default logger writes access-log lines to
+//! stdout as they are and everything else to stderr; install another one with
+//! `set_logger` to send them elsewhere.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// アクセスログの行に使うターゲット。
+///
+/// The target of access-log records, one Common Log Format line each.
+pub const ACCESS_TARGET: &str = "hello::access";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    fn from_usize(n: usize) -> Option<Level> {
+        match n {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 1件のログ。
+///
+/// One log message, passed to `Logger::log`.
+pub struct Record<'a> {
+    pub level: Level,
+    /// Where the message comes from: a module path, or `ACCESS_TARGET`.
+    pub target: &'a str,
+    pub args: fmt::Arguments<'a>,
+}
+
+/// ログの出力先。
+///
+/// Receives every record at or below the maximum level.
+pub trait Logger: Send + Sync {
+    fn log(&self, record: &Record);
+}
+
+/// 標準のログ出力先。
+///
+/// Writes access-log lines to stdout unchanged, and other records to stderr
+/// prefixed with their level and target.
+pub struct StdLogger;
+
+impl Logger for StdLogger {
+    fn log(&self, record: &Record) {
+        // 出力できなくても、サーバーは止めません
+        if record.target == ACCESS_TARGET {
+            let _ = writeln!(io::stdout().lock(), "{}", record.args);
+        } else {
+            let _ = writeln!(
+                io::stderr().lock(),
+                "[{} {}] {}",
+                record.level,
+                record.target,
+                record.args
+            );
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static LOGGER: RwLock<Option<Arc<dyn Logger>>> = RwLock::new(None);
+
+/// ログの出力先を入れ替えます。
+///
+/// Replaces the process-wide logger.
+pub fn set_logger<L: Logger + 'static>(logger: L) {
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(logger));
+}
+
+/// Only records at `level` or more severe are logged; `None` turns logging
+/// off. The default is `Level::Info`.
+pub fn set_max_level(level: Option<Level>) {
+    MAX_LEVEL.store(level.map_or(0, |l| l as usize), Ordering::Relaxed);
+}
+
+pub fn max_level() -> Option<Level> {
+    Level::from_usize(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Logs one record; used by the logging macros.
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let record = Record {
+        level,
+        target,
+        args,
+    };
+    let logger = LOGGER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match logger {
+        Some(logger) => logger.log(&record),
+        None => StdLogger.log(&record),
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Error, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Warn, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Info, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Debug, module_path!(), format_args!($($arg)+))
+    };
+}
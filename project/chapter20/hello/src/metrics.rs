@@ -0,0 +1,295 @@
+//! リクエストとスレッドプールの計測値。
+//!
+//! Request and thread pool metrics, rendered in the Prometheus text
+//! exposition format.
+
+use crate::pool::PoolStats;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// レイテンシのヒストグラムの区切り(秒)。
+///
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// 固定の区切りを持つヒストグラム。
+///
+/// A histogram over `LATENCY_BUCKETS`. Each bucket counts only its own
+/// observations; `render` makes them cumulative.
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let index = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                with_comma(labels),
+                le,
+                cumulative
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), self.count());
+    }
+}
+
+/// サーバー全体の計測値。
+///
+/// Counters and histograms collected by the server for every request.
+#[derive(Default)]
+pub struct Metrics {
+    // (メソッド, ステータス)ごとのリクエスト数
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    // メソッドごとのレイテンシ
+    latency: Mutex<BTreeMap<String, Arc<Histogram>>>,
+    bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Records one answered request.
+    pub fn record_request(&self, method: &str, status: u16, bytes: u64, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((method.to_string(), status))
+            .or_insert(0) += 1;
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+
+        // 観測中はロックを外しておきます
+        let histogram = self
+            .latency
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(method.to_string())
+            .or_default()
+            .clone();
+        histogram.observe(elapsed);
+    }
+
+    /// The number of requests recorded so far.
+    pub fn requests_total(&self) -> u64 {
+        self.requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .sum()
+    }
+
+    /// Renders the request metrics and `pool`'s counters in the Prometheus
+    /// text format.
+    pub fn render(&self, pool: &PoolStats) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests answered, by method and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((method, status), count) in self
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                escape(method),
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time to handle and answer a request.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for (method, histogram) in self
+            .latency
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            histogram.render(
+                &mut out,
+                "http_request_duration_seconds",
+                &format!("method=\"{}\"", escape(method)),
+            );
+        }
+
+        metric(
+            &mut out,
+            "http_response_bytes_total",
+            "counter",
+            "Response body bytes sent.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "threadpool_queue_depth",
+            "gauge",
+            "Jobs waiting for a worker.",
+            pool.queued,
+        );
+        metric(
+            &mut out,
+            "threadpool_workers",
+            "gauge",
+            "Worker threads running.",
+            pool.workers,
+        );
+        metric(
+            &mut out,
+            "threadpool_active_workers",
+            "gauge",
+            "Workers running a job.",
+            pool.active,
+        );
+        metric(
+            &mut out,
+            "threadpool_jobs_completed_total",
+            "counter",
+            "Jobs that ran to completion.",
+            pool.jobs_completed,
+        );
+        metric(
+            &mut out,
+            "threadpool_jobs_panicked_total",
+            "counter",
+            "Jobs that panicked.",
+            pool.jobs_panicked,
+        );
+        metric(
+            &mut out,
+            "threadpool_jobs_rejected_total",
+            "counter",
+            "Jobs rejected because the queue was full.",
+            pool.jobs_rejected,
+        );
+        metric(
+            &mut out,
+            "threadpool_jobs_dropped_total",
+            "counter",
+            "Queued jobs dropped to make room for newer ones.",
+            pool.jobs_dropped,
+        );
+
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn with_comma(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{},", labels)
+    }
+}
+
+/// Escapes a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.record_request("GET", 200, 10, Duration::from_millis(3));
+        metrics.record_request("GET", 200, 10, Duration::from_millis(30));
+        metrics.record_request("GET", 404, 5, Duration::from_secs(20));
+        let pool = PoolStats {
+            workers: 4,
+            active: 1,
+            queued: 2,
+            jobs_completed: 7,
+            ..PoolStats::default()
+        };
+
+        let text = metrics.render(&pool);
+        assert!(text.contains("http_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{method=\"GET\",status=\"404\"} 1\n"));
+        assert!(
+            text.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"0.005\"} 1\n")
+        );
+        assert!(
+            text.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"0.05\"} 2\n")
+        );
+        assert!(
+            text.contains("http_request_duration_seconds_bucket{method=\"GET\",le=\"+Inf\"} 3\n")
+        );
+        assert!(text.contains("http_request_duration_seconds_count{method=\"GET\"} 3\n"));
+        assert!(text.contains("http_response_bytes_total 25\n"));
+        assert!(text.contains("threadpool_queue_depth 2\n"));
+        assert!(text.contains("threadpool_active_workers 1\n"));
+        assert!(text.contains("threadpool_jobs_completed_total 7\n"));
+    }
+}
@@ -0,0 +1,70 @@
+//! `hello`の見本のページ。
+//!
+//! The sample pages `hello` serves when no document root is configured.
+
+use crate::SERVER_SOFTWARE;
+use crate::request::Request;
+use crate::router::Router;
+use crate::template::{Context, Templates};
+use crate::websocket;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// `hello.html`と`404.html`を返す見本のページ。
+///
+/// The sample pages: `/` answers with `hello.html`, `/sleep` with the same
+/// page after a delay, `/echo` is a WebSocket echo and every other path gets
+/// `404.html`. The pages are templates in the directory given to `new`,
+/// rendered with the request's `method` and `path` and the `server`'s name
+/// and version; edits to them show up on the next request.
+#[derive(Debug, Clone)]
+pub struct Pages {
+    templates: Arc<Templates>,
+    sleep: Duration,
+}
+
+impl Pages {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Pages {
+        Pages {
+            templates: Arc::new(Templates::new(dir)),
+            sleep: Duration::from_secs(5),
+        }
+    }
+
+    /// How long `/sleep` waits before answering; 5 seconds by default.
+    pub fn with_sleep(mut self, sleep: Duration) -> Pages {
+        self.sleep = sleep;
+        self
+    }
+
+    /// Adds the pages to `router`.
+    pub fn routes(&self, router: Router) -> Router {
+        let (templates, sleepy_templates, missing_templates) = (
+            Arc::clone(&self.templates),
+            Arc::clone(&self.templates),
+            Arc::clone(&self.templates),
+        );
+        let sleep = self.sleep;
+        router
+            .get("/", move |request, _| {
+                templates.response(200, "hello.html", &context(request))
+            })
+            .get("/sleep", move |request, _| {
+                thread::sleep(sleep);
+                sleepy_templates.response(200, "hello.html", &context(request))
+            })
+            .get("/echo", websocket::handler(websocket::echo))
+            .not_found(move |request, _| {
+                missing_templates.response(404, "404.html", &context(request))
+            })
+    }
+}
+
+fn context(request: &Request) -> Context {
+    Context::new()
+        .with("method", request.method.as_str())
+        .with("path", request.path())
+        .with("server", SERVER_SOFTWARE)
+}
@@ -0,0 +1,222 @@
+use super::scheduler::{QueuePolicy, Scheduler};
+use super::{Shared, ThreadPool, WorkerConfig, WorkerHook};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// ThreadPoolを作れなかった理由。
+///
+/// Why `ThreadPoolBuilder::build` could not create a pool.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The maximum number of workers was zero.
+    ZeroWorkers,
+    /// The minimum number of workers was larger than the maximum.
+    MinAboveMax { min: usize, max: usize },
+    /// A bounded queue was requested with a capacity of zero.
+    ZeroQueueCapacity,
+    /// The operating system refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroWorkers => f.write_str("a pool needs at least one worker"),
+            PoolCreationError::MinAboveMax { min, max } => write!(
+                f,
+                "minimum workers ({}) is larger than maximum workers ({})",
+                min, max
+            ),
+            PoolCreationError::ZeroQueueCapacity => f.write_str("queue capacity must not be zero"),
+            PoolCreationError::Spawn(e) => write!(f, "failed to start a worker thread: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PoolCreationError::Spawn(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// ThreadPoolの設定を組み立てます。
+///
+/// ワーカー数は`min_workers`から始まり、空いているワーカーがいないときに
+/// `max_workers`まで増えます。`idle_timeout`の間ジョブがなかったワーカーは、
+/// `min_workers`を下回らない範囲で終了します。
+///
+/// Configures and creates a `ThreadPool`.
+///
+/// The pool starts `min_workers` threads and starts more, up to
+/// `max_workers`, when a job is queued while no worker is idle. Workers above
+/// the minimum exit after `idle_timeout` without a job.
+///
+/// ```
+/// use hello::{QueuePolicy, ThreadPoolBuilder};
+/// use std::time::Duration;
+///
+/// let pool = ThreadPoolBuilder::new()
+///     .min_workers(2)
+///     .max_workers(8)
+///     .idle_timeout(Duration::from_secs(30))
+///     .thread_name_prefix("http-")
+///     .queue_capacity(100)
+///     .queue_policy(QueuePolicy::Reject)
+///     .build()
+///     .unwrap();
+/// pool.execute(|| println!("hello from the pool"));
+/// ```
+pub struct ThreadPoolBuilder {
+    min_workers: usize,
+    max_workers: usize,
+    idle_timeout: Duration,
+    thread_name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    queue_policy: QueuePolicy,
+    on_worker_start: Option<Arc<WorkerHook>>,
+    on_worker_stop: Option<Arc<WorkerHook>>,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Starts with one worker per available CPU, an unbounded queue and
+    /// a 60 second idle timeout.
+    pub fn new() -> ThreadPoolBuilder {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        ThreadPoolBuilder {
+            min_workers: cpus,
+            max_workers: cpus,
+            idle_timeout: Duration::from_secs(60),
+            thread_name_prefix: None,
+            stack_size: None,
+            queue_capacity: None,
+            queue_policy: QueuePolicy::Block,
+            on_worker_start: None,
+            on_worker_stop: None,
+        }
+    }
+
+    /// Sets a fixed number of workers (both the minimum and the maximum).
+    pub fn num_workers(mut self, n: usize) -> ThreadPoolBuilder {
+        self.min_workers = n;
+        self.max_workers = n;
+        self
+    }
+
+    pub fn min_workers(mut self, n: usize) -> ThreadPoolBuilder {
+        self.min_workers = n;
+        self
+    }
+
+    pub fn max_workers(mut self, n: usize) -> ThreadPoolBuilder {
+        self.max_workers = n;
+        self
+    }
+
+    /// How long a worker above the minimum waits for a job before exiting.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Names worker threads `<prefix><id>`.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> ThreadPoolBuilder {
+        self.thread_name_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Sets the stack size of worker threads, in bytes.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Limits the queue to `capacity` jobs; see `queue_policy`.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What `execute` does when a bounded queue is full.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> ThreadPoolBuilder {
+        self.queue_policy = policy;
+        self
+    }
+
+    /// Called with the worker id on each worker thread when it starts,
+    /// including threads started to replace a worker that died.
+    pub fn on_worker_start<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_worker_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called with the worker id on each worker thread when it stops, either
+    /// because the pool shut down or because it was idle too long.
+    pub fn on_worker_stop<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_worker_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// 設定に従ってThreadPoolを作り、最小数のワーカーを起動します。
+    ///
+    /// Creates the pool and starts its minimum number of workers.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.max_workers == 0 {
+            return Err(PoolCreationError::ZeroWorkers);
+        }
+        if self.min_workers > self.max_workers {
+            return Err(PoolCreationError::MinAboveMax {
+                min: self.min_workers,
+                max: self.max_workers,
+            });
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroQueueCapacity);
+        }
+
+        let config = WorkerConfig {
+            // 最低1つのワーカーは残し、キューのジョブが必ず実行されるようにします
+            min_workers: self.min_workers.max(1),
+            max_workers: self.max_workers,
+            idle_timeout: self.idle_timeout,
+            thread_name_prefix: self.thread_name_prefix,
+            stack_size: self.stack_size,
+            on_start: self.on_worker_start,
+            on_stop: self.on_worker_stop,
+        };
+        let queue = Scheduler::new(self.max_workers, self.queue_capacity);
+        let pool = ThreadPool {
+            shared: Arc::new(Shared::new(queue, config)),
+            policy: self.queue_policy,
+            timer: OnceLock::new(),
+            terminated: false,
+        };
+
+        for _ in 0..pool.shared.config.min_workers {
+            // 失敗したときは、起動済みのワーカーをpoolのdropで止めます
+            pool.shared
+                .start_worker()
+                .map_err(PoolCreationError::Spawn)?;
+        }
+
+        Ok(pool)
+    }
+}
@@ -0,0 +1,198 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// ジョブの結果を受け取れなかった理由。
+///
+/// Why a `JobHandle` did not produce a value.
+pub enum JobError {
+    /// The job panicked; holds the panic payload.
+    Panicked(Box<dyn Any + Send>),
+    /// The job was cancelled before a worker started it.
+    Cancelled,
+    /// The result was already taken by an earlier `try_join` or
+    /// `join_timeout`.
+    AlreadyJoined,
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(payload) => f
+                .debug_tuple("Panicked")
+                .field(&super::panic_message(&**payload))
+                .finish(),
+            JobError::Cancelled => f.write_str("Cancelled"),
+            JobError::AlreadyJoined => f.write_str("AlreadyJoined"),
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(payload) => {
+                write!(f, "job panicked: {}", super::panic_message(&**payload))
+            }
+            JobError::Cancelled => f.write_str("job was cancelled"),
+            JobError::AlreadyJoined => f.write_str("job result was already taken"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+enum Slot<T> {
+    Queued,
+    Running,
+    Finished(Result<T, JobError>),
+    Joined,
+}
+
+/// ジョブとハンドルの間で共有する状態。
+///
+/// The state a spawned job shares with its `JobHandle`.
+pub(crate) struct JobState<T> {
+    slot: Mutex<Slot<T>>,
+    done: Condvar,
+}
+
+impl<T> JobState<T> {
+    pub(crate) fn new() -> JobState<T> {
+        JobState {
+            slot: Mutex::new(Slot::Queued),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Called by the worker before running the job. Returns false if the
+    /// job was cancelled and must not run.
+    pub(crate) fn start(&self) -> bool {
+        let mut slot = self.lock();
+        match *slot {
+            Slot::Queued => {
+                *slot = Slot::Running;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn finish(&self, result: Result<T, JobError>) {
+        *self.lock() = Slot::Finished(result);
+        self.done.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Slot<T>> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 実行されずに捨てられたジョブを取り消し扱いにします。
+///
+/// Owned by a spawned job's closure. If the closure is dropped without
+/// running (rejected or dropped from a full queue), the handle sees
+/// `JobError::Cancelled` instead of waiting forever.
+pub(crate) struct CancelOnDrop<T>(pub(crate) Arc<JobState<T>>);
+
+impl<T> Drop for CancelOnDrop<T> {
+    fn drop(&mut self) {
+        let mut slot = self.0.lock();
+        if let Slot::Queued = *slot {
+            *slot = Slot::Finished(Err(JobError::Cancelled));
+            self.0.done.notify_all();
+        }
+    }
+}
+
+/// `ThreadPool::spawn`で起動したジョブの結果を待つためのハンドル。
+///
+/// ハンドルを捨ててもジョブは実行されます。
+///
+/// A handle to the result of a job started with `ThreadPool::spawn`.
+///
+/// Dropping the handle does not cancel the job.
+pub struct JobHandle<T> {
+    state: Arc<JobState<T>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(state: Arc<JobState<T>>) -> JobHandle<T> {
+        JobHandle { state }
+    }
+
+    /// ジョブが終わるまで待ち、その結果を返します。
+    ///
+    /// Waits for the job to finish and returns its value.
+    pub fn join(self) -> Result<T, JobError> {
+        let mut slot = self.state.lock();
+        loop {
+            if let Some(result) = take_finished(&mut slot) {
+                return result;
+            }
+            slot = self
+                .state
+                .done
+                .wait(slot)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Returns the result if the job has finished, without waiting.
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        take_finished(&mut self.state.lock())
+    }
+
+    /// Waits at most `timeout` for the job to finish.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        let deadline = Instant::now() + timeout;
+        let mut slot = self.state.lock();
+        loop {
+            if let Some(result) = take_finished(&mut slot) {
+                return Some(result);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            slot = self
+                .state
+                .done
+                .wait_timeout(slot, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// まだ開始していないジョブを取り消します。
+    ///
+    /// Cancels the job if no worker has started it yet, and returns whether
+    /// it did. A cancelled job's `join` returns `JobError::Cancelled`.
+    pub fn cancel(&self) -> bool {
+        let mut slot = self.state.lock();
+        match *slot {
+            Slot::Queued => {
+                *slot = Slot::Finished(Err(JobError::Cancelled));
+                self.state.done.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.state.lock(), Slot::Finished(_) | Slot::Joined)
+    }
+}
+
+fn take_finished<T>(slot: &mut Slot<T>) -> Option<Result<T, JobError>> {
+    match slot {
+        Slot::Queued | Slot::Running => None,
+        Slot::Joined => Some(Err(JobError::AlreadyJoined)),
+        Slot::Finished(_) => match std::mem::replace(slot, Slot::Joined) {
+            Slot::Finished(result) => Some(result),
+            _ => unreachable!(),
+        },
+    }
+}
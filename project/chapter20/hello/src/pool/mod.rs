@@ -0,0 +1,1138 @@
+mod builder;
+mod job;
+mod scheduler;
+mod timer;
+
+pub use self::builder::{PoolCreationError, ThreadPoolBuilder};
+pub use self::job::{JobError, JobHandle};
+pub use self::scheduler::QueuePolicy;
+pub use self::timer::TimerHandle;
+
+use self::job::JobState;
+use self::scheduler::{Popped, Pushed, Scheduler};
+use self::timer::Timer;
+use crate::lock;
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    policy: QueuePolicy,
+    // 最初に予約されたときに起動します
+    timer: OnceLock<Timer>,
+    terminated: bool,
+}
+trait FnBox {
+    fn call_box(self: Box<Self>);
+}
+
+impl<F: FnOnce()> FnBox for F {
+    fn call_box(self: Box<F>) {
+        (*self)()
+    }
+}
+type Job = Box<dyn FnBox + Send + 'static>;
+type PanicHandler = dyn Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static;
+type WorkerHook = dyn Fn(usize) + Send + Sync + 'static;
+
+/// `ThreadPoolBuilder`で決めた、ワーカースレッドの設定。
+///
+/// How worker threads are started and scaled, fixed when the pool is built.
+struct WorkerConfig {
+    min_workers: usize,
+    max_workers: usize,
+    idle_timeout: Duration,
+    thread_name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    on_start: Option<Arc<WorkerHook>>,
+    on_stop: Option<Arc<WorkerHook>>,
+}
+
+/// 全ワーカーで共有する状態。
+///
+/// State shared by every worker thread, including respawned ones.
+struct Shared {
+    queue: Scheduler,
+    config: WorkerConfig,
+    /// One slot per possible worker; `config.max_workers` in total.
+    workers: Vec<Worker>,
+    /// Worker threads currently running (or about to start).
+    live: AtomicUsize,
+    // ワーカーを同時に2つ起動して、同じスロットを使わないようにします
+    starting: Mutex<()>,
+    panic_handler: RwLock<Option<Arc<PanicHandler>>>,
+    jobs_completed: AtomicUsize,
+    jobs_panicked: AtomicUsize,
+    jobs_rejected: AtomicUsize,
+    jobs_dropped: AtomicUsize,
+    workers_respawned: AtomicUsize,
+}
+
+/// プールの統計情報。
+///
+/// A snapshot of the pool's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    /// Workers running a job rather than waiting for one.
+    pub active: usize,
+    /// Jobs waiting in the queue for a worker.
+    pub queued: usize,
+    pub jobs_completed: usize,
+    pub jobs_panicked: usize,
+    /// Jobs dropped by `QueuePolicy::Reject`.
+    pub jobs_rejected: usize,
+    /// Jobs dropped by `QueuePolicy::DropOldest` to make room.
+    pub jobs_dropped: usize,
+    pub workers_respawned: usize,
+}
+
+/// `try_execute`に渡したジョブ。キューが一杯だったので返されます。
+///
+/// Returned by `ThreadPool::try_execute` when the queue is full, holding the
+/// job that was not queued.
+pub struct QueueFull<F>(pub F);
+
+impl<F> QueueFull<F> {
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+
+impl<F> fmt::Debug for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("QueueFull(..)")
+    }
+}
+
+impl<F> fmt::Display for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("thread pool queue is full")
+    }
+}
+
+impl<F> std::error::Error for QueueFull<F> {}
+impl ThreadPool {
+    /// 新しいThreadPoolを生成する。
+    ///
+    /// sizeがプールのスレッド数です。
+    ///
+    /// # パニック
+    ///
+    /// sizeが0なら、`new`関数はパニックします。
+    ///
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        ThreadPoolBuilder::new()
+            .num_workers(size)
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// 待ち行列の長さに上限があるThreadPoolを生成する。
+    ///
+    /// Create a ThreadPool whose queue holds at most `capacity` jobs.
+    /// `policy` decides what `execute` does when the queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` or `capacity` is zero.
+    pub fn bounded(size: usize, capacity: usize, policy: QueuePolicy) -> ThreadPool {
+        assert!(size > 0);
+        assert!(capacity > 0);
+
+        ThreadPoolBuilder::new()
+            .num_workers(size)
+            .queue_capacity(capacity)
+            .queue_policy(policy)
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// 設定を細かく指定してThreadPoolを作ります。
+    ///
+    /// Returns a builder for a pool with more options than `new` and
+    /// `bounded`, such as a varying number of workers.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.submit(Box::new(f), self.policy);
+    }
+
+    /// `delay`が経ってからジョブを実行します。
+    ///
+    /// Runs `f` on the pool once `delay` has passed. See `execute_at`.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_at(Instant::now() + delay, f)
+    }
+
+    /// 指定した時刻にジョブを実行します。
+    ///
+    /// Runs `f` on the pool once `at` has passed. A timer thread, started the
+    /// first time a job is scheduled, hands the job to the queue when it is
+    /// due, to within about 10ms; the queue policy then applies as for
+    /// `execute`, except that `CallerRuns` waits for room instead. Jobs that
+    /// are not due yet when the pool shuts down are dropped.
+    pub fn execute_at<F>(&self, at: Instant, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.timer().once(at, Box::new(f))
+    }
+
+    /// `interval`ごとにジョブを繰り返し実行します。
+    ///
+    /// Runs `f` on the pool every `interval`, starting one `interval` from
+    /// now, until the returned handle is cancelled or the pool shuts down.
+    /// Runs never overlap: if the previous run has not finished when the
+    /// next is due, that run is skipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero());
+
+        self.timer().every(interval, Arc::new(f))
+    }
+
+    fn timer(&self) -> &Timer {
+        self.timer
+            .get_or_init(|| Timer::start(&self.shared, self.policy))
+    }
+
+    /// キューに空きがあるときだけジョブを追加します。待ちません。
+    ///
+    /// Queues `f` only if the queue has room, without blocking and whatever
+    /// the policy; otherwise returns it in `QueueFull`.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.try_submit(f)
+    }
+
+    /// ジョブを実行し、結果を受け取るためのハンドルを返します。
+    ///
+    /// Runs `f` on the pool and returns a handle to its return value.
+    ///
+    /// A panic in `f` is caught and returned from the handle's `join` as
+    /// `JobError::Panicked`, so it does not reach the `on_panic` handler.
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = Arc::new(JobState::new());
+        // キューから捨てられたジョブは、ハンドル側では取り消しとして扱います
+        let guard = job::CancelOnDrop(Arc::clone(&state));
+
+        self.execute(move || {
+            let job_state = &guard.0;
+            if !job_state.start() {
+                return;
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JobError::Panicked);
+            job_state.finish(result);
+        });
+
+        JobHandle::new(state)
+    }
+
+    /// ジョブがパニックしたときに呼ばれる関数を設定します。
+    ///
+    /// Sets a callback that receives the worker id and panic payload of every
+    /// job that panics. Without one, the panic message is logged.
+    pub fn on_panic<F>(&self, handler: F)
+    where
+        F: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .panic_handler
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(handler));
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// 別のスレッドから統計情報を読むためのハンドルを返します。
+    ///
+    /// Returns a handle that reads this pool's stats without owning the
+    /// pool, e.g. from a job running on it.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle(Arc::clone(&self.shared))
+    }
+
+    /// 別のスレッドからジョブを追加するためのハンドルを返します。
+    ///
+    /// Returns a handle that queues jobs on this pool without owning it,
+    /// e.g. from a job running on it.
+    pub fn executor(&self) -> Executor {
+        Executor(Arc::clone(&self.shared))
+    }
+
+    /// プールを停止します。キューに残っているジョブは実行してから停止し、
+    /// `timeout`以内に止まらなかったワーカーを報告します。
+    ///
+    /// Shuts the pool down. Jobs already queued are still run; workers that
+    /// have not finished within `timeout` are left running detached and
+    /// reported in the error.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Result<(), ShutdownTimeout> {
+        let stuck = self.terminate(Some(Instant::now() + timeout));
+
+        if stuck.is_empty() {
+            Ok(())
+        } else {
+            Err(ShutdownTimeout { workers: stuck })
+        }
+    }
+
+    /// Stops every worker, waiting for them until `deadline` (or forever),
+    /// and returns the ids of the workers that were still running.
+    fn terminate(&mut self, deadline: Option<Instant>) -> Vec<usize> {
+        self.terminated = true;
+        // 期限の来ていないジョブは捨て、これ以上キューに入らないようにします
+        if let Some(mut timer) = self.timer.take() {
+            timer.stop();
+        }
+        debug!("Sending terminate message to all workers.");
+
+        self.shared.queue.terminate();
+
+        // 全ワーカーを閉じます
+        info!("Shutting down all workers.");
+
+        let mut stuck = Vec::new();
+
+        for worker in &self.shared.workers {
+            // ワーカー{}を閉じます
+            debug!("Shutting down worker {}", worker.id);
+
+            // 落ちたスレッドは入れ替わっているので、最後のスレッドまで待ちます
+            while let Some(thread) = worker.take_thread() {
+                if let Some(deadline) = deadline {
+                    while !thread.is_finished() && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    if !thread.is_finished() {
+                        warn!("Worker {} did not stop in time", worker.id);
+                        stuck.push(worker.id);
+                        break;
+                    }
+                }
+
+                if thread.join().is_ok() {
+                    break;
+                }
+            }
+        }
+
+        stuck
+    }
+}
+
+/// `ThreadPool::stats_handle`で得られる、統計情報を読むためのハンドル。
+///
+/// Reads the stats of a `ThreadPool`. After the pool shuts down it keeps
+/// reporting the final counters.
+#[derive(Clone)]
+pub struct StatsHandle(Arc<Shared>);
+
+impl StatsHandle {
+    pub fn stats(&self) -> PoolStats {
+        self.0.stats()
+    }
+}
+
+impl fmt::Debug for StatsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("StatsHandle").field(&self.stats()).finish()
+    }
+}
+
+/// `ThreadPool::executor`で得られる、ジョブを追加するためのハンドル。
+///
+/// Queues jobs on a `ThreadPool` without owning it. Jobs queued once the
+/// pool has shut down and its workers have exited are dropped unrun.
+#[derive(Clone)]
+pub struct Executor(Arc<Shared>);
+
+impl Executor {
+    /// Queues `f` only if the queue has room, like
+    /// `ThreadPool::try_execute`.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.try_submit(f)
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Executor").field(&self.0.stats()).finish()
+    }
+}
+
+/// 期限内に停止しなかったワーカーのID。
+///
+/// Returned by `ThreadPool::shutdown_timeout` with the ids of the workers
+/// that were still running a job when the deadline passed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownTimeout {
+    pub workers: Vec<usize>,
+}
+
+impl fmt::Display for ShutdownTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "workers {:?} did not stop before the deadline",
+            self.workers
+        )
+    }
+}
+
+impl std::error::Error for ShutdownTimeout {}
+struct Worker {
+    id: usize,
+    // 再起動したスレッドが自分のハンドルを書き込めるよう共有します
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Worker {
+    fn new(id: usize) -> Worker {
+        Worker {
+            id,
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Whether no thread is running in this slot.
+    fn is_free(&self) -> bool {
+        lock(&self.thread)
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+
+    fn take_thread(&self) -> Option<thread::JoinHandle<()>> {
+        lock(&self.thread).take()
+    }
+}
+
+impl Shared {
+    fn new(queue: Scheduler, config: WorkerConfig) -> Shared {
+        Shared {
+            queue,
+            workers: (0..config.max_workers).map(Worker::new).collect(),
+            config,
+            live: AtomicUsize::new(0),
+            starting: Mutex::new(()),
+            panic_handler: RwLock::new(None),
+            jobs_completed: AtomicUsize::new(0),
+            jobs_panicked: AtomicUsize::new(0),
+            jobs_rejected: AtomicUsize::new(0),
+            jobs_dropped: AtomicUsize::new(0),
+            workers_respawned: AtomicUsize::new(0),
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        let workers = self.live.load(Ordering::SeqCst);
+        PoolStats {
+            workers,
+            active: workers.saturating_sub(self.queue.waiting()),
+            queued: self.queue.len(),
+            jobs_completed: self.jobs_completed.load(Ordering::SeqCst),
+            jobs_panicked: self.jobs_panicked.load(Ordering::SeqCst),
+            jobs_rejected: self.jobs_rejected.load(Ordering::SeqCst),
+            jobs_dropped: self.jobs_dropped.load(Ordering::SeqCst),
+            workers_respawned: self.workers_respawned.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Queues `f` if the queue has room, whatever the policy.
+    fn try_submit<F>(self: &Arc<Shared>, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.try_push(f, |f| Box::new(f)).map_err(QueueFull)?;
+        self.grow();
+        Ok(())
+    }
+
+    /// Queues `job` according to `policy`.
+    fn submit(self: &Arc<Shared>, job: Job, policy: QueuePolicy) {
+        match self.queue.push(job, policy) {
+            Pushed::Queued => self.grow(),
+            // 捨てたジョブはキューのロックを外してからdropします
+            Pushed::Rejected(job) => {
+                warn!("Job queue is full; rejecting a job.");
+                self.jobs_rejected.fetch_add(1, Ordering::SeqCst);
+                drop(job);
+            }
+            Pushed::DroppedOldest(oldest) => {
+                warn!("Job queue is full; dropping the oldest job.");
+                self.jobs_dropped.fetch_add(1, Ordering::SeqCst);
+                drop(oldest);
+            }
+            Pushed::RunHere(job) => job.call_box(),
+        }
+    }
+
+    /// 空いているワーカーがいなければ、上限までワーカーを増やします。
+    fn grow(self: &Arc<Shared>) {
+        if self.queue.waiting() > 0 || self.live.load(Ordering::SeqCst) >= self.config.max_workers {
+            return;
+        }
+        if let Err(e) = self.start_worker() {
+            error!("Failed to start a worker: {}", e);
+        }
+    }
+
+    /// Starts a worker in a free slot, unless the pool is at its maximum.
+    fn start_worker(self: &Arc<Shared>) -> io::Result<()> {
+        let _starting = lock(&self.starting);
+        if self.live.load(Ordering::SeqCst) >= self.config.max_workers {
+            return Ok(());
+        }
+        // 終了処理中のワーカーのスロットはまだ使えないので、今回は増やしません
+        let Some(worker) = self.workers.iter().find(|w| w.is_free()) else {
+            return Ok(());
+        };
+
+        self.live.fetch_add(1, Ordering::SeqCst);
+        spawn_worker_thread(worker.id, self).inspect_err(|_| {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+
+    /// Lets an idle worker exit if that leaves at least `min_workers`.
+    fn retire(&self) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > self.config.min_workers).then(|| live - 1)
+            })
+            .is_ok()
+    }
+}
+
+fn spawn_worker_thread(id: usize, shared: &Arc<Shared>) -> io::Result<()> {
+    // ハンドルを書き込むまでロックしておき、新しいスレッドが先に落ちても
+    // 古いハンドルで上書きしないようにします
+    let mut guard = lock(&shared.workers[id].thread);
+
+    let mut builder = thread::Builder::new();
+    if let Some(prefix) = &shared.config.thread_name_prefix {
+        builder = builder.name(format!("{}{}", prefix, id));
+    }
+    if let Some(stack_size) = shared.config.stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+
+    let shared = Arc::clone(shared);
+    *guard = Some(builder.spawn(move || {
+        let sentinel = Sentinel {
+            id,
+            shared: Arc::clone(&shared),
+        };
+        run_worker(id, &shared);
+        // 正常に終了したので再起動しません
+        std::mem::forget(sentinel);
+    })?);
+    Ok(())
+}
+
+fn run_worker(id: usize, shared: &Shared) {
+    shared.queue.register_worker(id);
+    if let Some(on_start) = &shared.config.on_start {
+        on_start(id);
+    }
+
+    // 最小数を超えるワーカーがいるときだけ、暇なワーカーを終了させます
+    let idle_timeout = (shared.config.max_workers > shared.config.min_workers)
+        .then_some(shared.config.idle_timeout);
+
+    loop {
+        match shared.queue.pop(id, idle_timeout) {
+            Popped::Job(job) => match panic::catch_unwind(AssertUnwindSafe(|| job.call_box())) {
+                Ok(()) => {
+                    shared.jobs_completed.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(payload) => {
+                    shared.jobs_panicked.fetch_add(1, Ordering::SeqCst);
+                    report_panic(id, shared, &*payload);
+                }
+            },
+            Popped::Idle => {
+                if shared.retire() {
+                    break;
+                }
+            }
+            Popped::Terminate => {
+                shared.live.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+        }
+    }
+
+    if let Some(on_stop) = &shared.config.on_stop {
+        on_stop(id);
+    }
+}
+
+fn report_panic(id: usize, shared: &Shared, payload: &(dyn Any + Send)) {
+    let handler = shared
+        .panic_handler
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+
+    match handler {
+        Some(handler) => handler(id, payload),
+        None => error!("Worker {} job panicked: {}", id, panic_message(payload)),
+    }
+}
+
+/// パニックの値からメッセージを取り出します。
+///
+/// Returns the message of a panic payload, for payloads created by `panic!`.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// ワーカースレッドがパニックで終了したときに、代わりのスレッドを起動します。
+///
+/// Lives on a worker thread's stack; if the thread unwinds past
+/// `run_worker` (e.g. the panic handler itself panicked), its drop spawns a
+/// replacement so the pool keeps its size.
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("Worker {} died; starting a new thread.", self.id);
+
+            self.shared.workers_respawned.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = spawn_worker_thread(self.id, &self.shared) {
+                error!("Failed to restart worker {}: {}", self.id, e);
+                self.shared.live.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if !self.terminated {
+            self.terminate(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn shutdown_reports_stuck_workers() {
+        let pool = ThreadPool::new(2);
+        pool.execute(|| thread::sleep(Duration::from_secs(2)));
+        thread::sleep(Duration::from_millis(50));
+
+        let err = pool
+            .shutdown_timeout(Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(err.workers.len(), 1);
+    }
+
+    #[test]
+    fn panicking_job_does_not_kill_the_pool() {
+        let pool = ThreadPool::new(2);
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&messages);
+        pool.on_panic(move |_, payload| {
+            seen.lock()
+                .unwrap()
+                .push(panic_message(payload).to_string());
+        });
+
+        for i in 0..4 {
+            pool.execute(move || panic!("job {} failed", i));
+        }
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let stats = pool.stats();
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+        assert_eq!(messages.lock().unwrap().len(), 4);
+        assert!(
+            messages
+                .lock()
+                .unwrap()
+                .contains(&"job 3 failed".to_string())
+        );
+        assert_eq!(stats.workers, 2);
+    }
+
+    #[test]
+    fn spawn_returns_the_job_result() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<JobHandle<usize>> = (0..4).map(|i| pool.spawn(move || i * 10)).collect();
+        let results: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn spawn_propagates_panics() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.spawn(|| -> u32 { panic!("boom") });
+        match handle.join() {
+            Err(JobError::Panicked(payload)) => assert_eq!(panic_message(&*payload), "boom"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(pool.spawn(|| 1).join().unwrap(), 1);
+    }
+
+    #[test]
+    fn try_join_and_timeout() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let mut handle = pool.spawn(move || {
+            receiver.recv().unwrap();
+            7
+        });
+
+        assert!(handle.try_join().is_none());
+        assert!(handle.join_timeout(Duration::from_millis(20)).is_none());
+        sender.send(()).unwrap();
+        assert_eq!(
+            handle
+                .join_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap(),
+            7
+        );
+        assert!(matches!(
+            handle.try_join(),
+            Some(Err(JobError::AlreadyJoined))
+        ));
+    }
+
+    #[test]
+    fn cancel_skips_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let (started_sender, started) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel::<()>();
+        let blocker = pool.spawn(move || {
+            started_sender.send(()).unwrap();
+            receiver.recv().unwrap()
+        });
+        started.recv().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&ran);
+        let queued = pool.spawn(move || counter.fetch_add(1, Ordering::SeqCst));
+
+        assert!(queued.cancel());
+        assert!(!blocker.cancel());
+        sender.send(()).unwrap();
+        blocker.join().unwrap();
+
+        assert!(matches!(queued.join(), Err(JobError::Cancelled)));
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    /// Starts a pool with one worker that stays busy until the returned
+    /// sender is used, so tests can fill its queue deterministically.
+    fn busy_pool(capacity: usize, policy: QueuePolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::bounded(1, capacity, policy);
+        let (started_sender, started) = mpsc::channel();
+        let (release, receiver) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            receiver.recv().unwrap();
+        });
+        started.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn try_execute_fails_when_full() {
+        let (pool, release) = busy_pool(2, QueuePolicy::Block);
+        assert!(pool.try_execute(|| {}).is_ok());
+        assert!(pool.try_execute(|| {}).is_ok());
+        let job = pool.try_execute(|| {}).unwrap_err().into_inner();
+        assert_eq!(pool.stats().queued, 2);
+
+        release.send(()).unwrap();
+        pool.execute(job);
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn executor_queues_jobs_from_a_job() {
+        let pool = ThreadPool::new(2);
+        let executor = pool.executor();
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || {
+            executor
+                .try_execute(move || tx.send(thread::current().id()).unwrap())
+                .unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let (pool, release) = busy_pool(1, QueuePolicy::Block);
+        let executor = pool.executor();
+        assert!(executor.try_execute(|| {}).is_ok());
+        assert!(executor.try_execute(|| {}).is_err());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn reject_and_drop_oldest_policies() {
+        let (pool, release) = busy_pool(1, QueuePolicy::Reject);
+        let rejected = pool.spawn(|| 1);
+        let second = pool.spawn(|| 2);
+        assert!(matches!(second.join(), Err(JobError::Cancelled)));
+        release.send(()).unwrap();
+        assert_eq!(rejected.join().unwrap(), 1);
+        assert_eq!(pool.stats().jobs_rejected, 1);
+
+        let (pool, release) = busy_pool(1, QueuePolicy::DropOldest);
+        let oldest = pool.spawn(|| 1);
+        let newest = pool.spawn(|| 2);
+        release.send(()).unwrap();
+        assert!(matches!(oldest.join(), Err(JobError::Cancelled)));
+        assert_eq!(newest.join().unwrap(), 2);
+        assert_eq!(pool.stats().jobs_dropped, 1);
+    }
+
+    #[test]
+    fn caller_runs_policy() {
+        let (pool, release) = busy_pool(1, QueuePolicy::CallerRuns);
+        pool.execute(|| {});
+        let caller = thread::current().id();
+        let ran_on = pool.spawn(|| thread::current().id());
+        assert_eq!(ran_on.join().unwrap(), caller);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn block_policy_waits_for_room() {
+        let (pool, release) = busy_pool(1, QueuePolicy::Block);
+        pool.execute(|| {});
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release.send(()).unwrap();
+        });
+        let started = Instant::now();
+        pool.execute(|| {});
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn idle_workers_steal_from_busy_ones() {
+        let pool = Arc::new(ThreadPool::new(4));
+        let (release, receiver) = mpsc::channel::<()>();
+        let inner_pool = Arc::clone(&pool);
+
+        // Jobs spawned from inside a job go to that worker's own deque; the
+        // worker then blocks, so only stealing can run them.
+        let outer = pool.spawn(move || {
+            let handles: Vec<JobHandle<thread::ThreadId>> = (0..8)
+                .map(|_| inner_pool.spawn(|| thread::current().id()))
+                .collect();
+            let ids: Vec<thread::ThreadId> =
+                handles.into_iter().map(|h| h.join().unwrap()).collect();
+            receiver.recv().unwrap();
+            ids
+        });
+
+        release.send(()).unwrap();
+        let ids = outer.join().unwrap();
+        assert_eq!(ids.len(), 8);
+        assert!(!ids.contains(&thread::current().id()));
+    }
+
+    #[test]
+    fn dead_worker_is_respawned() {
+        let pool = ThreadPool::new(1);
+        pool.on_panic(|_, _| panic!("panic handler failed"));
+        pool.execute(|| panic!("job failed"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let stats = pool.stats();
+        assert_eq!(stats.jobs_panicked, 1);
+        assert_eq!(stats.workers_respawned, 1);
+        assert_eq!(stats.workers, 1);
+    }
+
+    #[test]
+    fn builder_rejects_bad_configs() {
+        let err = ThreadPoolBuilder::new()
+            .num_workers(0)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, PoolCreationError::ZeroWorkers));
+
+        let err = ThreadPoolBuilder::new()
+            .min_workers(4)
+            .max_workers(2)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            PoolCreationError::MinAboveMax { min: 4, max: 2 }
+        ));
+
+        let err = ThreadPoolBuilder::new()
+            .queue_capacity(0)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, PoolCreationError::ZeroQueueCapacity));
+    }
+
+    #[test]
+    fn builder_names_threads_and_runs_hooks() {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        let (on_start, on_stop) = (Arc::clone(&started), Arc::clone(&stopped));
+        let pool = ThreadPoolBuilder::new()
+            .num_workers(2)
+            .thread_name_prefix("web-")
+            .stack_size(256 * 1024)
+            .on_worker_start(move |id| on_start.lock().unwrap().push(id))
+            .on_worker_stop(move |id| on_stop.lock().unwrap().push(id))
+            .build()
+            .unwrap();
+
+        let name = pool
+            .spawn(|| thread::current().name().map(str::to_string))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert!(name == "web-0" || name == "web-1", "{}", name);
+
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        let mut started = started.lock().unwrap().clone();
+        let mut stopped = stopped.lock().unwrap().clone();
+        started.sort();
+        stopped.sort();
+        assert_eq!(started, [0, 1]);
+        assert_eq!(stopped, [0, 1]);
+    }
+
+    #[test]
+    fn delayed_jobs_run_in_order_after_their_delay() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let started = Instant::now();
+        for (name, delay) in [("slow", 120), ("fast", 40)] {
+            let sender = sender.clone();
+            pool.execute_after(Duration::from_millis(delay), move || {
+                sender.send((name, started.elapsed())).unwrap();
+            });
+        }
+        let at = Instant::now() + Duration::from_millis(80);
+        pool.execute_at(at, move || sender.send(("at", started.elapsed())).unwrap());
+
+        let runs: Vec<(&str, Duration)> = (0..3)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        let names: Vec<&str> = runs.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["fast", "at", "slow"]);
+        assert!(runs[0].1 >= Duration::from_millis(40));
+        assert!(runs[2].1 >= Duration::from_millis(120));
+    }
+
+    #[test]
+    fn cancelled_timers_do_not_run() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&ran);
+        let handle = pool.execute_after(Duration::from_millis(50), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(handle.cancel());
+        assert!(handle.is_cancelled());
+        assert!(!handle.cancel());
+
+        let (sender, receiver) = mpsc::channel();
+        let fired = pool.execute_after(Duration::from_millis(10), move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!fired.cancel());
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn repeating_jobs_run_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let handle = pool.execute_every(Duration::from_millis(20), move || {
+            let _ = sender.lock().unwrap().send(());
+        });
+        for _ in 0..3 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(handle.cancel());
+
+        // 取り消しの前にキューに入った分だけは届くことがあります
+        thread::sleep(Duration::from_millis(50));
+        while receiver.try_recv().is_ok() {}
+        thread::sleep(Duration::from_millis(100));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn repeating_jobs_do_not_overlap() {
+        let pool = ThreadPool::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (r, o, n) = (
+            Arc::clone(&running),
+            Arc::clone(&overlapped),
+            Arc::clone(&runs),
+        );
+        pool.execute_every(Duration::from_millis(10), move || {
+            if r.fetch_add(1, Ordering::SeqCst) > 0 {
+                o.fetch_add(1, Ordering::SeqCst);
+            }
+            thread::sleep(Duration::from_millis(45));
+            r.fetch_sub(1, Ordering::SeqCst);
+            n.fetch_add(1, Ordering::SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(300));
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(overlapped.load(Ordering::SeqCst), 0);
+        assert!(runs.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn shutdown_drops_timers_that_are_not_due() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&ran);
+        let handle = pool.execute_after(Duration::from_secs(60), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let started = Instant::now();
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        assert!(handle.is_cancelled());
+    }
+
+    #[test]
+    fn pool_grows_when_busy_and_shrinks_when_idle() {
+        let pool = ThreadPoolBuilder::new()
+            .min_workers(1)
+            .max_workers(3)
+            .idle_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        assert_eq!(pool.stats().workers, 1);
+
+        let (release, receiver) = mpsc::channel::<()>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (started, running) = mpsc::channel();
+        for _ in 0..3 {
+            let receiver = Arc::clone(&receiver);
+            let started = started.clone();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                receiver.lock().unwrap().recv().unwrap();
+            });
+            // 次のジョブの前に、ワーカーがこのジョブを取るまで待ちます
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.stats().workers, 3);
+
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().workers > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.stats().workers, 1);
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
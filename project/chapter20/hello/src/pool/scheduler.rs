@@ -0,0 +1,295 @@
+use super::Job;
+use crate::lock;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// キューが一杯のときに`execute`がどうするか。
+///
+/// What `ThreadPool::execute` does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait until a worker takes a job off the queue.
+    Block,
+    /// Drop the new job.
+    Reject,
+    /// Drop the job that has waited longest and queue the new one.
+    DropOldest,
+    /// Run the new job on the calling thread.
+    CallerRuns,
+}
+
+/// What happened to a job handed to `Scheduler::push`.
+pub(crate) enum Pushed {
+    Queued,
+    Rejected(Job),
+    DroppedOldest(Job),
+    RunHere(Job),
+}
+
+/// What `Scheduler::pop` found for a worker.
+pub(crate) enum Popped {
+    Job(Job),
+    /// No job arrived within the idle timeout.
+    Idle,
+    /// The pool is terminating and every queued job has been taken.
+    Terminate,
+}
+
+// 待っているワーカーは、取りこぼしに備えてこの間隔でも起きます
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+const SPIN_ROUNDS: usize = 16;
+
+static NEXT_SCHEDULER_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // このスレッドがワーカーなら、(スケジューラーのID, ワーカーの番号)
+    static CURRENT_WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// ワーカーごとのキューと仕事の横取り(work stealing)でジョブを配るスケジューラー。
+///
+/// 外から追加されたジョブは順番にワーカーのキューへ振り分け、ジョブの中から
+/// 追加されたジョブはそのワーカー自身のキューに入れます。自分のキューが空の
+/// ワーカーは、他のワーカーのキューの後ろからジョブを取ります。
+///
+/// Hands jobs to workers through per-worker deques with work stealing.
+///
+/// Jobs submitted from outside the pool are spread round-robin over the
+/// workers' deques; jobs submitted from inside a job go to that worker's own
+/// deque. A worker takes from the front of its own deque and, when that is
+/// empty, steals from the back of the others', so workers only contend when
+/// they touch the same deque.
+pub(crate) struct Scheduler {
+    id: usize,
+    locals: Vec<Mutex<VecDeque<(u64, Job)>>>,
+    next_local: AtomicUsize,
+    next_seq: AtomicU64,
+    /// Jobs queued plus slots reserved by pushers that are about to queue.
+    queued: AtomicUsize,
+    capacity: Option<usize>,
+    terminating: AtomicBool,
+    sleepers: AtomicUsize,
+    /// Workers inside `pop`, i.e. not running a job.
+    waiting: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    space_lock: Mutex<()>,
+    space: Condvar,
+}
+
+impl Scheduler {
+    pub(crate) fn new(workers: usize, capacity: Option<usize>) -> Scheduler {
+        Scheduler {
+            id: NEXT_SCHEDULER_ID.fetch_add(1, Ordering::Relaxed),
+            locals: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            next_local: AtomicUsize::new(0),
+            next_seq: AtomicU64::new(0),
+            queued: AtomicUsize::new(0),
+            capacity,
+            terminating: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            space_lock: Mutex::new(()),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Marks the calling thread as worker `index` of this scheduler.
+    pub(crate) fn register_worker(&self, index: usize) {
+        CURRENT_WORKER.with(|w| w.set(Some((self.id, index))));
+    }
+
+    pub(crate) fn push(&self, job: Job, policy: QueuePolicy) -> Pushed {
+        if self.reserve() {
+            self.enqueue(job);
+            return Pushed::Queued;
+        }
+
+        match policy {
+            QueuePolicy::Reject => Pushed::Rejected(job),
+            QueuePolicy::CallerRuns => Pushed::RunHere(job),
+            QueuePolicy::Block => {
+                let mut guard = lock(&self.space_lock);
+                while !self.reserve() {
+                    guard = self
+                        .space
+                        .wait_timeout(guard, PARK_TIMEOUT)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                drop(guard);
+                self.enqueue(job);
+                Pushed::Queued
+            }
+            QueuePolicy::DropOldest => loop {
+                // 一番古いジョブと入れ替えるので、予約数は変わりません
+                if let Some(oldest) = self.take_oldest() {
+                    self.enqueue(job);
+                    return Pushed::DroppedOldest(oldest);
+                }
+                if self.reserve() {
+                    self.enqueue(job);
+                    return Pushed::Queued;
+                }
+                // 予約だけされてまだ入っていないジョブが入るのを待ちます
+                thread::yield_now();
+            },
+        }
+    }
+
+    /// Queues the job built by `make` only if there is room, without
+    /// waiting; otherwise gives `value` back.
+    pub(crate) fn try_push<T>(&self, value: T, make: impl FnOnce(T) -> Job) -> Result<(), T> {
+        if !self.reserve() {
+            return Err(value);
+        }
+        self.enqueue(make(value));
+        Ok(())
+    }
+
+    /// Waits for a job for worker `index`. Gives up with `Popped::Idle` if
+    /// none arrives within `idle_timeout`.
+    pub(crate) fn pop(&self, index: usize, idle_timeout: Option<Duration>) -> Popped {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let popped = self.wait_for_job(index, idle_timeout.map(|t| Instant::now() + t));
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        popped
+    }
+
+    fn wait_for_job(&self, index: usize, idle_deadline: Option<Instant>) -> Popped {
+        loop {
+            // すぐ次のジョブが来ることが多いので、眠る前に少し探し続けます
+            for _ in 0..SPIN_ROUNDS {
+                if let Some(job) = self.find_work(index) {
+                    return Popped::Job(job);
+                }
+                thread::yield_now();
+            }
+
+            let guard = lock(&self.sleep);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            if self.queued.load(Ordering::SeqCst) == 0 {
+                let mut park = PARK_TIMEOUT;
+                if self.terminating.load(Ordering::SeqCst) {
+                    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                    return Popped::Terminate;
+                }
+                if let Some(deadline) = idle_deadline {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                        return Popped::Idle;
+                    }
+                    park = park.min(deadline - now);
+                }
+                drop(
+                    self.wake
+                        .wait_timeout(guard, park)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+            }
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Tells workers to exit once the queues are empty.
+    pub(crate) fn terminate(&self) {
+        self.terminating.store(true, Ordering::SeqCst);
+        let _guard = lock(&self.sleep);
+        self.wake.notify_all();
+    }
+
+    /// The number of workers waiting for a job.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// The number of jobs waiting for a worker.
+    pub(crate) fn len(&self) -> usize {
+        self.locals.iter().map(|l| lock(l).len()).sum()
+    }
+
+    fn reserve(&self) -> bool {
+        match self.capacity {
+            None => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(capacity) => self
+                .queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |q| {
+                    (q < capacity).then_some(q + 1)
+                })
+                .is_ok(),
+        }
+    }
+
+    fn enqueue(&self, job: Job) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let index = match CURRENT_WORKER.with(Cell::get) {
+            Some((id, index)) if id == self.id => index,
+            _ => self.next_local.fetch_add(1, Ordering::Relaxed) % self.locals.len(),
+        };
+        lock(&self.locals[index]).push_back((seq, job));
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.sleep);
+            self.wake.notify_one();
+        }
+    }
+
+    fn find_work(&self, index: usize) -> Option<Job> {
+        let own = lock(&self.locals[index]).pop_front();
+        let job = own.or_else(|| self.steal(index))?;
+
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.capacity.is_some() {
+            let _guard = lock(&self.space_lock);
+            self.space.notify_one();
+        }
+        Some(job.1)
+    }
+
+    /// Takes half of the first non-empty deque found after `index`'s own,
+    /// keeps the rest in `index`'s deque and returns one job.
+    fn steal(&self, index: usize) -> Option<(u64, Job)> {
+        let n = self.locals.len();
+        for i in 1..n {
+            let mut victim = lock(&self.locals[(index + i) % n]);
+            if victim.is_empty() {
+                continue;
+            }
+            let keep = victim.len() / 2;
+            let mut stolen = victim.split_off(keep);
+            drop(victim);
+
+            let job = stolen.pop_front();
+            if !stolen.is_empty() {
+                lock(&self.locals[index]).append(&mut stolen);
+            }
+            return job;
+        }
+        None
+    }
+
+    fn take_oldest(&self) -> Option<Job> {
+        // 各キューの先頭の番号を比べて、一番古いジョブを探します
+        let oldest = self
+            .locals
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| lock(l).front().map(|(seq, _)| (*seq, i)))
+            .min()?;
+        let mut local = lock(&self.locals[oldest.1]);
+        match local.front() {
+            Some((seq, _)) if *seq == oldest.0 => local.pop_front().map(|(_, job)| job),
+            _ => None,
+        }
+    }
+}
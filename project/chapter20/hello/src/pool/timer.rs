@@ -0,0 +1,405 @@
+use super::{Job, QueuePolicy, Shared};
+use crate::lock;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// ホイールの1目盛りの長さ。これより細かい時刻の指定は切り上げられます
+const TICK: Duration = Duration::from_millis(10);
+const SLOTS: usize = 256;
+
+const PENDING: u8 = 0;
+const FIRED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+type Repeating = Arc<dyn Fn() + Send + Sync + 'static>;
+
+enum Task {
+    Once(Job),
+    Every {
+        job: Repeating,
+        interval: Duration,
+        /// Set while a run is queued or running, so runs never overlap.
+        running: Arc<AtomicBool>,
+    },
+}
+
+struct Entry {
+    tick: u64,
+    deadline: Instant,
+    task: Task,
+    state: Arc<AtomicU8>,
+}
+
+/// 目盛りごとのスロットにタイマーを並べたタイマーホイール。
+///
+/// A hashed timing wheel: each timer goes into the slot for the tick it is
+/// due at, modulo the number of slots. Advancing the wheel only looks at the
+/// slots of the ticks that passed, so adding and firing timers costs the same
+/// however many are pending. Timers more than one turn away simply stay in
+/// their slot until their tick comes round.
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    /// The last tick whose slot has been processed.
+    tick: u64,
+    len: usize,
+    stopped: bool,
+}
+
+impl Wheel {
+    fn new() -> Wheel {
+        Wheel {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            tick: 0,
+            len: 0,
+            stopped: false,
+        }
+    }
+
+    fn insert(&mut self, mut entry: Entry, now_tick: u64) {
+        // 空の間は目盛りを進めていないので、ここで追いつきます
+        if self.len == 0 {
+            self.tick = self.tick.max(now_tick);
+        }
+        entry.tick = entry.tick.max(self.tick + 1);
+        self.slots[(entry.tick % SLOTS as u64) as usize].push(entry);
+        self.len += 1;
+    }
+
+    /// Moves the wheel to `now_tick` and returns the timers that are due,
+    /// earliest first.
+    fn advance(&mut self, now_tick: u64) -> Vec<Entry> {
+        let passed = now_tick.saturating_sub(self.tick);
+        let mut due = Vec::new();
+        if self.len > 0 {
+            // 一周以上進んだときは、全部のスロットを一度だけ見れば足ります
+            for tick in self.tick + 1..=self.tick + passed.min(SLOTS as u64) {
+                let slot = &mut self.slots[(tick % SLOTS as u64) as usize];
+                let mut i = 0;
+                while i < slot.len() {
+                    if slot[i].tick <= now_tick {
+                        due.push(slot.swap_remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            self.len -= due.len();
+        }
+        self.tick = self.tick.max(now_tick);
+        due.sort_by_key(|entry| entry.deadline);
+        due
+    }
+}
+
+struct TimerState {
+    wheel: Mutex<Wheel>,
+    wake: Condvar,
+    start: Instant,
+}
+
+impl TimerState {
+    /// The tick `at` falls in, rounded up so timers never fire early.
+    fn tick_at(&self, at: Instant) -> u64 {
+        let elapsed = at.saturating_duration_since(self.start);
+        elapsed.as_nanos().div_ceil(TICK.as_nanos()) as u64
+    }
+
+    /// The last tick that has fully passed.
+    fn tick_now(&self) -> u64 {
+        (self.start.elapsed().as_nanos() / TICK.as_nanos()) as u64
+    }
+}
+
+/// 時刻を指定したジョブを、期限が来たらプールのキューに入れるスレッド。
+///
+/// Owns the timer thread of a `ThreadPool`. The thread sleeps while no
+/// timers are pending and otherwise wakes every tick, handing due jobs to
+/// the pool's queue; the jobs themselves always run on the workers.
+pub(crate) struct Timer {
+    state: Arc<TimerState>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    pub(crate) fn start(shared: &Arc<Shared>, policy: QueuePolicy) -> Timer {
+        let state = Arc::new(TimerState {
+            wheel: Mutex::new(Wheel::new()),
+            wake: Condvar::new(),
+            start: Instant::now(),
+        });
+        // タイマースレッドでジョブを実行すると他のタイマーが遅れるので、待たせます
+        let policy = match policy {
+            QueuePolicy::CallerRuns => QueuePolicy::Block,
+            policy => policy,
+        };
+
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &shared.config.thread_name_prefix {
+            builder = builder.name(format!("{}timer", prefix));
+        }
+        let thread = {
+            let state = Arc::clone(&state);
+            let shared = Arc::clone(shared);
+            builder
+                .spawn(move || run_timer(&state, &shared, policy))
+                .unwrap_or_else(|e| panic!("failed to start the timer thread: {}", e))
+        };
+
+        Timer {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn once(&self, at: Instant, job: Job) -> TimerHandle {
+        self.schedule(at, Task::Once(job))
+    }
+
+    pub(crate) fn every(&self, interval: Duration, job: Repeating) -> TimerHandle {
+        let task = Task::Every {
+            job,
+            interval,
+            running: Arc::new(AtomicBool::new(false)),
+        };
+        self.schedule(Instant::now() + interval, task)
+    }
+
+    fn schedule(&self, deadline: Instant, task: Task) -> TimerHandle {
+        let state = Arc::new(AtomicU8::new(PENDING));
+        let entry = Entry {
+            tick: self.state.tick_at(deadline),
+            deadline,
+            task,
+            state: Arc::clone(&state),
+        };
+
+        let mut wheel = lock(&self.state.wheel);
+        if wheel.stopped {
+            state.store(CANCELLED, Ordering::SeqCst);
+            return TimerHandle { state };
+        }
+        let was_empty = wheel.len == 0;
+        wheel.insert(entry, self.state.tick_now());
+        drop(wheel);
+        // 空のホイールでは眠り続けているので起こします
+        if was_empty {
+            self.state.wake.notify_one();
+        }
+        TimerHandle { state }
+    }
+
+    /// Stops the timer thread. Timers that have not fired are dropped, and
+    /// their handles report them as cancelled.
+    pub(crate) fn stop(&mut self) {
+        let mut wheel = lock(&self.state.wheel);
+        wheel.stopped = true;
+        let pending: Vec<Entry> = wheel.slots.iter_mut().flat_map(|s| s.drain(..)).collect();
+        wheel.len = 0;
+        drop(wheel);
+        self.state.wake.notify_one();
+        // ジョブはロックを外してから捨てます
+        for entry in pending {
+            entry.state.store(CANCELLED, Ordering::SeqCst);
+        }
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("The timer thread panicked.");
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_timer(state: &TimerState, shared: &Arc<Shared>, policy: QueuePolicy) {
+    let mut wheel = lock(&state.wheel);
+    loop {
+        if wheel.stopped {
+            break;
+        }
+
+        let due = wheel.advance(state.tick_now());
+        if !due.is_empty() {
+            // キューが一杯で待つこともあるので、ロックを外してから渡します
+            drop(wheel);
+            let again: Vec<Entry> = due
+                .into_iter()
+                .filter_map(|entry| fire(entry, shared, policy))
+                .collect();
+            wheel = lock(&state.wheel);
+            let now_tick = state.tick_now();
+            for mut entry in again {
+                entry.tick = state.tick_at(entry.deadline);
+                wheel.insert(entry, now_tick);
+            }
+            continue;
+        }
+
+        wheel = if wheel.len == 0 {
+            state
+                .wake
+                .wait(wheel)
+                .unwrap_or_else(PoisonError::into_inner)
+        } else {
+            let next =
+                state.start + Duration::from_nanos((wheel.tick + 1) * TICK.as_nanos() as u64);
+            state
+                .wake
+                .wait_timeout(wheel, next.saturating_duration_since(Instant::now()))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0
+        };
+    }
+}
+
+/// Hands a due timer's job to the pool. Returns the entry again, with its
+/// next deadline, if it repeats.
+fn fire(entry: Entry, shared: &Arc<Shared>, policy: QueuePolicy) -> Option<Entry> {
+    match entry.task {
+        Task::Once(job) => {
+            if entry
+                .state
+                .compare_exchange(PENDING, FIRED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                shared.submit(job, policy);
+            }
+            None
+        }
+        Task::Every {
+            job,
+            interval,
+            running,
+        } => {
+            if entry.state.load(Ordering::SeqCst) == CANCELLED {
+                return None;
+            }
+
+            if running.swap(true, Ordering::SeqCst) {
+                debug!("A repeating job is still running; skipping this run.");
+            } else {
+                let guard = Running(Arc::clone(&running));
+                let job = Arc::clone(&job);
+                let state = Arc::clone(&entry.state);
+                shared.submit(
+                    Box::new(move || {
+                        let _guard = guard;
+                        if state.load(Ordering::SeqCst) != CANCELLED {
+                            job();
+                        }
+                    }),
+                    policy,
+                );
+            }
+
+            // 遅れた分は取り戻さず、次の予定時刻から数え直します
+            let now = Instant::now();
+            let mut deadline = entry.deadline + interval;
+            if deadline <= now {
+                deadline = now + interval;
+            }
+            Some(Entry {
+                tick: 0,
+                deadline,
+                task: Task::Every {
+                    job,
+                    interval,
+                    running,
+                },
+                state: entry.state,
+            })
+        }
+    }
+}
+
+/// 実行中のしるしを、ジョブが終わるか捨てられたときに下ろします。
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// `execute_after`などで予約したジョブのハンドル。
+///
+/// ハンドルを捨ててもジョブは取り消されません。
+///
+/// A handle to a job scheduled with `ThreadPool::execute_after`,
+/// `execute_at` or `execute_every`.
+///
+/// Dropping the handle does not cancel the job.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    state: Arc<AtomicU8>,
+}
+
+impl TimerHandle {
+    /// 予約したジョブを取り消します。
+    ///
+    /// Cancels the job and returns whether it did. A one-off job can only be
+    /// cancelled before its time comes and it is handed to the queue; a
+    /// repeating job stops repeating, and a run that is queued but has not
+    /// started is skipped.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(PENDING, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == CANCELLED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tick: u64, base: Instant) -> Entry {
+        Entry {
+            tick,
+            deadline: base + Duration::from_nanos(tick * TICK.as_nanos() as u64),
+            task: Task::Once(Box::new(|| {})),
+            state: Arc::new(AtomicU8::new(PENDING)),
+        }
+    }
+
+    #[test]
+    fn wheel_keeps_timers_for_later_turns() {
+        let base = Instant::now();
+        let mut wheel = Wheel::new();
+        let far = SLOTS as u64 + 5;
+        wheel.insert(entry(far, base), 0);
+        wheel.insert(entry(5, base), 0);
+        wheel.insert(entry(3, base), 0);
+
+        assert!(wheel.advance(2).is_empty());
+        let due: Vec<u64> = wheel.advance(5).iter().map(|e| e.tick).collect();
+        assert_eq!(due, [3, 5]);
+        // 同じスロットを通っても、次の周のタイマーはまだ残ります
+        assert!(wheel.advance(SLOTS as u64 + 4).is_empty());
+        assert_eq!(wheel.len, 1);
+        assert_eq!(wheel.advance(far).len(), 1);
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test]
+    fn wheel_catches_up_after_long_gaps() {
+        let base = Instant::now();
+        let mut wheel = Wheel::new();
+        wheel.insert(entry(10, base), 0);
+        wheel.insert(entry(700, base), 0);
+        assert_eq!(wheel.advance(10_000).len(), 2);
+
+        // 空のまま時間が経っても、過去の時刻は次の目盛りで発火します
+        wheel.insert(entry(1, base), 20_000);
+        assert_eq!(wheel.advance(20_001).len(), 1);
+    }
+}
@@ -0,0 +1,643 @@
+//! 上流のサーバーにリクエストを転送するハンドラー。
+//!
+//! Forwards requests to upstream HTTP servers.
+
+use crate::request::{Headers, Method, Request};
+use crate::response::{Body, Response};
+use crate::router::Params;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// 上流のレスポンスの見出しの大きさの上限
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+// 接続ごとに意味を持つので、転送しないヘッダー (RFC 9110 7.6.1)
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// 上流のサーバーにリクエストを転送するハンドラー。
+///
+/// 上流を順番に使い、接続できない上流は飛ばします。
+///
+/// Forwards requests to a set of upstream servers, taking turns between
+/// them.
+///
+/// Each request goes to the next upstream over a new connection, without
+/// the hop-by-hop headers and with the client's address appended to
+/// `X-Forwarded-For`. An upstream that refuses a connection or fails its
+/// health check is marked down and only tried when every upstream is down,
+/// until it accepts a connection or passes a health check again.
+///
+/// Answers 502 when no upstream can be reached or one sends an invalid
+/// response, and 504 when one leaves a read or write waiting longer than
+/// the timeout. The upstream's response body is streamed to the client as
+/// it arrives.
+///
+/// ```no_run
+/// use hello::proxy::Proxy;
+/// use hello::router::Router;
+/// use std::time::Duration;
+///
+/// let upstreams = ["127.0.0.1:9000".parse().unwrap(), "127.0.0.1:9001".parse().unwrap()];
+/// let proxy = Proxy::new(upstreams).with_health_check("/health", Duration::from_secs(5));
+/// let router = Router::new().any("/api/*rest", proxy.handler());
+/// ```
+pub struct Proxy {
+    upstreams: Arc<[Upstream]>,
+    next: AtomicUsize,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    up: AtomicBool,
+}
+
+impl Upstream {
+    /// Records whether the upstream is reachable, logging when that changes.
+    fn set_up(&self, up: bool) {
+        if self.up.swap(up, Ordering::SeqCst) != up {
+            if up {
+                info!("Upstream {} is up.", self.addr);
+            } else {
+                warn!("Upstream {} is down.", self.addr);
+            }
+        }
+    }
+}
+
+impl Proxy {
+    /// Forwards to `upstreams`, which are all assumed up at first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `upstreams` is empty.
+    pub fn new<I: IntoIterator<Item = SocketAddr>>(upstreams: I) -> Proxy {
+        let upstreams: Arc<[Upstream]> = upstreams
+            .into_iter()
+            .map(|addr| Upstream {
+                addr,
+                up: AtomicBool::new(true),
+            })
+            .collect();
+        assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+        Proxy {
+            upstreams,
+            next: AtomicUsize::new(0),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// How long to wait for an upstream to accept a connection; 5 seconds
+    /// by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long an upstream may leave a read or write waiting while it is
+    /// sent the request or sends its response; 30 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends `GET path` to every upstream each `interval` from a background
+    /// thread, and marks the upstreams that cannot be reached or answer
+    /// with a 5xx status down. The thread ends after the proxy is dropped.
+    pub fn with_health_check(self, path: &str, interval: Duration) -> Proxy {
+        let upstreams = Arc::downgrade(&self.upstreams);
+        let path = path.to_string();
+        let (connect_timeout, timeout) = (self.connect_timeout, self.timeout);
+        let spawned = thread::Builder::new()
+            .name("proxy-health".to_string())
+            .spawn(move || {
+                while let Some(upstreams) = upstreams.upgrade() {
+                    for upstream in upstreams.iter() {
+                        let result = check(upstream.addr, &path, connect_timeout, timeout);
+                        if let Err(e) = &result {
+                            debug!("Health check of {} failed: {}", upstream.addr, e);
+                        }
+                        upstream.set_up(result.is_ok());
+                    }
+                    drop(upstreams);
+                    thread::sleep(interval);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Cannot start the health checks: {}", e);
+        }
+        self
+    }
+
+    /// Returns a route handler that forwards requests with their target
+    /// unchanged.
+    pub fn handler(self) -> impl Fn(&Request, &Params) -> Response + Send + Sync + 'static {
+        move |request, _| self.forward(request, &request.target)
+    }
+
+    /// Forwards `request` to the next upstream that accepts a connection,
+    /// asking it for `target` instead of the request's own target.
+    pub fn forward(&self, request: &Request, target: &str) -> Response {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        let mut order: Vec<&Upstream> = (0..count)
+            .map(|i| &self.upstreams[(start + i) % count])
+            .collect();
+        // 落ちている上流は、他もすべて落ちているときだけ試します
+        order.sort_by_key(|upstream| !upstream.up.load(Ordering::SeqCst));
+
+        for upstream in order {
+            let stream = match TcpStream::connect_timeout(&upstream.addr, self.connect_timeout) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Cannot connect to upstream {}: {}", upstream.addr, e);
+                    upstream.set_up(false);
+                    continue;
+                }
+            };
+            upstream.set_up(true);
+            return match self.exchange(stream, upstream.addr, request, target) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Upstream {} failed: {}", upstream.addr, e);
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                            Response::new(504).with_body("Gateway Timeout")
+                        }
+                        _ => Response::new(502).with_body("Bad Gateway"),
+                    }
+                }
+            };
+        }
+        Response::new(502).with_body("Bad Gateway")
+    }
+
+    /// Sends the request over `stream` and reads the head of the response.
+    fn exchange(
+        &self,
+        stream: TcpStream,
+        upstream: SocketAddr,
+        request: &Request,
+        target: &str,
+    ) -> io::Result<Response> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut writer = io::BufWriter::new(&stream);
+        writer.write_all(request_head(request, target, upstream).as_bytes())?;
+        writer.write_all(&request.body)?;
+        writer.flush()?;
+        drop(writer);
+
+        read_response(BufReader::new(stream), request.method == Method::Head)
+    }
+}
+
+/// Builds the head of the request sent upstream. The connection to the
+/// upstream is closed after one response.
+fn request_head(request: &Request, target: &str, upstream: SocketAddr) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, target);
+    let listed = connection_options(&request.headers);
+    for (name, value) in request.headers.iter() {
+        if is_hop_by_hop(name, &listed)
+            || name.eq_ignore_ascii_case("content-length")
+            || name.eq_ignore_ascii_case("x-forwarded-for")
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !request.headers.contains("host") {
+        head.push_str(&format!("Host: {}\r\n", upstream));
+    }
+
+    let client = request.peer.map(|peer| peer.ip().to_string());
+    let forwarded_for: Vec<&str> = request
+        .headers
+        .get_all("x-forwarded-for")
+        .chain(client.as_deref())
+        .collect();
+    if !forwarded_for.is_empty() {
+        head.push_str(&format!(
+            "X-Forwarded-For: {}\r\n",
+            forwarded_for.join(", ")
+        ));
+    }
+
+    // 本文はもう復号されているので、長さを付け直します
+    if !request.body.is_empty()
+        || request.headers.contains("content-length")
+        || request.headers.contains("transfer-encoding")
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+/// Reads the upstream's response, leaving its body to be streamed from
+/// `reader`. `head_only` is set for responses to `HEAD`.
+fn read_response(mut reader: BufReader<TcpStream>, head_only: bool) -> io::Result<Response> {
+    let (status, headers) = loop {
+        let (status, headers) = read_head(&mut reader)?;
+        match status {
+            101 => return Err(invalid_data("upgrades are not supported")),
+            // 100 Continueなどの途中経過は読み飛ばします
+            100..=199 => continue,
+            _ => break (status, headers),
+        }
+    };
+
+    let mut response = Response::new(status);
+    let listed = connection_options(&headers);
+    for (name, value) in headers.iter() {
+        if !is_hop_by_hop(name, &listed) && !name.eq_ignore_ascii_case("content-length") {
+            response.headers.append(name, value);
+        }
+    }
+
+    if head_only || status == 204 || status == 304 {
+        // 本文がなくても、HEADへの応答では長さをそのまま伝えます
+        if let Some(len) = headers.get("content-length") {
+            response.headers.insert("Content-Length", len);
+        }
+        return Ok(response);
+    }
+
+    let chunked = headers
+        .get_all("transfer-encoding")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .last()
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+    response.body = if chunked {
+        Body::chunked(ChunkedBody {
+            reader,
+            remaining: 0,
+            started: false,
+            done: false,
+        })
+    } else if let Some(len) = headers.get("content-length") {
+        let len: u64 = len
+            .trim()
+            .parse()
+            .map_err(|_| invalid_data("invalid Content-Length"))?;
+        Body::from_reader(reader.take(len), len)
+    } else {
+        // 長さのない本文は、接続が閉じるまで続きます
+        Body::chunked(iter::from_fn(move || {
+            let mut buf = vec![0; READ_CHUNK];
+            match reader.read(&mut buf) {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some(Ok(buf))
+                }
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    };
+    Ok(response)
+}
+
+/// Reads a status line and the header fields after it.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Headers)> {
+    let mut read = 0;
+    let line = read_line(reader, &mut read)?;
+    let status = parse_status_line(&line).ok_or_else(|| invalid_data("malformed status line"))?;
+
+    let mut headers = Headers::new();
+    loop {
+        let line = read_line(reader, &mut read)?;
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data("malformed header line"))?;
+        headers.append(name.trim(), value.trim());
+    }
+}
+
+/// `HTTP/1.1 200 OK` to 200.
+fn parse_status_line(line: &str) -> Option<u16> {
+    let mut parts = line.splitn(3, ' ');
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let code = parts.next()?;
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    code.parse().ok()
+}
+
+/// Reads one line without its line ending, adding its length to `read` and
+/// failing once the lines read so far exceed the head size limit.
+fn read_line<R: BufRead>(reader: &mut R, read: &mut usize) -> io::Result<String> {
+    let mut line = Vec::new();
+    let limit = MAX_HEAD_BYTES.saturating_sub(*read) as u64;
+    let n = reader.take(limit).read_until(b'\n', &mut line)?;
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "upstream closed the connection",
+        ));
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid_data("response head too large or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    *read += n;
+    String::from_utf8(line).map_err(|_| invalid_data("line is not valid UTF-8"))
+}
+
+/// The header names listed in `Connection`, which are hop-by-hop too.
+pub(crate) fn connection_options(headers: &Headers) -> Vec<String> {
+    headers
+        .get_all("connection")
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+pub(crate) fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    let name = name.to_ascii_lowercase();
+    HOP_BY_HOP.contains(&name.as_str()) || listed.contains(&name)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// チャンク形式の本文を復号しながら読むイテレーター。
+///
+/// Decodes a chunked body, yielding at most `READ_CHUNK` bytes at a time
+/// however large the upstream's chunks are.
+struct ChunkedBody<R> {
+    reader: R,
+    /// Bytes left in the current chunk.
+    remaining: usize,
+    /// A chunk has been read, so its CRLF comes before the next size.
+    started: bool,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedBody<R> {
+    fn read_piece(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.remaining == 0 {
+            let mut read = 0;
+            if self.started && !read_line(&mut self.reader, &mut read)?.is_empty() {
+                return Err(invalid_data("chunk data not followed by CRLF"));
+            }
+            let line = read_line(&mut self.reader, &mut read)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size =
+                usize::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))?;
+            if size == 0 {
+                // トレーラーは読み飛ばします
+                while !read_line(&mut self.reader, &mut read)?.is_empty() {}
+                return Ok(None);
+            }
+            self.remaining = size;
+            self.started = true;
+        }
+
+        let mut piece = vec![0; self.remaining.min(READ_CHUNK)];
+        self.reader.read_exact(&mut piece)?;
+        self.remaining -= piece.len();
+        Ok(Some(piece))
+    }
+}
+
+impl<R: BufRead> Iterator for ChunkedBody<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        match self.read_piece() {
+            Ok(Some(piece)) => Some(Ok(piece)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Asks the upstream at `addr` for `path` and fails unless it answers
+/// with a status below 500.
+fn check(
+    addr: SocketAddr,
+    path: &str,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, connect_timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )?;
+    let (status, _) = read_head(&mut BufReader::new(stream))?;
+    if status >= 500 {
+        return Err(io::Error::other(format!("answered {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+    use crate::request::RequestReader;
+    use crate::router::Router;
+    use crate::server::{Server, ShutdownHandle};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    /// Starts a stand-in upstream that answers with its name, the method,
+    /// the path and what it was told about the client.
+    fn backend(name: &'static str, healthy: bool) -> (SocketAddr, ShutdownHandle) {
+        let router = Router::new()
+            .get("/health", move |_, _| {
+                Response::new(if healthy { 200 } else { 503 })
+            })
+            .get("/chunked", |_, _| {
+                let chunks = ["one ", "two ", "three"].map(|c| Ok(c.as_bytes().to_vec()));
+                Response::new(200).with_body(Body::chunked(chunks.into_iter()))
+            })
+            .any("/*path", move |request, _| {
+                let forwarded = request.headers.get("x-forwarded-for").unwrap_or("-");
+                Response::new(200)
+                    .with_header("X-Backend", name)
+                    .with_body(format!(
+                        "{} {} {} for {} with {:?}",
+                        name,
+                        request.method,
+                        request.target,
+                        forwarded,
+                        String::from_utf8_lossy(&request.body)
+                    ))
+            });
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            ThreadPool::new(2),
+            router,
+        );
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        thread::spawn(move || server.run());
+        (addr, handle)
+    }
+
+    /// An address nothing listens on.
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn request(raw: &str) -> Request {
+        let mut request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        request.peer = Some("192.0.2.7:40000".parse().unwrap());
+        request
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn takes_turns_and_adds_forwarded_for() {
+        let (a, stop_a) = backend("a", true);
+        let (b, stop_b) = backend("b", true);
+        let proxy = Proxy::new([a, b]);
+
+        let get =
+            request("GET /items?x=1 HTTP/1.1\r\nHost: t\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n");
+        let bodies: Vec<String> = (0..4)
+            .map(|_| body(proxy.forward(&get, &get.target)))
+            .collect();
+        assert_eq!(
+            bodies[0],
+            "a GET /items?x=1 for 10.0.0.1, 192.0.2.7 with \"\""
+        );
+        assert!(bodies[1].starts_with("b GET"));
+        assert!(bodies[2].starts_with("a GET"));
+        assert!(bodies[3].starts_with("b GET"));
+
+        let post = request(
+            "POST /new HTTP/1.1\r\nHost: t\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        );
+        let response = proxy.forward(&post, "/rewritten");
+        assert_eq!(response.headers.get("x-backend"), Some("a"));
+        assert_eq!(
+            body(response),
+            "a POST /rewritten for 192.0.2.7 with \"abc\""
+        );
+
+        let response = proxy.forward(&get, "/chunked");
+        assert!(response.body.is_chunked());
+        assert_eq!(body(response), "one two three");
+
+        stop_a.shutdown();
+        stop_b.shutdown();
+    }
+
+    #[test]
+    fn skips_upstreams_that_refuse_connections() {
+        let (live, stop) = backend("live", true);
+        let dead = closed_port();
+        let proxy = Proxy::new([dead, live]);
+
+        let get = request("GET / HTTP/1.1\r\nHost: t\r\n\r\n");
+        for _ in 0..3 {
+            assert!(body(proxy.forward(&get, "/")).starts_with("live GET"));
+        }
+        assert!(!proxy.upstreams[0].up.load(Ordering::SeqCst));
+
+        stop.shutdown();
+        let response = Proxy::new([dead]).forward(&get, "/");
+        assert_eq!(response.status, 502);
+    }
+
+    #[test]
+    fn health_checks_mark_failing_upstreams_down() {
+        let (good, stop_good) = backend("good", true);
+        let (bad, stop_bad) = backend("bad", false);
+        let proxy = Proxy::new([bad, good]).with_health_check("/health", Duration::from_millis(50));
+
+        let started = Instant::now();
+        while proxy.upstreams[0].up.load(Ordering::SeqCst) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let get = request("GET / HTTP/1.1\r\nHost: t\r\n\r\n");
+        for _ in 0..3 {
+            assert!(body(proxy.forward(&get, "/")).starts_with("good GET"));
+        }
+
+        stop_good.shutdown();
+        stop_bad.shutdown();
+    }
+
+    #[test]
+    fn answers_504_and_502_for_misbehaving_upstreams() {
+        // One upstream accepts and never answers, the other answers nonsense.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let garbled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let garbled_addr = garbled.local_addr().unwrap();
+        thread::spawn(move || {
+            let _held: Vec<TcpStream> = silent.incoming().map_while(Result::ok).collect();
+        });
+        thread::spawn(move || {
+            for mut stream in garbled.incoming().map_while(Result::ok) {
+                let _ = stream.write_all(b"SPDY/3 ok\r\n\r\n");
+            }
+        });
+
+        let get = request("GET / HTTP/1.1\r\nHost: t\r\n\r\n");
+        let started = Instant::now();
+        let response = Proxy::new([silent_addr])
+            .with_timeout(Duration::from_millis(200))
+            .forward(&get, "/");
+        assert_eq!(response.status, 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let response = Proxy::new([garbled_addr]).forward(&get, "/");
+        assert_eq!(response.status, 502);
+    }
+}
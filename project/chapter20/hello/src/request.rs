@@ -0,0 +1,831 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::num::IntErrorKind;
+use std::time::{Duration, Instant};
+
+/// リクエストメソッド。
+///
+/// The request method.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    pub(crate) fn parse(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// HTTPのバージョン。
+///
+/// The HTTP version of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    /// HTTP/2, whose requests arrive as frames rather than text.
+    Http2,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2.0",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// ヘッダーの一覧。名前の比較は大文字小文字を区別しません。
+///
+/// An ordered list of header fields. Names are compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { fields: Vec::new() }
+    }
+
+    /// Returns the first value of the header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value of the header `name`, in the order they were received.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Returns true if the comma-separated header `name` lists `token`,
+    /// e.g. `contains_token("connection", "close")`.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Adds a field, keeping any existing fields with the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces every field named `name` with a single field.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// 解析済みのHTTPリクエスト。
+///
+/// A parsed HTTP/1.x request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// The address of the client, filled in by the server that received
+    /// the request; `None` otherwise.
+    pub peer: Option<SocketAddr>,
+}
+
+impl Request {
+    /// The path component of the request target, without the query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    /// The query string of the request target, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|i| &self.target[i + 1..])
+    }
+}
+
+/// リクエストの解析に失敗した理由。
+///
+/// Why a request could not be read.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request was malformed (400).
+    BadRequest(&'static str),
+    /// The body was larger than `Limits::max_body_bytes` (413).
+    PayloadTooLarge,
+    /// The request line and headers were larger than
+    /// `Limits::max_header_bytes` (431).
+    HeadersTooLarge,
+    /// The request used an HTTP version other than 1.0 or 1.1 (505).
+    VersionNotSupported,
+    /// The headers or body did not arrive before their deadline (408).
+    Timeout,
+    /// The connection failed or was closed in the middle of a request.
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status code and reason phrase to answer this error with, or
+    /// `None` if the connection is unusable and should just be dropped.
+    pub fn status(&self) -> Option<(u16, &'static str)> {
+        match self {
+            ParseError::BadRequest(_) => Some((400, "Bad Request")),
+            ParseError::PayloadTooLarge => Some((413, "Payload Too Large")),
+            ParseError::HeadersTooLarge => Some((431, "Request Header Fields Too Large")),
+            ParseError::VersionNotSupported => Some((505, "HTTP Version Not Supported")),
+            ParseError::Timeout => Some((408, "Request Timeout")),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ParseError::PayloadTooLarge => f.write_str("request body too large"),
+            ParseError::HeadersTooLarge => f.write_str("request headers too large"),
+            ParseError::VersionNotSupported => f.write_str("HTTP version not supported"),
+            ParseError::Timeout => f.write_str("request not received in time"),
+            ParseError::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// 受け付けるリクエストの大きさと、受け取りにかけられる時間の上限。
+///
+/// Size limits and deadlines applied while reading a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of the request line plus headers (and chunked trailers).
+    pub max_header_bytes: usize,
+    /// Maximum size of the decoded body.
+    pub max_body_bytes: usize,
+    /// How long the request line and headers may take to arrive, counted
+    /// from their first byte. Waiting for that first byte is not limited.
+    pub header_timeout: Option<Duration>,
+    /// How long the body may take to arrive once the headers are complete.
+    pub body_timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_bytes: 8 * 1024,
+            max_body_bytes: 1024 * 1024,
+            header_timeout: None,
+            body_timeout: None,
+        }
+    }
+}
+
+type SetReadTimeout = Box<dyn FnMut(Option<Duration>) -> io::Result<()>>;
+
+const READ_CHUNK: usize = 4096;
+
+/// ストリームから少しずつ読み込みながらリクエストを解析します。
+///
+/// 読みすぎたバイトは次のリクエストのために保持されます。
+///
+/// Parses requests from a stream, reading only as much as it needs.
+///
+/// Bytes read past the end of one request are kept for the next call to
+/// `read_request`.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+    // 読み込み中のヘッダーか本文の期限
+    deadline: Option<Instant>,
+    set_read_timeout: Option<SetReadTimeout>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R) -> RequestReader<R> {
+        RequestReader::with_limits(inner, Limits::default())
+    }
+
+    pub fn with_limits(inner: R, limits: Limits) -> RequestReader<R> {
+        RequestReader {
+            inner,
+            buf: Vec::new(),
+            limits,
+            deadline: None,
+            set_read_timeout: None,
+        }
+    }
+
+    /// Calls `set_read_timeout` with the time left before each read while
+    /// a deadline is running, and with `None` once it is over, so that a
+    /// blocking read returns in time. Without it, deadlines are only
+    /// checked between reads.
+    pub fn with_read_timeout<F>(mut self, set_read_timeout: F) -> RequestReader<R>
+    where
+        F: FnMut(Option<Duration>) -> io::Result<()> + 'static,
+    {
+        self.set_read_timeout = Some(Box::new(set_read_timeout));
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Bytes already read from the stream but not yet part of a request.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Reads the next request.
+    ///
+    /// Returns `Ok(None)` if the peer closed the connection cleanly before
+    /// sending any part of a new request.
+    pub fn read_request(&mut self) -> Result<Option<Request>, ParseError> {
+        self.set_deadline(None)?;
+        let head_len = match self.read_head()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let head: Vec<u8> = self.buf.drain(..head_len).collect();
+        let (method, target, version, headers) = parse_head(&head)?;
+
+        self.set_deadline(self.limits.body_timeout)?;
+        let body = self.read_body(&headers)?;
+        self.set_deadline(None)?;
+
+        Ok(Some(Request {
+            method,
+            target,
+            version,
+            headers,
+            body,
+            peer: None,
+        }))
+    }
+
+    /// Reads until the buffer holds a complete request head and returns its
+    /// length including the blank line.
+    fn read_head(&mut self) -> Result<Option<usize>, ParseError> {
+        // RFC 9112 2.2: ignore empty lines received before a request line.
+        loop {
+            while self.buf.starts_with(b"\r\n") || self.buf.starts_with(b"\n") {
+                let n = if self.buf[0] == b'\r' { 2 } else { 1 };
+                self.buf.drain(..n);
+            }
+            if !self.buf.is_empty() && self.buf != b"\r" {
+                break;
+            }
+            if self.fill()? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(unexpected_eof())
+                };
+            }
+        }
+
+        // リクエストの最初のバイトが届いたので、ヘッダーの期限を数え始めます
+        self.set_deadline(self.limits.header_timeout)?;
+        let mut searched = 0;
+        loop {
+            if let Some(end) = find_head_end(&self.buf, searched) {
+                if end > self.limits.max_header_bytes {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                return Ok(Some(end));
+            }
+            if self.buf.len() > self.limits.max_header_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            searched = self.buf.len().saturating_sub(3);
+            if self.fill()? == 0 {
+                return Err(unexpected_eof());
+            }
+        }
+    }
+
+    fn read_body(&mut self, headers: &Headers) -> Result<Vec<u8>, ParseError> {
+        match framing(headers)? {
+            Framing::Chunked => self.read_chunked(),
+            Framing::Length(n) if n > self.limits.max_body_bytes => {
+                Err(ParseError::PayloadTooLarge)
+            }
+            Framing::Length(n) => self.take(n),
+        }
+    }
+
+    fn read_chunked(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line(self.limits.max_header_bytes)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ParseError::BadRequest("invalid chunk size"));
+            }
+            // 桁があふれる大きさだけが413で、それ以外の誤りは400です
+            let size = usize::from_str_radix(size, 16).map_err(|e| match e.kind() {
+                IntErrorKind::PosOverflow => ParseError::PayloadTooLarge,
+                _ => ParseError::BadRequest("invalid chunk size"),
+            })?;
+            if size == 0 {
+                break;
+            }
+            if size > self.limits.max_body_bytes - body.len() {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            body.extend(self.take(size)?);
+            if !self.read_line(2)?.is_empty() {
+                return Err(ParseError::BadRequest("chunk data not followed by CRLF"));
+            }
+        }
+
+        // Trailer fields are read to keep the stream in sync but not kept.
+        let mut trailer_bytes = 0;
+        loop {
+            let remaining = self.limits.max_header_bytes.saturating_sub(trailer_bytes);
+            let line = self.read_line(remaining)?;
+            if line.is_empty() {
+                break;
+            }
+            trailer_bytes += line.len() + 2;
+            parse_header(&line)?;
+        }
+
+        Ok(body)
+    }
+
+    /// Reads one line of at most `max` bytes, without its line ending.
+    fn read_line(&mut self, max: usize) -> Result<String, ParseError> {
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buf[searched..].iter().position(|&b| b == b'\n') {
+                let end = searched + i;
+                if end > max + 1 {
+                    return Err(ParseError::HeadersTooLarge);
+                }
+                let mut line: Vec<u8> = self.buf.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return String::from_utf8(line)
+                    .map_err(|_| ParseError::BadRequest("line is not valid UTF-8"));
+            }
+            if self.buf.len() > max + 1 {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            searched = self.buf.len();
+            if self.fill()? == 0 {
+                return Err(unexpected_eof());
+            }
+        }
+    }
+
+    /// Removes exactly `n` bytes from the front of the stream.
+    fn take(&mut self, n: usize) -> Result<Vec<u8>, ParseError> {
+        while self.buf.len() < n {
+            let want = (n - self.buf.len()).max(READ_CHUNK);
+            if self.fill_up_to(want)? == 0 {
+                return Err(unexpected_eof());
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    /// Starts a deadline `timeout` from now, or ends the current one.
+    fn set_deadline(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let had_deadline = self.deadline.is_some();
+        self.deadline = timeout.map(|t| Instant::now() + t);
+        if had_deadline
+            && self.deadline.is_none()
+            && let Some(set_read_timeout) = &mut self.set_read_timeout
+        {
+            set_read_timeout(None)?;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<usize, ParseError> {
+        self.fill_up_to(READ_CHUNK)
+    }
+
+    fn fill_up_to(&mut self, want: usize) -> Result<usize, ParseError> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ParseError::Timeout);
+            }
+            if let Some(set_read_timeout) = &mut self.set_read_timeout {
+                set_read_timeout(Some(remaining))?;
+            }
+        }
+
+        let start = self.buf.len();
+        self.buf.resize(start + want, 0);
+        let result = loop {
+            match self.inner.read(&mut self.buf[start..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                other => break other,
+            }
+        };
+        self.buf.truncate(start + *result.as_ref().unwrap_or(&0));
+        match result {
+            Err(e)
+                if self.deadline.is_some()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                Err(ParseError::Timeout)
+            }
+            other => Ok(other?),
+        }
+    }
+}
+
+fn unexpected_eof() -> ParseError {
+    ParseError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed in the middle of a request",
+    ))
+}
+
+/// Finds the end of the header block (after `\r\n\r\n` or `\n\n`),
+/// starting the search at `from`.
+pub(crate) fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while let Some(pos) = buf[i..].iter().position(|&b| b == b'\n') {
+        let nl = i + pos;
+        let rest = &buf[nl + 1..];
+        if rest.starts_with(b"\r\n") {
+            return Some(nl + 3);
+        }
+        if rest.starts_with(b"\n") {
+            return Some(nl + 2);
+        }
+        i = nl + 1;
+    }
+    None
+}
+
+/// Parses a complete request head, as found by `find_head_end`.
+pub(crate) fn parse_head(head: &[u8]) -> Result<(Method, String, Version, Headers), ParseError> {
+    let head = std::str::from_utf8(head)
+        .map_err(|_| ParseError::BadRequest("request head is not valid UTF-8"))?;
+
+    let mut lines = head.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l));
+    let (method, target, version) = parse_request_line(lines.next().unwrap_or(""))?;
+
+    let mut headers = Headers::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (name, value) = parse_header(line)?;
+        headers.append(name, value);
+    }
+
+    if version == Version::Http11 && headers.get_all("host").count() != 1 {
+        return Err(ParseError::BadRequest(
+            "HTTP/1.1 requires exactly one Host header",
+        ));
+    }
+    Ok((method, target, version, headers))
+}
+
+/// 本文の区切り方。
+///
+/// How the body of a request is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// Exactly this many bytes follow the head.
+    Length(usize),
+    /// The body uses the chunked transfer coding.
+    Chunked,
+}
+
+/// Tells from the request headers how the body is delimited.
+pub(crate) fn framing(headers: &Headers) -> Result<Framing, ParseError> {
+    if headers.contains("transfer-encoding") {
+        if headers.contains("content-length") {
+            return Err(ParseError::BadRequest(
+                "both Transfer-Encoding and Content-Length present",
+            ));
+        }
+        let last = headers
+            .get_all("transfer-encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .last();
+        return match last {
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+            _ => Err(ParseError::BadRequest(
+                "final transfer coding is not chunked",
+            )),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all("content-length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        // Anything that overflows is certainly too large.
+        let n: usize = value.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+        if length.is_some_and(|l| l != n) {
+            return Err(ParseError::BadRequest("conflicting Content-Length values"));
+        }
+        length = Some(n);
+    }
+    Ok(Framing::Length(length.unwrap_or(0)))
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+
+    if !is_token(method) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if !is_request_target(target) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(ParseError::VersionNotSupported),
+        _ => return Err(ParseError::BadRequest("invalid HTTP version")),
+    };
+
+    Ok((Method::parse(method), target.to_string(), version))
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    if line.starts_with([' ', '\t']) {
+        return Err(ParseError::BadRequest("obsolete header line folding"));
+    }
+    let colon = line
+        .find(':')
+        .ok_or(ParseError::BadRequest("header line without a colon"))?;
+    let name = &line[..colon];
+    if !is_token(name) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    let value = line[colon + 1..].trim_matches([' ', '\t']);
+    if !is_field_value(value) {
+        return Err(ParseError::BadRequest("invalid header value"));
+    }
+    Ok((name, value))
+}
+
+/// Whether `s` is a token (RFC 9110 5.6.2), as methods and header names
+/// must be.
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_token_byte)
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Whether `value` may be a header value: no control characters but tabs.
+pub(crate) fn is_field_value(value: &str) -> bool {
+    !value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+}
+
+/// Whether `target` may be a request target: not empty, and without
+/// control characters.
+pub(crate) fn is_request_target(target: &str) -> bool {
+    !target.is_empty() && !target.bytes().any(|b| b.is_ascii_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most one byte per `read`, like a very slow client.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        RequestReader::new(raw).read_request()
+    }
+
+    fn status_of(raw: &[u8]) -> u16 {
+        parse(raw).unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn parses_simple_get() {
+        let req = parse(b"GET /index.html?x=1 HTTP/1.1\r\nHost: a\r\nUser-Agent: t\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path(), "/index.html");
+        assert_eq!(req.query(), Some("x=1"));
+        assert_eq!(req.version, Version::Http11);
+        assert_eq!(req.headers.get("user-agent"), Some("t"));
+        assert!(req.body.is_empty());
+    }
+
+    #[test]
+    fn reads_incrementally_and_keeps_pipelined_bytes() {
+        let raw =
+            b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.0\r\n\r\n";
+        let mut reader = RequestReader::new(Trickle(raw));
+
+        let first = reader.read_request().unwrap().unwrap();
+        assert_eq!(first.method, Method::Post);
+        assert_eq!(first.body, b"hello");
+
+        let second = reader.read_request().unwrap().unwrap();
+        assert_eq!(second.target, "/b");
+        assert_eq!(second.version, Version::Http10);
+
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let req = parse(raw).unwrap().unwrap();
+        assert_eq!(req.body, b"Wikipedia in \r\n\r\nchunks.");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(status_of(b"GET /\r\n\r\n"), 400);
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\n\r\n"), 400);
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"), 400);
+        assert_eq!(
+            status_of(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n"),
+            400
+        );
+        assert_eq!(
+            status_of(
+                b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"
+            ),
+            400
+        );
+        assert_eq!(status_of(b"GET / HTTP/2.0\r\n\r\n"), 505);
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            400
+        );
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let limits = Limits {
+            max_header_bytes: 64,
+            max_body_bytes: 4,
+            ..Limits::default()
+        };
+
+        let long = format!(
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(100)
+        );
+        let err = RequestReader::with_limits(long.as_bytes(), limits)
+            .read_request()
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().0, 431);
+
+        let big = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let err = RequestReader::with_limits(&big[..], limits)
+            .read_request()
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().0, 413);
+
+        let chunked = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+        let err = RequestReader::with_limits(&chunked[..], limits)
+            .read_request()
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().0, 413);
+
+        // 前の断片に足すとあふれる大きさも、上限を超えたものとして扱います
+        let huge = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        let err = RequestReader::with_limits(&huge[..], limits)
+            .read_request()
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().0, 413);
+        let err = parse(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n10000000000000000\r\n")
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().0, 413);
+    }
+
+    #[test]
+    fn truncated_request_is_an_io_error() {
+        let err =
+            parse(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nabc").unwrap_err();
+        assert!(err.status().is_none());
+    }
+}
@@ -0,0 +1,522 @@
+use crate::request::{Headers, Version};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+/// 本文を少しずつ生成するイテレーター。
+///
+/// The pieces of a body produced while the response is written.
+pub type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+// 本文をファイルなどから読むときの単位
+const READ_CHUNK: usize = 16 * 1024;
+
+/// レスポンスの本文。
+///
+/// The body of a response. Apart from `Bytes`, bodies are produced while the
+/// response is written, so that large files or generated content are never
+/// held in memory at once.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes read from `reader`.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    /// `len` bytes in total, produced by `chunks`.
+    Stream {
+        chunks: Chunks,
+        len: u64,
+    },
+    /// Chunks of an unknown total length, sent with the chunked transfer
+    /// coding (or until the connection closes, to HTTP/1.0 clients).
+    Chunked(Chunks),
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body::Bytes(Vec::new())
+    }
+
+    /// A body of `len` bytes read from `reader` when the response is written.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, len: u64) -> Body {
+        Body::Reader {
+            reader: Box::new(reader),
+            len,
+        }
+    }
+
+    /// The rest of `file`, from its current position.
+    pub fn file(mut file: File) -> io::Result<Body> {
+        let len = file.metadata()?.len();
+        let position = file.stream_position()?;
+        Ok(Body::from_reader(file, len.saturating_sub(position)))
+    }
+
+    /// A body of exactly `len` bytes, produced by `chunks`.
+    pub fn stream<I>(chunks: I, len: u64) -> Body
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        Body::Stream {
+            chunks: Box::new(chunks),
+            len,
+        }
+    }
+
+    /// A body of unknown length, produced by `chunks`.
+    pub fn chunked<I>(chunks: I) -> Body
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        Body::Chunked(Box::new(chunks))
+    }
+
+    /// The length of the body, unless it is chunked.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } | Body::Stream { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        matches!(self, Body::Chunked(_))
+    }
+
+    /// The body, if it is held in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for chunk in self.into_chunks() {
+            out.extend(chunk?);
+        }
+        Ok(out)
+    }
+
+    /// Turns any body into the chunks it is made of.
+    pub fn into_chunks(self) -> Chunks {
+        match self {
+            Body::Bytes(bytes) => Box::new(Some(Ok(bytes)).into_iter()),
+            Body::Reader { reader, len } => Box::new(ReadChunks {
+                reader: reader.take(len),
+                remaining: len,
+            }),
+            Body::Stream { chunks, .. } | Body::Chunked(chunks) => chunks,
+        }
+    }
+
+    /// Writes the body, framed as `chunked` if asked, and returns the
+    /// number of body bytes written.
+    fn write_to<W: Write>(&mut self, w: &mut W, chunked: bool) -> io::Result<u64> {
+        let expected = self.len();
+        let mut written = 0;
+        let body = std::mem::replace(self, Body::empty());
+        // 書き込んだ後も長さが分かるよう、空の本文を残しておきます
+        if let Some(len) = expected {
+            *self = Body::Stream {
+                chunks: Box::new(std::iter::empty()),
+                len,
+            };
+        }
+
+        for chunk in body.into_chunks() {
+            let chunk = chunk?;
+            if chunk.is_empty() {
+                continue;
+            }
+            if chunked {
+                write!(w, "{:x}\r\n", chunk.len())?;
+                w.write_all(&chunk)?;
+                w.write_all(b"\r\n")?;
+            } else {
+                w.write_all(&chunk)?;
+            }
+            written += chunk.len() as u64;
+        }
+        if chunked {
+            w.write_all(b"0\r\n\r\n")?;
+        }
+
+        if expected.is_some_and(|len| len != written) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "response body does not match its length",
+            ));
+        }
+        Ok(written)
+    }
+}
+
+/// Reads a body in `READ_CHUNK`-sized pieces.
+struct ReadChunks<R> {
+    reader: io::Take<R>,
+    remaining: u64,
+}
+
+impl<R: Read> Iterator for ReadChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0; self.remaining.min(READ_CHUNK as u64) as usize];
+        match self.reader.read(&mut buf) {
+            Ok(0) => {
+                self.remaining = 0;
+                Some(Err(io::ErrorKind::UnexpectedEof.into()))
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                self.remaining -= n as u64;
+                Some(Ok(buf))
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => self.next(),
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+            Body::Stream { len, .. } => f.debug_struct("Stream").field("len", len).finish(),
+            Body::Chunked(_) => f.write_str("Chunked"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Body {
+        Body::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Body {
+        Body::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+// テストで`assert_eq!(response.body, b"...")`と書けるようにします
+impl<const N: usize> PartialEq<&[u8; N]> for Body {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self.as_bytes() == Some(&other[..])
+    }
+}
+
+/// ハンドラーが返すHTTPレスポンス。
+///
+/// An HTTP response produced by a handler.
+///
+/// ```
+/// use hello::response::{Body, Response};
+///
+/// let lines = (1..=3).map(|i| Ok(format!("line {}\n", i).into_bytes()));
+/// let response = Response::new(200)
+///     .with_header("Content-Type", "text/plain; charset=utf-8")
+///     .with_body(Body::chunked(lines));
+/// assert!(response.body.is_chunked());
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+    upgrade: Option<OnUpgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::empty(),
+            upgrade: None,
+        }
+    }
+
+    /// Hands the connection to `on_upgrade` once a `101 Switching
+    /// Protocols` response has been written, on the same worker; the
+    /// connection is closed when it returns. Ignored for other statuses.
+    pub fn with_upgrade<F>(mut self, on_upgrade: F) -> Response
+    where
+        F: FnOnce(Upgraded<'_>) + Send + 'static,
+    {
+        self.upgrade = Some(OnUpgrade(Box::new(on_upgrade)));
+        self
+    }
+
+    /// Takes the function that takes over the connection, if this is a
+    /// 101 response that has one.
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade.take().filter(|_| self.status == 101)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body<B: Into<Body>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// `HEAD`への応答から本文を除きます。
+    ///
+    /// Drops the body of a response to `HEAD`, keeping the headers that
+    /// describe it: `Content-Length` when its length is known, or
+    /// `Transfer-Encoding: chunked` when it is not.
+    pub(crate) fn strip_body(&mut self) {
+        if has_body(self.status) && !self.headers.contains("content-length") {
+            match self.body.len() {
+                Some(len) => self.headers.insert("Content-Length", &len.to_string()),
+                None => self.headers.insert("Transfer-Encoding", "chunked"),
+            }
+        }
+        self.body = Body::empty();
+    }
+
+    /// Writes the response for an HTTP/1.1 client; see `write_for`.
+    pub fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<u64> {
+        self.write_for(w, Version::Http11)
+    }
+
+    /// Writes the status line, headers and body, and returns the number of
+    /// body bytes written.
+    ///
+    /// `Content-Length` is filled in from the body unless a handler already
+    /// set it or the status forbids a body. A chunked body is sent with
+    /// `Transfer-Encoding: chunked` to HTTP/1.1 clients; HTTP/1.0 clients get
+    /// the bare bytes, so the connection must be closed after it.
+    ///
+    /// The body is consumed; its length is kept.
+    pub fn write_for<W: Write>(&mut self, w: &mut W, version: Version) -> io::Result<u64> {
+        let chunked = self.body.is_chunked() && version == Version::Http11 && has_body(self.status);
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        if chunked {
+            self.headers.remove("content-length");
+            self.headers.insert("Transfer-Encoding", "chunked");
+        } else if let Some(len) = self.body.len()
+            && !self.headers.contains("content-length")
+            && !self.headers.contains("transfer-encoding")
+            && has_body(self.status)
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", len));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        // 見出しと本文の先頭をまとめて送ります
+        let mut w = io::BufWriter::with_capacity(READ_CHUNK, w);
+        w.write_all(head.as_bytes())?;
+        let written = if has_body(self.status) {
+            self.body.write_to(&mut w, chunked)?
+        } else {
+            0
+        };
+        w.flush()?;
+        Ok(written)
+    }
+}
+
+/// `101 Switching Protocols`の後で接続を引き継ぐ処理。
+pub(crate) struct OnUpgrade(Box<dyn for<'a> FnOnce(Upgraded<'a>) + Send>);
+
+impl OnUpgrade {
+    pub(crate) fn run(self, upgraded: Upgraded) {
+        (self.0)(upgraded)
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
+
+/// 読み書きできる接続。
+///
+/// A connection that can be read and written, plain or TLS.
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write + ?Sized> Stream for T {}
+
+/// プロトコルを切り替えた後の接続。
+///
+/// A connection after `101 Switching Protocols`. Reads first return the
+/// bytes the client sent after its request that the server had already
+/// read.
+pub struct Upgraded<'a> {
+    buffered: io::Cursor<Vec<u8>>,
+    stream: &'a mut dyn Stream,
+}
+
+impl<'a> Upgraded<'a> {
+    pub fn new(stream: &'a mut dyn Stream, buffered: Vec<u8>) -> Upgraded<'a> {
+        Upgraded {
+            buffered: io::Cursor::new(buffered),
+            stream,
+        }
+    }
+}
+
+impl Read for Upgraded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// 1xx, 204 and 304 responses never have a body.
+pub(crate) fn has_body(status: u16) -> bool {
+    !(100..200).contains(&status) && status != 204 && status != 304
+}
+
+/// Returns the standard reason phrase for `status`.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines() -> Body {
+        Body::chunked((1..=2).map(|i| Ok(format!("line {}\n", i).into_bytes())))
+    }
+
+    #[test]
+    fn writes_chunked_bodies() {
+        let mut out = Vec::new();
+        let written = Response::new(200)
+            .with_body(lines())
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(written, 14);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             7\r\nline 1\n\r\n7\r\nline 2\n\r\n0\r\n\r\n"
+        );
+
+        // HTTP/1.0には枠なしで送り、接続を閉じて終わりを知らせます
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_body(lines())
+            .write_for(&mut out, Version::Http10)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\n\r\nline 1\nline 2\n"
+        );
+    }
+
+    #[test]
+    fn writes_streams_and_files_with_their_length() {
+        let chunks = vec![Ok(b"ab".to_vec()), Ok(b"cd".to_vec())];
+        let mut response = Response::new(200).with_body(Body::stream(chunks.into_iter(), 4));
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nabcd");
+        assert_eq!(response.body.len(), Some(4));
+
+        let mut short = Response::new(200).with_body(Body::stream(std::iter::empty(), 4));
+        let err = short.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn omits_the_body_when_the_status_forbids_one() {
+        let mut out = Vec::new();
+        Response::new(304)
+            .with_header("ETag", "\"x\"")
+            .with_body("ignored")
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 304 Not Modified\r\nETag: \"x\"\r\n\r\n");
+    }
+}
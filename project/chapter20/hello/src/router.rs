@@ -0,0 +1,376 @@
+use crate::middleware::Middleware;
+use crate::request::{Method, Request};
+use crate::response::Response;
+
+type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync + 'static>;
+
+/// パスパターンから取り出した値。
+///
+/// Values captured from the path by `:name` and `*name` segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    /// `None` matches every method.
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+/// メソッドとパスパターンでハンドラーを選ぶルーター。
+///
+/// パターンは`/users/:id`のような名前付きセグメントと、末尾の
+/// `/static/*path`のようなワイルドカードを使えます。登録した順に照合します。
+///
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns may contain named segments such as `/users/:id` and a trailing
+/// wildcard such as `/static/*path`, which captures the rest of the path.
+/// Routes are tried in the order they were registered.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_, _| Response::new(404).with_body("Not Found")),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Registers `handler` for `method` requests matching `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` does not start with `/` or has a wildcard segment
+    /// anywhere but at the end.
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: Some(method),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Registers `handler` for requests matching `pattern`, whatever their
+    /// method, e.g. to forward them elsewhere.
+    pub fn any<F>(mut self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: None,
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Sets the handler used when no pattern matches the path.
+    pub fn not_found<F>(mut self, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// ハンドラーの前後で動くミドルウェアを追加します。
+    ///
+    /// Adds `middleware` to the end of the chain that `dispatch` runs
+    /// around the handlers; see `Middleware` for the order of the hooks.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs `request` through the middleware chain and the handler chosen
+    /// by `handle`. This is what the server calls for each request.
+    pub fn dispatch(&self, request: &mut Request) -> Response {
+        // 途中で応答したミドルウェアより前のものだけ、afterを呼びます
+        let mut ran = 0;
+        let mut answered = None;
+        for middleware in &self.middleware {
+            answered = middleware.before(request);
+            if answered.is_some() {
+                break;
+            }
+            ran += 1;
+        }
+
+        let mut response = answered.unwrap_or_else(|| self.handle(request));
+        for middleware in self.middleware[..ran].iter().rev() {
+            middleware.after(request, &mut response);
+        }
+        response
+    }
+
+    /// Runs the handler for `request`, without the middleware.
+    ///
+    /// `HEAD` falls back to the `GET` handler; the server drops the body of
+    /// every response to `HEAD` before writing it. If the
+    /// path matches but the method does not, answers 405 with an `Allow`
+    /// header listing the methods that would have matched.
+    pub fn handle(&self, request: &Request) -> Response {
+        let path = request.path();
+        let mut allowed: Vec<&Method> = Vec::new();
+        let mut head_fallback = None;
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, path) {
+                Some(params) => params,
+                None => continue,
+            };
+            let Some(method) = &route.method else {
+                return (route.handler)(request, &params);
+            };
+            if *method == request.method {
+                return (route.handler)(request, &params);
+            }
+            if request.method == Method::Head && *method == Method::Get && head_fallback.is_none() {
+                head_fallback = Some((route, params));
+            }
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+
+        if let Some((route, params)) = head_fallback {
+            return (route.handler)(request, &params);
+        }
+
+        if allowed.is_empty() {
+            return (self.not_found)(request, &Params::default());
+        }
+
+        if allowed.contains(&&Method::Get) && !allowed.contains(&&Method::Head) {
+            allowed.push(&Method::Head);
+        }
+        let allow: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+        Response::new(405)
+            .with_header("Allow", &allow.join(", "))
+            .with_body("Method Not Allowed")
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(
+        pattern.starts_with('/'),
+        "route pattern must start with '/'"
+    );
+
+    let parts: Vec<&str> = pattern[1..].split('/').filter(|s| !s.is_empty()).collect();
+    let mut segments = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        if let Some(name) = part.strip_prefix(':') {
+            segments.push(Segment::Param(name.to_string()));
+        } else if let Some(name) = part.strip_prefix('*') {
+            assert!(i == parts.len() - 1, "wildcard must be the last segment");
+            segments.push(Segment::Wildcard(name.to_string()));
+        } else {
+            segments.push(Segment::Literal(part.to_string()));
+        }
+    }
+    segments
+}
+
+fn match_segments(segments: &[Segment], path: &str) -> Option<Params> {
+    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut params = Params::default();
+
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Literal(lit) => {
+                if parts.get(i).map(|p| percent_decode(p)) != Some(lit.clone()) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let part = parts.get(i)?;
+                params.pairs.push((name.clone(), percent_decode(part)));
+            }
+            Segment::Wildcard(name) => {
+                let rest: Vec<String> = parts[i.min(parts.len())..]
+                    .iter()
+                    .map(|p| percent_decode(p))
+                    .collect();
+                params.pairs.push((name.clone(), rest.join("/")));
+                return Some(params);
+            }
+        }
+    }
+
+    if parts.len() == segments.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are left as they are.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestReader;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, target);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_, _| Response::new(200).with_body("home"))
+            .get("/users/:id", |_, p| {
+                Response::new(200).with_body(format!("user {}", p.get("id").unwrap()))
+            })
+            .delete("/users/:id", |_, _| Response::new(204))
+            .get("/static/*path", |_, p| {
+                Response::new(200).with_body(p.get("path").unwrap().to_string())
+            })
+    }
+
+    #[test]
+    fn matches_literals_and_params() {
+        let router = router();
+        assert_eq!(router.handle(&request("GET", "/")).body, b"home");
+        assert_eq!(
+            router.handle(&request("GET", "/users/42?x=1")).body,
+            b"user 42"
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/users/a%20b")).body,
+            b"user a b"
+        );
+        assert_eq!(router.handle(&request("DELETE", "/users/42")).status, 204);
+        assert_eq!(router.handle(&request("GET", "/users")).status, 404);
+        assert_eq!(router.handle(&request("GET", "/users/1/2")).status, 404);
+    }
+
+    #[test]
+    fn wildcard_captures_the_rest() {
+        let router = router();
+        assert_eq!(
+            router.handle(&request("GET", "/static/css/site.css")).body,
+            b"css/site.css"
+        );
+        assert_eq!(router.handle(&request("GET", "/static")).body, b"");
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let response = router().handle(&request("POST", "/users/1"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("allow"), Some("GET, DELETE, HEAD"));
+    }
+
+    #[test]
+    fn head_uses_get_handler() {
+        let mut response = router().handle(&request("HEAD", "/"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.len(), Some(4));
+
+        response.strip_body();
+        assert_eq!(response.headers.get("content-length"), Some("4"));
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn any_matches_every_method() {
+        let router = router().any("/api/*rest", |r, p| {
+            Response::new(200).with_body(format!("{} {}", r.method, p.get("rest").unwrap()))
+        });
+        for method in ["GET", "PATCH", "DELETE"] {
+            let response = router.handle(&request(method, "/api/v1/items"));
+            assert_eq!(
+                response.body.as_bytes(),
+                Some(format!("{} v1/items", method).as_bytes())
+            );
+        }
+    }
+}
@@ -0,0 +1,1210 @@
+use crate::compression;
+use crate::date::DateTime;
+use crate::log::{self, ACCESS_TARGET, Level};
+use crate::metrics::Metrics;
+use crate::request::{Limits, Method, ParseError, Request, RequestReader, Version};
+use crate::response::{Response, Upgraded};
+use crate::router::Router;
+use crate::tls::TlsConfig;
+use crate::{ShutdownTimeout, StatsHandle, ThreadPool};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod h2c;
+
+/// `Server`が計測値を返すパス。
+///
+/// The path at which a `Server` answers with its metrics.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// 持続的接続の設定。
+///
+/// Settings for persistent (keep-alive) connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// How long to wait for the next request before closing the connection.
+    pub idle_timeout: Duration,
+    /// How many requests to serve on one connection before closing it.
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// 遅いクライアントや多すぎる接続から、サーバーを守るための上限。
+///
+/// Limits that keep slow or greedy clients from tying up the server.
+/// A request whose headers or body miss their deadline is answered with
+/// 408 and its connection closed; a connection whose writes stall for
+/// longer than `write_timeout` is dropped, as are connections beyond
+/// `max_connections_per_ip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Size limits and read deadlines for each request.
+    pub request: Limits,
+    /// How long a single write to the client may block.
+    pub write_timeout: Duration,
+    /// How many connections one client IP address may have open at once;
+    /// `None` for no limit.
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits {
+            request: Limits {
+                header_timeout: Some(Duration::from_secs(10)),
+                body_timeout: Some(Duration::from_secs(30)),
+                ..Limits::default()
+            },
+            write_timeout: Duration::from_secs(30),
+            max_connections_per_ip: None,
+        }
+    }
+}
+
+/// 接続を受け付けて、スレッドプールで処理するサーバー。
+///
+/// Accepts connections and serves each one on a `ThreadPool` worker until
+/// it is told to shut down through a `ShutdownHandle`.
+///
+/// A server can accept plain HTTP connections on several listeners, e.g. an
+/// IPv4 and an IPv6 address, and HTTPS connections on any number of TLS
+/// listeners at the same time; TLS is terminated on the worker that serves
+/// the connection.
+///
+/// On Linux, `with_event_loop` switches the plain HTTP listeners to a few
+/// epoll event loops, so that idle connections no longer hold a worker.
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    tls_listeners: Vec<(TcpListener, TlsConfig)>,
+    pool: ThreadPool,
+    settings: Arc<RwLock<Settings>>,
+    metrics: Arc<Metrics>,
+    shutdown_timeout: Duration,
+    #[cfg(target_os = "linux")]
+    event_loops: Option<usize>,
+    state: Arc<ShutdownState>,
+}
+
+/// `ReloadHandle`で差し替えられる設定。
+///
+/// The settings a `ReloadHandle` can change while the server runs. Each
+/// connection takes a copy when it is accepted.
+#[derive(Clone)]
+struct Settings {
+    router: Arc<Router>,
+    keep_alive: KeepAlive,
+    compression: bool,
+    limits: ConnectionLimits,
+    http2: bool,
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    next_id: AtomicU64,
+    // 停止時に待機中の接続を閉じるため、開いている接続を覚えておきます
+    connections: Mutex<HashMap<u64, TcpStream>>,
+}
+
+impl ShutdownState {
+    fn register(&self, stream: &TcpStream) -> Option<u64> {
+        let clone = stream.try_clone().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut connections = self.connections.lock().unwrap();
+        // 停止処理の後にキューから取り出された接続は、すぐに閉じます
+        if self.requested.load(Ordering::SeqCst) {
+            let _ = clone.shutdown(Shutdown::Read);
+        }
+        connections.insert(id, clone);
+        Some(id)
+    }
+
+    fn unregister(&self, id: Option<u64>) {
+        if let Some(id) = id {
+            self.connections.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+impl Server {
+    pub fn new(listener: TcpListener, pool: ThreadPool, router: Router) -> Server {
+        Server {
+            listeners: vec![listener],
+            tls_listeners: Vec::new(),
+            pool,
+            settings: Arc::new(RwLock::new(Settings {
+                router: Arc::new(router),
+                keep_alive: KeepAlive::default(),
+                compression: false,
+                limits: ConnectionLimits::default(),
+                http2: false,
+            })),
+            metrics: Arc::new(Metrics::new()),
+            shutdown_timeout: Duration::from_secs(30),
+            #[cfg(target_os = "linux")]
+            event_loops: None,
+            state: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                next_id: AtomicU64::new(0),
+                connections: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Also accepts plain HTTP connections on `listener`.
+    pub fn with_listener(mut self, listener: TcpListener) -> Server {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Also accepts HTTPS connections on `listener`, presenting `tls`'s
+    /// certificate.
+    pub fn with_tls_listener(mut self, listener: TcpListener, tls: TlsConfig) -> Server {
+        self.tls_listeners.push((listener, tls));
+        self
+    }
+
+    pub fn with_keep_alive(self, keep_alive: KeepAlive) -> Server {
+        write(&self.settings).keep_alive = keep_alive;
+        self
+    }
+
+    /// Compresses responses with gzip or deflate when the client accepts
+    /// it. Off by default.
+    pub fn with_compression(self, compression: bool) -> Server {
+        write(&self.settings).compression = compression;
+        self
+    }
+
+    /// Sets the deadlines and limits that protect the server from slow
+    /// clients. See `ConnectionLimits` for the defaults.
+    pub fn with_limits(self, limits: ConnectionLimits) -> Server {
+        write(&self.settings).limits = limits;
+        self
+    }
+
+    /// 平文の接続で、HTTP/2 (h2c) も受け付けます。
+    ///
+    /// Also serves cleartext HTTP/2 to clients that open a plain connection
+    /// with the HTTP/2 preface. Off by default. An HTTP/2 connection holds a
+    /// pool worker while it is open, and each of its streams is answered on
+    /// another, so the pool needs more workers than there are HTTP/2
+    /// clients.
+    pub fn with_http2(self, http2: bool) -> Server {
+        write(&self.settings).http2 = http2;
+        self
+    }
+
+    /// Sets how long `run` waits for in-flight jobs after it stops accepting.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// 平文の接続を、`threads`個のイベントループで待ち受けます。
+    ///
+    /// Serves the plain HTTP listener from `threads` epoll event loops
+    /// instead of a worker per connection. The loops accept connections and
+    /// read and write them without blocking; only a complete request is
+    /// handed to the pool, and its response is buffered in memory before
+    /// the loop writes it. TLS listeners are still served by the pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    #[cfg(target_os = "linux")]
+    pub fn with_event_loop(mut self, threads: usize) -> Server {
+        assert!(threads > 0);
+        self.event_loops = Some(threads);
+        self
+    }
+
+    /// Records requests into `metrics` instead of a registry of its own.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Server {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// The address of the first plain HTTP listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// The addresses of the plain HTTP listeners, in the order they were
+    /// added.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// The addresses of the TLS listeners, in the order they were added.
+    pub fn tls_local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.tls_listeners
+            .iter()
+            .map(|(listener, _)| listener.local_addr())
+            .collect()
+    }
+
+    /// Returns a handle that can stop `run` from another thread.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        let mut wake_addrs = self.local_addrs()?;
+        wake_addrs.extend(self.tls_local_addrs()?);
+        for addr in &mut wake_addrs {
+            if addr.ip().is_unspecified() {
+                let loopback = match addr {
+                    SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                    SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+                };
+                addr.set_ip(loopback);
+            }
+        }
+        Ok(ShutdownHandle {
+            state: Arc::clone(&self.state),
+            wake_addrs,
+        })
+    }
+
+    /// Returns a handle that changes the router and connection settings of
+    /// the running server from another thread.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            settings: Arc::clone(&self.settings),
+        }
+    }
+
+    /// 停止を指示されるまで接続を受け付けます。
+    ///
+    /// Connections that find the pool's queue full are answered with 503.
+    /// `GET /metrics` is answered by the server itself with its metrics in
+    /// the Prometheus text format. After a shutdown request, closes idle
+    /// keep-alive connections, lets queued jobs finish and waits up to the
+    /// shutdown timeout for in-flight ones.
+    pub fn run(self) -> Result<(), ShutdownTimeout> {
+        let Server {
+            mut listeners,
+            tls_listeners,
+            pool,
+            settings,
+            metrics,
+            shutdown_timeout,
+            #[cfg(target_os = "linux")]
+            event_loops,
+            state,
+        } = self;
+        let acceptor = Acceptor {
+            pool: &pool,
+            settings,
+            instruments: Arc::new(Instruments {
+                metrics,
+                pool: pool.stats_handle(),
+            }),
+            clients: Arc::default(),
+            state: Arc::clone(&state),
+        };
+
+        // 最初の待ち受け以外は、それぞれ別のスレッドで受け付けます
+        thread::scope(|scope| {
+            for (listener, tls) in tls_listeners {
+                let acceptor = &acceptor;
+                scope.spawn(move || acceptor.accept(listener, Some(tls)));
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(threads) = event_loops {
+                event_loop::run(listeners, threads, &acceptor, shutdown_timeout);
+                return;
+            }
+            let first = listeners.remove(0);
+            for listener in listeners {
+                let acceptor = &acceptor;
+                scope.spawn(move || acceptor.accept(listener, None));
+            }
+            acceptor.accept(first, None);
+        });
+        info!("Shutting down.");
+
+        // 読み込み待ちの接続はEOFを受け取り、応答中の接続は最後まで書き込みます
+        for stream in state.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        pool.shutdown_timeout(shutdown_timeout)
+    }
+}
+
+/// クライアントのIPアドレスごとに、開いている接続を数えます。
+///
+/// Counts the open connections of each client IP address.
+#[derive(Default)]
+struct Clients {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Clients {
+    /// Counts a new connection from `ip`, unless `limit` connections from
+    /// it are open already.
+    fn admit(self: &Arc<Self>, ip: IpAddr, limit: Option<usize>) -> Option<ClientSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if limit.is_some_and(|limit| count >= limit) {
+            return None;
+        }
+        open.insert(ip, count + 1);
+        Some(ClientSlot {
+            clients: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// Stops counting its connection when dropped.
+struct ClientSlot {
+    clients: Arc<Clients>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut open = self.clients.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// What each accept loop of `Server::run` shares.
+struct Acceptor<'a> {
+    pool: &'a ThreadPool,
+    settings: Arc<RwLock<Settings>>,
+    instruments: Arc<Instruments>,
+    clients: Arc<Clients>,
+    state: Arc<ShutdownState>,
+}
+
+impl Acceptor<'_> {
+    /// The settings for a newly accepted connection.
+    fn settings(&self) -> Settings {
+        read(&self.settings).clone()
+    }
+
+    /// How the HTTP/2 streams of a connection accepted with `settings` are
+    /// answered, or `None` if HTTP/2 is off.
+    fn http2_service(&self, settings: &Settings) -> Option<h2c::Service> {
+        settings.http2.then(|| h2c::Service {
+            router: Arc::clone(&settings.router),
+            keep_alive: settings.keep_alive,
+            compression: settings.compression,
+            limits: settings.limits,
+            instruments: Arc::clone(&self.instruments),
+            executor: self.pool.executor(),
+        })
+    }
+
+    /// Accepts connections on `listener` until shutdown is requested.
+    fn accept(&self, listener: TcpListener, tls: Option<TlsConfig>) {
+        for stream in listener.incoming() {
+            if self.state.requested.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let settings = self.settings();
+            // TLSの上のHTTP/2には対応していません
+            let http2 = match tls {
+                None => self.http2_service(&settings),
+                Some(_) => None,
+            };
+            let Settings {
+                router,
+                keep_alive,
+                compression,
+                limits,
+                ..
+            } = settings;
+            let Some(slot) = self.admit(&stream, &limits) else {
+                continue;
+            };
+            let instruments = Arc::clone(&self.instruments);
+            let state = Arc::clone(&self.state);
+            let tls = tls.clone();
+            // キューが一杯のときに503を返すため、先に複製しておきます。
+            // TLSの接続には平文で返せないので、そのまま閉じます
+            let overflow = match tls {
+                None => stream.try_clone().ok(),
+                Some(_) => None,
+            };
+
+            let job = move || {
+                let _slot = slot;
+                let id = state.register(&stream);
+                let options = Options {
+                    keep_alive: &keep_alive,
+                    compression,
+                    limits: &limits,
+                    instruments: Some(&instruments),
+                };
+                if let Err(e) = serve_stream(stream, tls.as_ref(), http2, &router, &options) {
+                    debug!("Connection error: {}", e);
+                }
+                state.unregister(id);
+            };
+
+            if self.pool.try_execute(job).is_err() {
+                warn!("Thread pool is busy; answering 503.");
+                if let Some(mut stream) = overflow {
+                    let _ = Response::new(503)
+                        .with_header("Retry-After", "1")
+                        .with_header("Connection", "close")
+                        .write_to(&mut stream);
+                }
+            }
+        }
+    }
+
+    /// Counts `stream` against its client's connection limit, or returns
+    /// `None` if the client has too many open; `stream` is then dropped.
+    fn admit(&self, stream: &TcpStream, limits: &ConnectionLimits) -> Option<ClientSlot> {
+        let ip = stream.peer_addr().ok()?.ip();
+        let slot = self.clients.admit(ip, limits.max_connections_per_ip);
+        if slot.is_none() {
+            warn!("Too many connections from {}; closing.", ip);
+        }
+        slot
+    }
+}
+
+/// 実行中のサーバーの設定を差し替えるためのハンドル。
+///
+/// Changes the settings of a running `Server`, e.g. after its configuration
+/// file was reloaded. Connections accepted afterwards use the new settings;
+/// open ones keep the settings they started with.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    settings: Arc<RwLock<Settings>>,
+}
+
+impl ReloadHandle {
+    pub fn set_router(&self, router: Router) {
+        write(&self.settings).router = Arc::new(router);
+    }
+
+    pub fn set_keep_alive(&self, keep_alive: KeepAlive) {
+        write(&self.settings).keep_alive = keep_alive;
+    }
+
+    pub fn set_compression(&self, compression: bool) {
+        write(&self.settings).compression = compression;
+    }
+
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        write(&self.settings).limits = limits;
+    }
+
+    pub fn set_http2(&self, http2: bool) {
+        write(&self.settings).http2 = http2;
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 別のスレッドから`Server::run`を止めるためのハンドル。
+///
+/// Stops a running `Server` from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+    wake_addrs: Vec<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if !self.state.requested.swap(true, Ordering::SeqCst) {
+            // acceptで待っているループを起こします
+            for addr in &self.wake_addrs {
+                let _ = TcpStream::connect_timeout(addr, Duration::from_secs(1));
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// 1つの接続でリクエストを順に処理します。
+///
+/// パイプライン化されたリクエストも受け取った順に応答します。
+///
+/// Serves requests on one connection until the client or the keep-alive
+/// settings close it.
+///
+/// Pipelined requests are answered one after another in the order they
+/// arrived, since the reader keeps any bytes read past the current request.
+pub fn serve_connection(
+    stream: TcpStream,
+    router: &Router,
+    keep_alive: &KeepAlive,
+) -> io::Result<()> {
+    let limits = ConnectionLimits::default();
+    let options = Options {
+        keep_alive,
+        compression: false,
+        limits: &limits,
+        instruments: None,
+    };
+    serve_stream(stream, None, None, router, &options)
+}
+
+/// How `serve` answers the requests on a connection.
+struct Options<'a> {
+    keep_alive: &'a KeepAlive,
+    compression: bool,
+    limits: &'a ConnectionLimits,
+    instruments: Option<&'a Instruments>,
+}
+
+/// What `Server` measures its connections with.
+struct Instruments {
+    metrics: Arc<Metrics>,
+    pool: StatsHandle,
+}
+
+/// Serves an accepted connection, first terminating TLS if `tls` is given.
+/// With `http2`, a plain connection that opens with the HTTP/2 preface is
+/// served as HTTP/2.
+fn serve_stream(
+    stream: TcpStream,
+    tls: Option<&TlsConfig>,
+    http2: Option<h2c::Service>,
+    router: &Router,
+    options: &Options,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(options.keep_alive.idle_timeout))?;
+    stream.set_write_timeout(Some(options.limits.write_timeout))?;
+    let peer = stream.peer_addr().ok();
+    if let Some(service) = http2 {
+        let wait = options.limits.request.header_timeout;
+        let deadline = Instant::now() + wait.unwrap_or(options.keep_alive.idle_timeout);
+        match h2c::has_preface(&stream, deadline) {
+            Ok(true) => return h2c::serve(stream, Vec::new(), peer, service),
+            Ok(false) => {}
+            // 何も送られないまま待ち時間が過ぎた接続は、黙って閉じます
+            Err(e) if is_timeout(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    let socket = stream.try_clone()?;
+    match tls {
+        None => serve(stream, socket, peer, router, options),
+        Some(tls) => {
+            let mut stream = tls.accept(stream)?;
+            serve(&mut stream, socket, peer, router, options)?;
+            // 接続を閉じる前に、close_notifyで終わりを知らせます
+            stream.conn.send_close_notify();
+            stream.flush()
+        }
+    }
+}
+
+/// Serves requests read from `stream`; `socket` is the connection under
+/// it, whose read timeout follows the request deadlines.
+fn serve<S: Read + Write>(
+    stream: S,
+    socket: TcpStream,
+    peer: Option<SocketAddr>,
+    router: &Router,
+    options: &Options,
+) -> io::Result<()> {
+    // 期限のないときは、次のリクエストを待つ時間に戻します
+    let idle_timeout = options.keep_alive.idle_timeout;
+    let upgrade_socket = socket.try_clone()?;
+    let mut reader = RequestReader::with_limits(stream, options.limits.request).with_read_timeout(
+        move |remaining| socket.set_read_timeout(Some(remaining.unwrap_or(idle_timeout))),
+    );
+    let mut served = 0;
+
+    loop {
+        let mut request = match reader.read_request() {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(e)) if is_timeout(&e) && reader.buffered().is_empty() => {
+                return Ok(());
+            }
+            Err(ParseError::Io(e)) => return Err(e),
+            Err(e) => {
+                let (code, _) = e.status().unwrap();
+                let mut response = Response::new(code).with_header("Connection", "close");
+                let written = response.write_to(reader.get_mut())?;
+                log_access(peer, None, response.status, written);
+                return Ok(());
+            }
+        };
+        request.peer = peer;
+        served += 1;
+        let started = Instant::now();
+        let (mut response, persist) = respond(&mut request, router, options, served);
+        let written = response.write_for(reader.get_mut(), request.version)?;
+        record(peer, &request, &response, written, started, options);
+
+        // 切り替えた後の接続も、しばらく何も届かなければ閉じます
+        if let Some(on_upgrade) = response.take_upgrade() {
+            upgrade_socket.set_read_timeout(Some(idle_timeout))?;
+            let buffered = reader.buffered().to_vec();
+            on_upgrade.run(Upgraded::new(reader.get_mut(), buffered));
+            return Ok(());
+        }
+        if !persist {
+            return Ok(());
+        }
+    }
+}
+
+/// リクエストに応答を作り、接続を続けるかどうかを決めます。
+///
+/// Answers one parsed request: runs it through the router and its
+/// middleware (or serves the metrics), applies compression and decides
+/// whether the connection persists afterwards, setting the `Connection` and
+/// `Keep-Alive` headers to match. `served` counts this request.
+fn respond(
+    request: &mut Request,
+    router: &Router,
+    options: &Options,
+    served: usize,
+) -> (Response, bool) {
+    let keep_alive = options.keep_alive;
+    let mut response = match options.instruments {
+        Some(instruments) if request.method == Method::Get && request.path() == METRICS_PATH => {
+            Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(instruments.metrics.render(&instruments.pool.stats()))
+        }
+        _ => router.dispatch(request),
+    };
+    // プロトコルを切り替えるときは、接続のヘッダーをハンドラーに任せます
+    if response.status == 101 {
+        return (response, false);
+    }
+    if options.compression {
+        let accept_encoding = request.headers.get("accept-encoding").unwrap_or("");
+        compression::compress(&mut response, accept_encoding);
+    }
+    // HEADへの応答は、どこで作られたものでも本文を送りません
+    if request.method == Method::Head {
+        response.strip_body();
+    }
+    // HTTP/1.0では長さの分からない本文の終わりを、接続を閉じて知らせます
+    let persist = wants_keep_alive(request)
+        && !response.headers.contains_token("connection", "close")
+        && !(request.version == Version::Http10 && response.body.is_chunked())
+        && served < keep_alive.max_requests;
+
+    if !persist {
+        response.headers.insert("Connection", "close");
+    } else if request.version == Version::Http10 {
+        response.headers.insert("Connection", "keep-alive");
+        response.headers.insert(
+            "Keep-Alive",
+            &format!(
+                "timeout={}, max={}",
+                keep_alive.idle_timeout.as_secs(),
+                keep_alive.max_requests - served
+            ),
+        );
+    }
+    (response, persist)
+}
+
+/// Logs and measures a response once `written` body bytes have been sent.
+fn record(
+    peer: Option<SocketAddr>,
+    request: &Request,
+    response: &Response,
+    written: u64,
+    started: Instant,
+    options: &Options,
+) {
+    log_access(peer, Some(request), response.status, written);
+    if let Some(instruments) = options.instruments {
+        // 知らないメソッドはクライアントが好きに作れるので、1つにまとめて数えます
+        let method = match &request.method {
+            Method::Other(_) => "OTHER",
+            method => method.as_str(),
+        };
+        instruments
+            .metrics
+            .record_request(method, response.status, written, started.elapsed());
+    }
+}
+
+/// アクセスログに1行書きます。
+///
+/// Logs one Common Log Format line for an answered request. `request` is
+/// `None` when the request could not be parsed.
+fn log_access(peer: Option<SocketAddr>, request: Option<&Request>, status: u16, bytes: u64) {
+    if !log::enabled(Level::Info) {
+        return;
+    }
+    let host = peer.map_or_else(|| "-".to_string(), |p| p.ip().to_string());
+    let line = match request {
+        Some(r) => format!("{} {} {}", r.method, r.target, r.version),
+        None => "-".to_string(),
+    };
+    let bytes = match bytes {
+        0 => "-".to_string(),
+        n => n.to_string(),
+    };
+    log::log(
+        Level::Info,
+        ACCESS_TARGET,
+        format_args!(
+            "{} - - [{}] \"{}\" {} {}",
+            host,
+            DateTime::from_system_time(SystemTime::now()).clf(),
+            line,
+            status,
+            bytes
+        ),
+    );
+}
+
+/// HTTP/1.1 connections persist unless the client asks to close them;
+/// HTTP/1.0 connections only persist if the client asks for keep-alive.
+/// HTTP/2 streams never close their connection.
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.contains_token("connection", "close"),
+        Version::Http10 => request.headers.contains_token("connection", "keep-alive"),
+        Version::Http2 => true,
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Body;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn spawn_server(keep_alive: KeepAlive) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let router = Router::new().get("/:name", |_, p| {
+                Response::new(200).with_body(p.get("name").unwrap().to_string())
+            });
+            serve_connection(stream, &router, &keep_alive).unwrap();
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    fn read_all(mut stream: TcpStream) -> String {
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let mut client = spawn_server(KeepAlive::default());
+        client
+            .write_all(
+                b"GET /a HTTP/1.1\r\nHost: t\r\n\r\n\
+                  GET /b HTTP/1.1\r\nHost: t\r\n\r\n\
+                  GET /c HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let out = read_all(client);
+        let bodies: Vec<&str> = out
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .map(|r| r.rsplit("\r\n\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["a", "b", "c"]);
+        assert_eq!(out.matches("Connection: close").count(), 1);
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let mut client = spawn_server(KeepAlive {
+            max_requests: 2,
+            ..KeepAlive::default()
+        });
+        for _ in 0..3 {
+            client
+                .write_all(b"GET /x HTTP/1.1\r\nHost: t\r\n\r\n")
+                .unwrap();
+        }
+        let out = read_all(client);
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn http10_closes_unless_asked_to_keep_alive() {
+        let mut client = spawn_server(KeepAlive::default());
+        client
+            .write_all(b"GET /x HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /y HTTP/1.0\r\n\r\n")
+            .unwrap();
+        let out = read_all(client);
+        assert!(out.contains("Keep-Alive: timeout=5, max=99"));
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn shutdown_handle_stops_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let router = Router::new().get("/", |_, _| Response::new(200).with_body("hi"));
+        let server = Server::new(listener, ThreadPool::new(2), router);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        // An idle keep-alive connection must not hold up the shutdown.
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: t\r\n\r\n")
+            .unwrap();
+        let mut buf = [0; 64];
+        assert!(idle.read(&mut buf).unwrap() > 0);
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(handle.is_shutdown());
+        assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn overloaded_server_answers_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let router = Router::new().get("/", |_, _| Response::new(200));
+        let pool = ThreadPool::bounded(1, 1, crate::QueuePolicy::Reject);
+        let server = Server::new(listener, pool, router);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        // One connection occupies the worker, one waits in the queue.
+        let _busy = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let _queued = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut out = String::new();
+        rejected.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let client = spawn_server(KeepAlive {
+            idle_timeout: Duration::from_millis(100),
+            ..KeepAlive::default()
+        });
+        assert_eq!(read_all(client), "");
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let router = Router::new().get("/", |_, _| Response::new(200).with_body("hi"));
+        let server = Server::new(listener, ThreadPool::new(2), router);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let metrics = server.metrics();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: t\r\n\r\n\
+                  GET /metrics HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let out = read_all(client);
+        assert!(out.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(out.contains("http_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        assert!(out.contains("threadpool_workers 2\n"));
+        assert!(out.contains("threadpool_active_workers 1\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert_eq!(metrics.requests_total(), 2);
+    }
+
+    #[test]
+    fn compresses_when_enabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let page = "<p>hello</p>".repeat(100);
+        let body = page.clone();
+        let router = Router::new().get("/", move |_, _| {
+            Response::new(200)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(body.clone())
+        });
+        let server = Server::new(listener, ThreadPool::new(1), router).with_compression(true);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: t\r\nAccept-Encoding: gzip\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut out = Vec::new();
+        client.read_to_end(&mut out).unwrap();
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&out[..split]).into_owned();
+        assert!(head.contains("Content-Encoding: gzip"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&out[split + 4..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page);
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serves_plain_and_tls_listeners_at_once() {
+        use rustls::pki_types::PrivatePkcs8KeyDer;
+
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = TlsConfig::new(
+            vec![generated.cert.der().clone()],
+            PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der()).into(),
+        )
+        .unwrap();
+
+        let router = Router::new().get("/", |_, _| Response::new(200).with_body("hi"));
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            ThreadPool::new(2),
+            router,
+        )
+        .with_tls_listener(TcpListener::bind("127.0.0.1:0").unwrap(), tls);
+        let addr = server.local_addr().unwrap();
+        let tls_addr = server.tls_local_addrs().unwrap()[0];
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection =
+            rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap())
+                .unwrap();
+        let mut client =
+            rustls::StreamOwned::new(connection, TcpStream::connect(tls_addr).unwrap());
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\nhi"));
+
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_all(plain).ends_with("\r\n\r\nhi"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    fn spawn_limited(
+        pool: ThreadPool,
+        limits: ConnectionLimits,
+    ) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+        let router = Router::new()
+            .get("/", |_, _| Response::new(200).with_body("hi"))
+            .post("/", |_, _| Response::new(200).with_body("posted"))
+            .get("/big", |_, _| {
+                let len = 64 * 1024 * 1024;
+                Response::new(200).with_body(Body::from_reader(io::repeat(0).take(len), len))
+            });
+        let server = Server::new(TcpListener::bind("127.0.0.1:0").unwrap(), pool, router)
+            .with_limits(limits);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run().unwrap());
+        (addr, handle, running)
+    }
+
+    /// Reads until the server closes the connection, tolerating the reset
+    /// that follows when it closes with the client's bytes still unread.
+    fn read_until_closed(stream: &mut TcpStream) -> String {
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = stream.read(&mut buf) {
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    /// Sends `head` at once, then the rest of a header line one byte every
+    /// 50ms, the way a slowloris client keeps a connection open.
+    fn trickle(addr: SocketAddr, head: &'static [u8]) -> TcpStream {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(head).unwrap();
+        let mut writer = client.try_clone().unwrap();
+        thread::spawn(move || {
+            for byte in b"X-Slow: ".iter().cycle().take(60) {
+                thread::sleep(Duration::from_millis(50));
+                if writer.write_all(&[*byte]).is_err() {
+                    break;
+                }
+            }
+        });
+        client
+    }
+
+    #[test]
+    fn slow_headers_are_answered_with_408() {
+        let mut limits = ConnectionLimits::default();
+        limits.request.header_timeout = Some(Duration::from_millis(300));
+        let (addr, handle, running) = spawn_limited(ThreadPool::new(2), limits);
+
+        let started = Instant::now();
+        let mut client = trickle(addr, b"GET / HTTP/1.1\r\nHost: t\r\n");
+        let out = read_until_closed(&mut client);
+        assert!(
+            out.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{:?}",
+            out
+        );
+        // The trickle would go on for three seconds, and every byte arrives
+        // well within the idle timeout.
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn slow_bodies_are_answered_with_408() {
+        let mut limits = ConnectionLimits::default();
+        limits.request.body_timeout = Some(Duration::from_millis(300));
+        let (addr, handle, running) = spawn_limited(ThreadPool::new(2), limits);
+
+        let started = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: t\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+        let out = read_until_closed(&mut client);
+        assert!(
+            out.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{:?}",
+            out
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn stalled_writes_free_the_worker() {
+        let limits = ConnectionLimits {
+            write_timeout: Duration::from_millis(300),
+            ..ConnectionLimits::default()
+        };
+        let (addr, handle, running) = spawn_limited(ThreadPool::new(1), limits);
+
+        // This client never reads the 64 MiB it asked for, so the only
+        // worker blocks once the socket buffers are full.
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled
+            .write_all(b"GET /big HTTP/1.1\r\nHost: t\r\n\r\n")
+            .unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(read_all(client).ends_with("\r\n\r\nhi"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let limits = ConnectionLimits {
+            max_connections_per_ip: Some(2),
+            ..ConnectionLimits::default()
+        };
+        let (addr, handle, running) = spawn_limited(ThreadPool::new(4), limits);
+
+        let first = TcpStream::connect(addr).unwrap();
+        let _second = TcpStream::connect(addr).unwrap();
+        let mut third = TcpStream::connect(addr).unwrap();
+        let _ = third.write_all(b"GET / HTTP/1.1\r\nHost: t\r\n\r\n");
+        assert_eq!(read_until_closed(&mut third), "");
+
+        // Closing a connection makes room for another one.
+        drop(first);
+        thread::sleep(Duration::from_millis(100));
+        let mut fourth = TcpStream::connect(addr).unwrap();
+        fourth
+            .write_all(b"GET / HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_all(fourth).ends_with("\r\n\r\nhi"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+}
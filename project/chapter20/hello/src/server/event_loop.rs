@@ -0,0 +1,1307 @@
+//! epollによるイベントループ。
+//!
+//! Serves plain HTTP connections from a few threads that wait for readiness
+//! with epoll, instead of tying up a worker for each connection's lifetime.
+//!
+//! Every loop has its own epoll instance. The listeners are registered in
+//! all of them with `EPOLLEXCLUSIVE`, so a new connection wakes one loop,
+//! which then owns it. A loop reads until it holds a complete request and queues
+//! that request on the `ThreadPool`; the worker writes the response into
+//! memory and hands it back through the loop's completion queue, and the
+//! loop sends it as fast as the socket accepts it. A body that is read or
+//! produced piece by piece is not gathered in memory: the worker writes it
+//! to the socket itself, waiting whenever the socket is full.
+//!
+//! A connection that switches protocols leaves the loop: its session runs
+//! on a worker with the socket back in blocking mode, and ends once the
+//! client has sent nothing for the keep-alive idle timeout. So does a connection
+//! that opens with the HTTP/2 preface when HTTP/2 is on.
+//!
+//! The `ConnectionLimits` are enforced by the loop's periodic sweep: a
+//! request that misses its header or body deadline is answered with 408,
+//! and a response that makes no progress for the write timeout is dropped.
+
+use super::{
+    Acceptor, ClientSlot, ConnectionLimits, Options, Settings, h2c, log_access, record, respond,
+};
+use crate::http2::PREFACE;
+use crate::request::{self, Framing, Limits, ParseError, Request, RequestReader};
+use crate::response::{Body, OnUpgrade, Response, Upgraded};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 待ち受けには1から順にトークンを振ります
+const WAKER: u64 = 0;
+
+const READABLE: u32 = (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+const WRITABLE: u32 = libc::EPOLLOUT as u32;
+
+// 期限切れの接続と停止の指示を確かめる間隔
+const TICK: Duration = Duration::from_millis(100);
+
+const READ_CHUNK: usize = 16 * 1024;
+const MAX_EVENTS: usize = 256;
+
+/// 停止を指示されるまで、`threads`個のイベントループで接続を処理します。
+///
+/// Serves `listeners` from `threads` event loops until shutdown is
+/// requested, then lets the responses in progress finish for up to
+/// `shutdown_timeout` before closing what is left.
+pub(super) fn run(
+    listeners: Vec<TcpListener>,
+    threads: usize,
+    acceptor: &Acceptor,
+    shutdown_timeout: Duration,
+) {
+    for listener in &listeners {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Cannot make the listener non-blocking: {}", e);
+            return;
+        }
+    }
+
+    thread::scope(|scope| {
+        for id in 0..threads {
+            let listeners = &listeners;
+            let spawned = thread::Builder::new()
+                .name(format!("event-loop-{}", id))
+                .spawn_scoped(scope, move || match EventLoop::new(listeners, acceptor) {
+                    Ok(mut event_loop) => event_loop.run(shutdown_timeout),
+                    Err(e) => error!("Event loop {} failed to start: {}", id, e),
+                });
+            if let Err(e) = spawned {
+                error!("Failed to start event loop {}: {}", id, e);
+            }
+        }
+    });
+}
+
+/// 届きかけのリクエストをどこまで調べたか。
+///
+/// How far the bytes of the pending request have been looked at, so that
+/// each read only scans what arrived since instead of parsing again from
+/// the start.
+#[derive(Clone, Copy)]
+enum Progress {
+    /// Looking for the end of the head from this offset.
+    Head(usize),
+    /// The request is complete once the input holds this many bytes.
+    Length(usize),
+    /// Walking a chunked body; `body` bytes of data have been seen so far.
+    Chunks { at: usize, body: usize, part: Part },
+}
+
+/// チャンク形式の本文で、次に来るもの。
+#[derive(Clone, Copy)]
+enum Part {
+    /// A chunk-size line.
+    Size,
+    /// The line ending after a chunk's data.
+    DataEnd,
+    /// A trailer line, after this many bytes of trailers.
+    Trailers(usize),
+}
+
+/// 接続の状態。
+enum State {
+    /// Waiting for the rest of a request.
+    Reading,
+    /// A worker is answering a request.
+    Handling,
+    /// Sending a response.
+    Writing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    state: State,
+    /// Bytes received but not yet part of a dispatched request.
+    input: Vec<u8>,
+    progress: Progress,
+    output: Vec<u8>,
+    written: usize,
+    /// Close once the output has been written.
+    closing: bool,
+    /// The client has closed its side.
+    eof: bool,
+    served: usize,
+    last_active: Instant,
+    /// When the first byte of the pending request arrived.
+    request_started: Option<Instant>,
+    /// When the headers of the pending request were complete.
+    head_received: Option<Instant>,
+    limits: ConnectionLimits,
+    /// How HTTP/2 streams are answered, if the connection may switch to it.
+    http2: Option<h2c::Service>,
+    _slot: ClientSlot,
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        peer: SocketAddr,
+        limits: ConnectionLimits,
+        http2: Option<h2c::Service>,
+        slot: ClientSlot,
+    ) -> Connection {
+        Connection {
+            stream,
+            peer: Some(peer),
+            state: State::Reading,
+            input: Vec::new(),
+            progress: Progress::Head(0),
+            output: Vec::new(),
+            written: 0,
+            closing: false,
+            eof: false,
+            served: 0,
+            last_active: Instant::now(),
+            request_started: None,
+            head_received: None,
+            limits,
+            http2,
+            _slot: slot,
+        }
+    }
+
+    /// Whether the pending request has missed its header or body deadline.
+    fn overdue(&self) -> bool {
+        let limits = &self.limits.request;
+        match (self.request_started, self.head_received) {
+            (_, Some(at)) => limits.body_timeout.is_some_and(|t| at.elapsed() >= t),
+            (Some(at), None) => limits.header_timeout.is_some_and(|t| at.elapsed() >= t),
+            (None, None) => false,
+        }
+    }
+
+    /// Scans the input that arrived since the last call, and tells whether
+    /// it now holds a whole request, or a malformed one, worth parsing.
+    fn advance(&mut self) -> bool {
+        let limits = self.limits.request;
+        loop {
+            match self.progress {
+                Progress::Head(from) => {
+                    // RFC 9112 2.2: ignore empty lines received before a request line.
+                    while self.input.starts_with(b"\r\n") || self.input.starts_with(b"\n") {
+                        let n = if self.input[0] == b'\r' { 2 } else { 1 };
+                        self.input.drain(..n);
+                    }
+                    let Some(end) = request::find_head_end(&self.input, from) else {
+                        self.progress = Progress::Head(self.input.len().saturating_sub(3));
+                        return self.input.len() > limits.max_header_bytes;
+                    };
+                    self.head_received.get_or_insert_with(Instant::now);
+                    if end > limits.max_header_bytes {
+                        return true;
+                    }
+                    let framing = request::parse_head(&self.input[..end])
+                        .and_then(|(_, _, _, headers)| request::framing(&headers));
+                    self.progress = match framing {
+                        Ok(Framing::Length(n)) if n <= limits.max_body_bytes => {
+                            Progress::Length(end + n)
+                        }
+                        Ok(Framing::Chunked) => Progress::Chunks {
+                            at: end,
+                            body: 0,
+                            part: Part::Size,
+                        },
+                        // 誤りは解析して答えます
+                        _ => return true,
+                    };
+                }
+                Progress::Length(n) => return self.input.len() >= n,
+                Progress::Chunks { at, body, part } => {
+                    let Some(rest) = self.input.get(at..) else {
+                        return false;
+                    };
+                    if let Part::DataEnd = part {
+                        let n = match rest {
+                            [b'\n', ..] => 1,
+                            [b'\r', b'\n', ..] => 2,
+                            [] | [b'\r'] => return false,
+                            _ => return true,
+                        };
+                        self.progress = Progress::Chunks {
+                            at: at + n,
+                            body,
+                            part: Part::Size,
+                        };
+                        continue;
+                    }
+                    let Some(i) = rest.iter().position(|&b| b == b'\n') else {
+                        return rest.len() > limits.max_header_bytes + 1;
+                    };
+                    let line = &rest[..i];
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    let at = at + i + 1;
+                    self.progress = match part {
+                        Part::Trailers(_) if line.is_empty() => return true,
+                        Part::Trailers(seen) if seen + line.len() + 2 > limits.max_header_bytes => {
+                            return true;
+                        }
+                        Part::Trailers(seen) => Progress::Chunks {
+                            at,
+                            body,
+                            part: Part::Trailers(seen + line.len() + 2),
+                        },
+                        _ => match chunk_size(line) {
+                            Some(0) => Progress::Chunks {
+                                at,
+                                body,
+                                part: Part::Trailers(0),
+                            },
+                            Some(size) if size <= limits.max_body_bytes - body => {
+                                Progress::Chunks {
+                                    at: at + size,
+                                    body: body + size,
+                                    part: Part::DataEnd,
+                                }
+                            }
+                            _ => return true,
+                        },
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Reads a chunk-size line, ignoring chunk extensions.
+fn chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+struct EventLoop<'a> {
+    poller: Poller,
+    listeners: &'a [TcpListener],
+    acceptor: &'a Acceptor<'a>,
+    completions: Arc<Completions>,
+    connections: HashMap<u64, Connection>,
+    // トークンは使い回さないので、閉じた接続への応答を取り違えません
+    next_token: u64,
+    stopping: bool,
+    last_sweep: Instant,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(listeners: &'a [TcpListener], acceptor: &'a Acceptor<'a>) -> io::Result<EventLoop<'a>> {
+        let poller = Poller::new()?;
+        let completions = Arc::new(Completions {
+            ready: Mutex::new(Vec::new()),
+            waker: Waker::new()?,
+        });
+        for (i, listener) in listeners.iter().enumerate() {
+            poller.add(
+                listener.as_raw_fd(),
+                i as u64 + 1,
+                (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32,
+            )?;
+        }
+        poller.add(completions.waker.0.as_raw_fd(), WAKER, libc::EPOLLIN as u32)?;
+
+        Ok(EventLoop {
+            poller,
+            listeners,
+            acceptor,
+            completions,
+            connections: HashMap::new(),
+            next_token: listeners.len() as u64 + 1,
+            stopping: false,
+            last_sweep: Instant::now(),
+        })
+    }
+
+    fn run(&mut self, shutdown_timeout: Duration) {
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+        let mut deadline = None;
+
+        loop {
+            if !self.stopping && self.acceptor.state.requested.load(Ordering::SeqCst) {
+                deadline = Some(Instant::now() + shutdown_timeout);
+                self.stop();
+            }
+            if let Some(deadline) = deadline
+                && (self.connections.is_empty() || Instant::now() >= deadline)
+            {
+                break;
+            }
+
+            if let Err(e) = self.poller.wait(&mut events, TICK) {
+                error!("Event loop failed: {}", e);
+                break;
+            }
+            for event in &events {
+                let (token, flags) = (event.u64, event.events);
+                match token {
+                    WAKER => self.complete(),
+                    token if token <= self.listeners.len() as u64 => {
+                        self.accept(&self.listeners[token as usize - 1])
+                    }
+                    token => self.ready(token, flags),
+                }
+            }
+
+            if self.last_sweep.elapsed() >= TICK {
+                self.sweep();
+            }
+        }
+    }
+
+    /// Stops accepting and closes the connections that are not being
+    /// answered, like the blocking server does on shutdown.
+    fn stop(&mut self) {
+        self.stopping = true;
+        for listener in self.listeners {
+            let _ = self.poller.delete(listener.as_raw_fd());
+        }
+        let reading: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| matches!(c.state, State::Reading))
+            .map(|(&token, _)| token)
+            .collect();
+        for token in reading {
+            self.close(token);
+        }
+    }
+
+    fn accept(&mut self, listener: &TcpListener) {
+        if self.stopping {
+            return;
+        }
+        loop {
+            let (stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                // 他のループが先に受け付けました
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    return;
+                }
+            };
+            let settings = self.acceptor.settings();
+            let limits = settings.limits;
+            let Some(slot) = self.acceptor.admit(&stream, &limits) else {
+                continue;
+            };
+            let token = self.next_token;
+            self.next_token += 1;
+            let registered = stream
+                .set_nonblocking(true)
+                .and_then(|()| self.poller.add(stream.as_raw_fd(), token, READABLE));
+            match registered {
+                Ok(()) => {
+                    let http2 = self.acceptor.http2_service(&settings);
+                    let connection = Connection::new(stream, peer, limits, http2, slot);
+                    self.connections.insert(token, connection);
+                }
+                Err(e) => warn!("Failed to register a connection: {}", e),
+            }
+        }
+    }
+
+    fn ready(&mut self, token: u64, flags: u32) {
+        let Some(connection) = self.connections.get(&token) else {
+            return;
+        };
+        if flags & libc::EPOLLERR as u32 != 0 {
+            self.close(token);
+            return;
+        }
+        match connection.state {
+            State::Reading => self.read(token),
+            State::Writing => self.write(token),
+            // 応答を待っている間に切断されたら、応答は捨てます
+            State::Handling if flags & libc::EPOLLHUP as u32 != 0 => self.close(token),
+            State::Handling => {}
+        }
+    }
+
+    fn read(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let start = connection.input.len();
+        connection.input.resize(start + READ_CHUNK, 0);
+        let result = connection.stream.read(&mut connection.input[start..]);
+        connection
+            .input
+            .truncate(start + *result.as_ref().unwrap_or(&0));
+
+        match result {
+            Ok(0) => connection.eof = true,
+            Ok(_) => {
+                connection.last_active = Instant::now();
+                connection
+                    .request_started
+                    .get_or_insert(connection.last_active);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) =>
+            {
+                return;
+            }
+            Err(e) => {
+                debug!("Connection error: {}", e);
+                self.close(token);
+                return;
+            }
+        }
+        self.process(token);
+    }
+
+    /// Hands the next buffered request to the pool, answers a malformed one,
+    /// or closes the connection if the client has gone.
+    fn process(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        // HTTP/2の前置きは、全部届くまで待ちます
+        if connection.served == 0
+            && connection.http2.is_some()
+            && h2c::starts_preface(&connection.input)
+        {
+            if connection.input.len() >= PREFACE.len() {
+                self.serve_http2(token);
+            } else if connection.eof {
+                self.close(token);
+            }
+            return;
+        }
+        // 届いた分だけを調べ、リクエストが揃ってから一度だけ解析します
+        if !connection.advance() {
+            if connection.eof {
+                self.close(token);
+            }
+            return;
+        }
+        match parse(&connection.input, connection.limits.request) {
+            Ok(Some((mut request, len))) => {
+                connection.input.drain(..len);
+                connection.progress = Progress::Head(0);
+                request.peer = connection.peer;
+                self.dispatch(token, request);
+            }
+            Ok(None) if connection.eof => self.close(token),
+            Ok(None) => {}
+            Err(e) => self.reject(token, e),
+        }
+    }
+
+    /// Answers a request that cannot be served with the error's status and
+    /// closes the connection.
+    fn reject(&mut self, token: u64, e: ParseError) {
+        let Some(connection) = self.connections.get(&token) else {
+            return;
+        };
+        let code = e.status().map_or(400, |(code, _)| code);
+        let mut response = Response::new(code).with_header("Connection", "close");
+        let mut output = Vec::new();
+        let written = response.write_to(&mut output).unwrap_or(0);
+        log_access(connection.peer, None, response.status, written);
+        self.start_writing(token, output, false);
+    }
+
+    fn dispatch(&mut self, token: u64, mut request: Request) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        // 本文を少しずつ書く応答のために、ワーカーにもソケットを渡します
+        let socket = match connection.stream.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("Connection error: {}", e);
+                self.close(token);
+                return;
+            }
+        };
+        connection.served += 1;
+        connection.state = State::Handling;
+        connection.request_started = None;
+        connection.head_received = None;
+        // 応答ができるまで、この接続からは読みません
+        if self
+            .poller
+            .modify(connection.stream.as_raw_fd(), token, 0)
+            .is_err()
+        {
+            self.close(token);
+            return;
+        }
+
+        let acceptor = self.acceptor;
+        let Settings {
+            router,
+            keep_alive,
+            compression,
+            ..
+        } = acceptor.settings();
+        let limits = connection.limits;
+        let instruments = Arc::clone(&acceptor.instruments);
+        let (peer, served) = (connection.peer, connection.served);
+        let reply = Reply {
+            token,
+            completions: Arc::clone(&self.completions),
+            sent: false,
+        };
+
+        let job = move || {
+            let options = Options {
+                keep_alive: &keep_alive,
+                compression,
+                limits: &limits,
+                instruments: Some(&instruments),
+            };
+            let started = Instant::now();
+            let (mut response, persist) = respond(&mut request, &router, &options, served);
+            let on_upgrade = response.take_upgrade();
+            if on_upgrade.is_none() && !matches!(response.body, Body::Bytes(_)) {
+                // ループは応答を待っていてソケットに触れないので、ここから直接書きます
+                let mut socket = Blocking {
+                    socket: &socket,
+                    timeout: limits.write_timeout,
+                };
+                match response.write_for(&mut socket, request.version) {
+                    Ok(written) => {
+                        record(peer, &request, &response, written, started, &options);
+                        reply.send(Vec::new(), persist);
+                    }
+                    Err(e) => {
+                        debug!("Connection error: {}", e);
+                        reply.send(Vec::new(), false);
+                    }
+                }
+                return;
+            }
+            let mut output = Vec::new();
+            match response.write_for(&mut output, request.version) {
+                Ok(written) => {
+                    record(peer, &request, &response, written, started, &options);
+                    match on_upgrade {
+                        Some(on_upgrade) => reply.upgrade(output, on_upgrade),
+                        None => reply.send(output, persist),
+                    }
+                }
+                // 本文を作り終えられなかったときは、できたところまで送って閉じます
+                Err(e) => {
+                    debug!("Connection error: {}", e);
+                    reply.send(output, false);
+                }
+            }
+        };
+
+        // 返されたジョブを捨てると閉じる指示が届きますが、そのときには
+        // もう応答を待っていないので無視されます
+        if acceptor.pool.try_execute(job).is_err() {
+            warn!("Thread pool is busy; answering 503.");
+            let mut output = Vec::new();
+            let _ = Response::new(503)
+                .with_header("Retry-After", "1")
+                .with_header("Connection", "close")
+                .write_to(&mut output);
+            self.start_writing(token, output, false);
+        }
+    }
+
+    /// Starts sending the responses that workers have finished.
+    fn complete(&mut self) {
+        for Completion {
+            token,
+            output,
+            persist,
+            upgrade,
+        } in self.completions.take()
+        {
+            if let Some(connection) = self.connections.get(&token)
+                && matches!(connection.state, State::Handling)
+            {
+                match upgrade {
+                    Some(on_upgrade) => self.hand_off(token, output, on_upgrade),
+                    None => self.start_writing(token, output, persist),
+                }
+            }
+        }
+    }
+
+    /// Takes a connection that switched protocols out of the loop, and
+    /// writes the 101 response and runs the new protocol on a worker.
+    fn hand_off(&mut self, token: u64, output: Vec<u8>, on_upgrade: OnUpgrade) {
+        let Some(connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.poller.delete(connection.stream.as_raw_fd());
+        let Connection {
+            mut stream,
+            input,
+            limits,
+            _slot: slot,
+            ..
+        } = connection;
+        let state = Arc::clone(&self.acceptor.state);
+        let idle_timeout = self.acceptor.settings().keep_alive.idle_timeout;
+
+        let job = move || {
+            let _slot = slot;
+            let ready = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.set_read_timeout(Some(idle_timeout)))
+                .and_then(|()| stream.set_write_timeout(Some(limits.write_timeout)))
+                .and_then(|()| stream.write_all(&output));
+            if let Err(e) = ready {
+                debug!("Connection error: {}", e);
+                return;
+            }
+            // 停止するときに読み込みを止められるよう、登録しておきます
+            let id = state.register(&stream);
+            on_upgrade.run(Upgraded::new(&mut stream, input));
+            state.unregister(id);
+        };
+        if self.acceptor.pool.try_execute(job).is_err() {
+            warn!("Thread pool is busy; closing an upgraded connection.");
+        }
+    }
+
+    /// Takes a connection that opened with the HTTP/2 preface out of the
+    /// loop, and serves it as HTTP/2 on a worker.
+    fn serve_http2(&mut self, token: u64) {
+        let Some(connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.poller.delete(connection.stream.as_raw_fd());
+        let Connection {
+            stream,
+            peer,
+            input,
+            limits,
+            http2: Some(service),
+            _slot: slot,
+            ..
+        } = connection
+        else {
+            return;
+        };
+        let state = Arc::clone(&self.acceptor.state);
+
+        let job = move || {
+            let _slot = slot;
+            let idle_timeout = service.keep_alive.idle_timeout;
+            let ready = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.set_read_timeout(Some(idle_timeout)))
+                .and_then(|()| stream.set_write_timeout(Some(limits.write_timeout)));
+            if let Err(e) = ready {
+                debug!("Connection error: {}", e);
+                return;
+            }
+            let id = state.register(&stream);
+            if let Err(e) = h2c::serve(stream, input, peer, service) {
+                debug!("Connection error: {}", e);
+            }
+            state.unregister(id);
+        };
+        if self.acceptor.pool.try_execute(job).is_err() {
+            warn!("Thread pool is busy; closing an HTTP/2 connection.");
+        }
+    }
+
+    fn start_writing(&mut self, token: u64, output: Vec<u8>, persist: bool) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        connection.output = output;
+        connection.written = 0;
+        connection.closing = !persist || self.stopping;
+        connection.state = State::Writing;
+        self.write(token);
+    }
+
+    fn write(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        while connection.written < connection.output.len() {
+            match connection
+                .stream
+                .write(&connection.output[connection.written..])
+            {
+                Ok(0) => {
+                    self.close(token);
+                    return;
+                }
+                Ok(n) => {
+                    connection.written += n;
+                    connection.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // 書けるようになったら続きを送ります
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let fd = connection.stream.as_raw_fd();
+                    if self.poller.modify(fd, token, WRITABLE).is_err() {
+                        self.close(token);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    debug!("Connection error: {}", e);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        if connection.closing {
+            self.close(token);
+            return;
+        }
+        connection.output = Vec::new();
+        connection.state = State::Reading;
+        connection.last_active = Instant::now();
+        // パイプライン化された次のリクエストは、ここから期限を数えます
+        connection.request_started =
+            (!connection.input.is_empty()).then_some(connection.last_active);
+        let fd = connection.stream.as_raw_fd();
+        if self.poller.modify(fd, token, READABLE).is_err() {
+            self.close(token);
+            return;
+        }
+        // パイプライン化された次のリクエストが届いているかもしれません
+        self.process(token);
+    }
+
+    /// Answers requests that missed their deadline with 408, and closes
+    /// connections that stayed idle longer than the keep-alive timeout or
+    /// whose responses stalled longer than the write timeout.
+    fn sweep(&mut self) {
+        self.last_sweep = Instant::now();
+        let idle_timeout = self.acceptor.settings().keep_alive.idle_timeout;
+        let mut overdue = Vec::new();
+        let mut expired = Vec::new();
+        for (&token, c) in &self.connections {
+            match c.state {
+                State::Reading if c.overdue() => overdue.push(token),
+                State::Reading if c.last_active.elapsed() >= idle_timeout => expired.push(token),
+                State::Writing if c.last_active.elapsed() >= c.limits.write_timeout => {
+                    expired.push(token)
+                }
+                _ => {}
+            }
+        }
+        for token in overdue {
+            self.reject(token, ParseError::Timeout);
+        }
+        for token in expired {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            let _ = self.poller.delete(connection.stream.as_raw_fd());
+        }
+    }
+}
+
+/// Parses the first request in `input`, returning it with the number of
+/// bytes it took, or `None` if `input` does not hold a whole request yet.
+fn parse(input: &[u8], limits: Limits) -> Result<Option<(Request, usize)>, ParseError> {
+    // 期限は`sweep`で確かめるので、ここでは大きさだけを制限します
+    let limits = Limits {
+        header_timeout: None,
+        body_timeout: None,
+        ..limits
+    };
+    let mut reader = RequestReader::with_limits(input, limits);
+    match reader.read_request() {
+        Ok(Some(request)) => {
+            let unread = reader.buffered().len() + reader.get_ref().len();
+            Ok(Some((request, input.len() - unread)))
+        }
+        Ok(None) | Err(ParseError::Io(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// ワーカーからノンブロッキングのソケットに書くための`Write`。
+///
+/// Writes to the loop's non-blocking socket from a worker, waiting up to
+/// `timeout` for room whenever the socket is full.
+struct Blocking<'a> {
+    socket: &'a TcpStream,
+    timeout: Duration,
+}
+
+impl Write for Blocking<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match (&mut &*self.socket).write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.wait()?,
+                other => return other,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Blocking<'_> {
+    fn wait(&self) -> io::Result<()> {
+        let mut fd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        let timeout = self.timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `fd` is a valid pollfd, and the count says there is one.
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            0 => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the client stopped reading the response",
+            )),
+            n => cvt(n).map(drop).or_else(|e| match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            }),
+        }
+    }
+}
+
+/// ワーカーが書き終えた応答。
+struct Completion {
+    token: u64,
+    output: Vec<u8>,
+    persist: bool,
+    /// Set when the response switched protocols.
+    upgrade: Option<OnUpgrade>,
+}
+
+/// Responses finished by workers, waiting for their loop to send them.
+struct Completions {
+    ready: Mutex<Vec<Completion>>,
+    waker: Waker,
+}
+
+impl Completions {
+    fn push(&self, completion: Completion) {
+        self.lock().push(completion);
+        self.waker.wake();
+    }
+
+    fn take(&self) -> Vec<Completion> {
+        self.waker.reset();
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Completion>> {
+        self.ready.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// ワーカーから応答を返すための宛先。
+///
+/// Where a worker sends its response. If the job is dropped or panics
+/// before sending one, the connection is closed instead of waiting forever.
+struct Reply {
+    token: u64,
+    completions: Arc<Completions>,
+    sent: bool,
+}
+
+impl Reply {
+    fn send(mut self, output: Vec<u8>, persist: bool) {
+        self.sent = true;
+        self.completions.push(Completion {
+            token: self.token,
+            output,
+            persist,
+            upgrade: None,
+        });
+    }
+
+    /// Sends a 101 response, after which `on_upgrade` takes over.
+    fn upgrade(mut self, output: Vec<u8>, on_upgrade: OnUpgrade) {
+        self.sent = true;
+        self.completions.push(Completion {
+            token: self.token,
+            output,
+            persist: false,
+            upgrade: Some(on_upgrade),
+        });
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.completions.push(Completion {
+                token: self.token,
+                output: Vec::new(),
+                persist: false,
+                upgrade: None,
+            });
+        }
+    }
+}
+
+/// epollのインスタンス。
+struct Poller(OwnedFd);
+
+impl Poller {
+    fn new() -> io::Result<Poller> {
+        // SAFETY: epoll_create1 has no preconditions, and the descriptor it
+        // returns is owned by nothing else.
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poller(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        // SAFETY: `event` outlives the call, and the kernel only reads it.
+        cvt(unsafe { libc::epoll_ctl(self.0.as_raw_fd(), op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` and fills `events` with the ready descriptors.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        // SAFETY: the kernel writes at most `capacity` events into the
+        // vector's spare capacity, and `set_len` covers only those.
+        let n = unsafe {
+            libc::epoll_wait(
+                self.0.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as libc::c_int,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        match cvt(n) {
+            Ok(n) => {
+                unsafe { events.set_len(n as usize) };
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// 他のスレッドからループを起こすためのeventfd。
+struct Waker(OwnedFd);
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: as for epoll_create1.
+        let fd = cvt(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+        Ok(Waker(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn wake(&self) {
+        let one: u64 = 1;
+        // 既に起こされているなら、書けなくても構いません
+        // SAFETY: writes the 8 bytes of `one`.
+        unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+    }
+
+    fn reset(&self) {
+        let mut count: u64 = 0;
+        // SAFETY: reads at most 8 bytes into `count`.
+        unsafe {
+            libc::read(
+                self.0.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+    }
+}
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPool;
+    use crate::response::{Body, Response};
+    use crate::router::Router;
+    use crate::server::{ConnectionLimits, Server};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Mutex, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start(
+        pool: ThreadPool,
+    ) -> (
+        std::net::SocketAddr,
+        crate::server::ShutdownHandle,
+        thread::JoinHandle<()>,
+    ) {
+        start_with_limits(pool, ConnectionLimits::default())
+    }
+
+    fn start_with_limits(
+        pool: ThreadPool,
+        limits: ConnectionLimits,
+    ) -> (
+        std::net::SocketAddr,
+        crate::server::ShutdownHandle,
+        thread::JoinHandle<()>,
+    ) {
+        let router = Router::new()
+            .get("/:name", |_, p| {
+                Response::new(200).with_body(p.get("name").unwrap().to_string())
+            })
+            .post("/echo", |req, _| {
+                Response::new(200).with_body(req.body.clone())
+            });
+        start_with_router(pool, limits, router)
+    }
+
+    fn start_with_router(
+        pool: ThreadPool,
+        limits: ConnectionLimits,
+        router: Router,
+    ) -> (
+        std::net::SocketAddr,
+        crate::server::ShutdownHandle,
+        thread::JoinHandle<()>,
+    ) {
+        let server = Server::new(TcpListener::bind("127.0.0.1:0").unwrap(), pool, router)
+            .with_limits(limits)
+            .with_event_loop(2);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run().unwrap());
+        (addr, handle, running)
+    }
+
+    /// Reads one response whose body is expected to end with `body`.
+    fn read_response(stream: &mut TcpStream, body: &str) -> String {
+        let mut out = Vec::new();
+        let mut buf = [0; 1024];
+        while !out.ends_with(format!("\r\n\r\n{}", body).as_bytes()) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed early");
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn idle_connections_do_not_hold_workers() {
+        let (addr, handle, running) = start(ThreadPool::new(1));
+
+        // With one worker, a single idle keep-alive connection would block
+        // every later client in the thread-per-connection mode.
+        let mut idle = Vec::new();
+        for _ in 0..20 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /idle HTTP/1.1\r\nHost: t\r\n\r\n")
+                .unwrap();
+            assert!(read_response(&mut client, "idle").starts_with("HTTP/1.1 200 OK\r\n"));
+            idle.push(client);
+        }
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /active HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\nactive"));
+
+        handle.shutdown();
+        running.join().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(idle[0].read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn answers_pipelined_and_malformed_requests() {
+        let (addr, handle, running) = start(ThreadPool::new(2));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /a HTTP/1.1\r\nHost: t\r\n\r\n\
+                  GET /b HTTP/1.1\r\nHost: t\r\n\r\n\
+                  GET /c HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        let bodies: Vec<&str> = out
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .map(|r| r.rsplit("\r\n\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["a", "b", "c"]);
+
+        let mut bad = TcpStream::connect(addr).unwrap();
+        bad.write_all(b"GET /\r\n\r\n").unwrap();
+        let mut out = String::new();
+        bad.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn slow_requests_are_answered_with_408() {
+        let mut limits = ConnectionLimits::default();
+        limits.request.header_timeout = Some(Duration::from_millis(300));
+        limits.request.body_timeout = Some(Duration::from_millis(300));
+        let (addr, handle, running) = start_with_limits(ThreadPool::new(1), limits);
+        let started = Instant::now();
+
+        // One client trickles its headers and stays active the whole time;
+        // the other stops halfway through its body.
+        let mut slow_head = TcpStream::connect(addr).unwrap();
+        slow_head.write_all(b"GET /a HTTP/1.1\r\n").unwrap();
+        let mut writer = slow_head.try_clone().unwrap();
+        thread::spawn(move || {
+            for _ in 0..60 {
+                thread::sleep(Duration::from_millis(50));
+                if writer.write_all(b"X").is_err() {
+                    break;
+                }
+            }
+        });
+        let mut slow_body = TcpStream::connect(addr).unwrap();
+        slow_body
+            .write_all(b"POST /b HTTP/1.1\r\nHost: t\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+
+        for client in [&mut slow_head, &mut slow_body] {
+            let mut out = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = client.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+            }
+            assert!(out.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn reassembles_requests_that_arrive_a_byte_at_a_time() {
+        let (addr, handle, running) = start(ThreadPool::new(2));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_nodelay(true).unwrap();
+        let requests: &[u8] =
+            b"\r\nPOST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 5\r\n\r\nhello\
+              POST /echo HTTP/1.1\r\nHost: t\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3;x=y\r\nabc\r\n2\nde\n0\r\nTrailer: 1\r\n\r\n";
+        for &b in requests {
+            client.write_all(&[b]).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        let out = read_response(&mut client, "abcde");
+        assert!(
+            out.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 200 OK\r\n")
+        );
+
+        // 大きすぎる本文は、届くのを待たずに断ります
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 999999999\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_are_sent_as_they_are_produced() {
+        // The second half of the body is only produced once the client has
+        // seen the first, which never happens if the body is gathered first.
+        let (go, wait) = mpsc::channel::<()>();
+        let wait = Mutex::new(Some(wait));
+        let router = Router::new().get("/stream", move |_, _| {
+            let wait = wait.lock().unwrap().take().unwrap();
+            let chunks = [vec![b'a'; 64 * 1024], b"end".to_vec()]
+                .into_iter()
+                .enumerate()
+                .map(move |(i, chunk)| {
+                    if i == 1 {
+                        wait.recv().unwrap();
+                    }
+                    Ok(chunk)
+                });
+            Response::new(200).with_body(Body::chunked(chunks))
+        });
+        let (addr, handle, running) =
+            start_with_router(ThreadPool::new(1), ConnectionLimits::default(), router);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET /stream HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0; 16 * 1024];
+        while out.iter().filter(|&&b| b == b'a').count() < 64 * 1024 {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed early");
+            out.extend_from_slice(&buf[..n]);
+        }
+        go.send(()).unwrap();
+        client.read_to_end(&mut out).unwrap();
+        assert!(out.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with(b"3\r\nend\r\n0\r\n\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+}
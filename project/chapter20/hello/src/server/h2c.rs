@@ -0,0 +1,1102 @@
+//! 平文のHTTP/2 (h2c) の接続。
+//!
+//! Serves HTTP/2 connections whose clients open with the preface in
+//! cleartext.
+//!
+//! The worker that took the connection reads its frames for as long as it
+//! is open. Once a stream's request is complete, the stream is answered on
+//! a pool worker of its own, which writes the response's frames through
+//! the connection's shared writer, waiting for the client to open its
+//! flow-control windows when they run out. Request bodies are buffered in
+//! full before the request is dispatched, and their window is given back as
+//! soon as each DATA frame arrives; the body size limit is what bounds them.
+//!
+//! On a protocol error that affects the whole connection, the server sends
+//! GOAWAY with the error code and closes the connection. When the client
+//! closes, the connection idles out or the server shuts down, the server
+//! sends GOAWAY with `NO_ERROR` and the streams already being answered are
+//! finished.
+
+use super::{
+    ConnectionLimits, Instruments, KeepAlive, Options, is_timeout, log_access, record, respond,
+};
+use crate::Executor;
+use crate::http2::frame::{ACK, END_HEADERS, END_STREAM};
+use crate::http2::hpack::{self, Decoder, Encoder};
+use crate::http2::{
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_WINDOW, Error, ErrorCode, Frame, Kind, MAX_WINDOW, PREFACE,
+    Settings,
+};
+use crate::lock;
+use crate::request::{self, Headers, Method, Request, Version};
+use crate::response::{self, Body, Response};
+use crate::router::Router;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 1つの接続で同時に開けるストリームの数
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+// CONTINUATIONで続くヘッダーブロックの上限
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+// 前置きが分かれて届いたときに、続きを確かめる間隔
+const PREFACE_POLL: Duration = Duration::from_millis(1);
+
+// HTTP/2では使えない、接続についてのヘッダー (RFC 9113 8.2.2)
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// HTTP/2の接続で、ストリームに応答するための設定。
+///
+/// What the streams of an HTTP/2 connection are answered with.
+pub(super) struct Service {
+    pub(super) router: Arc<Router>,
+    pub(super) keep_alive: KeepAlive,
+    pub(super) compression: bool,
+    pub(super) limits: ConnectionLimits,
+    pub(super) instruments: Arc<Instruments>,
+    pub(super) executor: Executor,
+}
+
+/// Waits for the first bytes on `socket` without consuming them, and tells
+/// whether they are the HTTP/2 preface. A preface that is still incomplete
+/// at `deadline` counts as HTTP/1.
+pub(super) fn has_preface(socket: &TcpStream, deadline: Instant) -> io::Result<bool> {
+    let mut buf = [0; PREFACE.len()];
+    loop {
+        let n = socket.peek(&mut buf)?;
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(PREFACE_POLL);
+    }
+}
+
+/// Whether `input`, the first bytes of a connection, starts the HTTP/2
+/// preface; it may be too short to hold all of it yet.
+pub(super) fn starts_preface(input: &[u8]) -> bool {
+    let n = input.len().min(PREFACE.len());
+    n > 0 && input[..n] == PREFACE[..n]
+}
+
+/// Serves an HTTP/2 connection until it closes. `buffered` holds bytes
+/// already read from `stream`, starting with the preface if it was read.
+/// `stream` must be blocking, with the keep-alive idle timeout as its read
+/// timeout.
+pub(super) fn serve(
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    peer: Option<SocketAddr>,
+    service: Service,
+) -> io::Result<()> {
+    let shared = Arc::new(Shared {
+        writer: Mutex::new(Writer {
+            stream: stream.try_clone()?,
+            encoder: Encoder::new(),
+        }),
+        flow: Mutex::new(Flow {
+            window: DEFAULT_WINDOW as i64,
+            initial_window: DEFAULT_WINDOW as i64,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE as usize,
+            streams: HashMap::new(),
+            closed: false,
+        }),
+        window_changed: Condvar::new(),
+        write_timeout: service.limits.write_timeout,
+    });
+    let decoder = Decoder::new().with_max_list_size(service.limits.request.max_header_bytes);
+    let mut connection = Connection {
+        shared: Arc::clone(&shared),
+        service: Arc::new(service),
+        peer,
+        decoder,
+        peer_settings: Settings::default(),
+        receiving: HashMap::new(),
+        last_stream: 0,
+        going_away: false,
+    };
+    let mut reader = BufReader::new(io::Cursor::new(buffered).chain(stream));
+
+    let (code, reason) = match connection.run(&mut reader) {
+        Ok(()) => (ErrorCode::NO_ERROR, ""),
+        Err(Error::Connection(code, reason)) => {
+            debug!("HTTP/2 connection error {}: {}", code, reason);
+            (code, reason)
+        }
+        Err(Error::Io(e)) if is_timeout(&e) => (ErrorCode::NO_ERROR, ""),
+        Err(Error::Io(e)) => {
+            shared.close();
+            return Err(e);
+        }
+        Err(Error::Stream(..)) => unreachable!("stream errors are handled by the reader"),
+    };
+    // 応答中のストリームは、エラーでなければ最後まで送ります
+    if code != ErrorCode::NO_ERROR {
+        shared.close();
+    }
+    let _ = shared.write(&[Frame::goaway(connection.last_stream, code, reason)]);
+    Ok(())
+}
+
+/// 読み込み側と、応答するワーカーとで共有する状態。
+struct Shared {
+    writer: Mutex<Writer>,
+    flow: Mutex<Flow>,
+    /// Signalled when a send window grows or a stream is reset.
+    window_changed: Condvar,
+    write_timeout: Duration,
+}
+
+struct Writer {
+    stream: TcpStream,
+    /// Headers must be encoded in the order their blocks are written.
+    encoder: Encoder,
+}
+
+/// 送信側のフロー制御の状態。
+struct Flow {
+    /// The connection's send window.
+    window: i64,
+    /// The send window new streams start with.
+    initial_window: i64,
+    max_frame_size: usize,
+    /// The send windows of the open streams. A stream leaves the map when
+    /// its response is complete or it is reset.
+    streams: HashMap<u32, i64>,
+    /// The connection failed; nothing more can be sent.
+    closed: bool,
+}
+
+impl Shared {
+    fn write(&self, frames: &[Frame]) -> io::Result<()> {
+        let mut writer = lock(&self.writer);
+        let mut bytes = Vec::new();
+        for frame in frames {
+            frame.write_to(&mut bytes)?;
+        }
+        io::Write::write_all(&mut writer.stream, &bytes)
+    }
+
+    /// Writes a header block for stream `id`, in as many frames as the
+    /// client's maximum frame size requires.
+    fn write_headers(
+        &self,
+        id: u32,
+        fields: &[(String, String)],
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let max_frame_size = lock(&self.flow).max_frame_size;
+        let mut writer = lock(&self.writer);
+        let mut block = Vec::new();
+        writer
+            .encoder
+            .encode(fields.iter().map(|(n, v)| (&n[..], &v[..])), &mut block);
+
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut bytes = Vec::new();
+        let first = chunks.next().unwrap_or(&[]).to_vec();
+        Frame::headers(id, first, end_stream, chunks.peek().is_none()).write_to(&mut bytes)?;
+        while let Some(chunk) = chunks.next() {
+            Frame::continuation(id, chunk.to_vec(), chunks.peek().is_none())
+                .write_to(&mut bytes)?;
+        }
+        io::Write::write_all(&mut writer.stream, &bytes)
+    }
+
+    /// Sends `data` on stream `id` as the flow-control windows allow.
+    fn write_data(&self, id: u32, mut data: &[u8], end_stream: bool) -> Result<(), Error> {
+        loop {
+            let n = if data.is_empty() {
+                0
+            } else {
+                self.reserve(id, data.len())?
+            };
+            let (piece, rest) = data.split_at(n);
+            self.write(&[Frame::data(
+                id,
+                piece.to_vec(),
+                end_stream && rest.is_empty(),
+            )])?;
+            data = rest;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Takes up to `wanted` bytes from the windows of the connection and
+    /// stream `id`, waiting up to the write timeout for them to open.
+    fn reserve(&self, id: u32, wanted: usize) -> Result<usize, Error> {
+        let deadline = Instant::now() + self.write_timeout;
+        let mut flow = lock(&self.flow);
+        loop {
+            if flow.closed {
+                return Err(
+                    io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into(),
+                );
+            }
+            let Some(&stream_window) = flow.streams.get(&id) else {
+                return Err(Error::Stream(id, ErrorCode::CANCEL, "stream reset"));
+            };
+            let available = stream_window.min(flow.window);
+            if available > 0 {
+                let n = wanted.min(available as usize).min(flow.max_frame_size);
+                flow.window -= n as i64;
+                flow.streams.insert(id, stream_window - n as i64);
+                return Ok(n);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Stream(
+                    id,
+                    ErrorCode::CANCEL,
+                    "flow control window stayed closed",
+                ));
+            }
+            flow = self
+                .window_changed
+                .wait_timeout(flow, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Starts counting the send window of a new stream.
+    fn open(&self, id: u32) {
+        let mut flow = lock(&self.flow);
+        let window = flow.initial_window;
+        flow.streams.insert(id, window);
+    }
+
+    /// Stops counting stream `id`; returns whether it was open.
+    fn finish(&self, id: u32) -> bool {
+        let removed = lock(&self.flow).streams.remove(&id).is_some();
+        self.window_changed.notify_all();
+        removed
+    }
+
+    /// Resets stream `id` with `code`.
+    fn reset(&self, id: u32, code: ErrorCode) {
+        self.finish(id);
+        let _ = self.write(&[Frame::rst_stream(id, code)]);
+    }
+
+    /// Fails every stream still waiting to send.
+    fn close(&self) {
+        lock(&self.flow).closed = true;
+        self.window_changed.notify_all();
+    }
+
+    fn active(&self) -> usize {
+        lock(&self.flow).streams.len()
+    }
+}
+
+/// 接続を読む側の状態。
+struct Connection {
+    shared: Arc<Shared>,
+    service: Arc<Service>,
+    peer: Option<SocketAddr>,
+    decoder: Decoder,
+    peer_settings: Settings,
+    /// The requests still arriving, by stream.
+    receiving: HashMap<u32, Request>,
+    /// The highest stream the client has opened.
+    last_stream: u32,
+    /// The client sent GOAWAY.
+    going_away: bool,
+}
+
+impl Connection {
+    fn run<R: BufRead>(&mut self, reader: &mut R) -> Result<(), Error> {
+        let mut preface = [0; PREFACE.len()];
+        reader.read_exact(&mut preface)?;
+        if &preface != PREFACE {
+            return Err(protocol("invalid preface"));
+        }
+        let settings = Settings {
+            max_concurrent_streams: Some(MAX_CONCURRENT_STREAMS),
+            max_header_list_size: Some(self.service.limits.request.max_header_bytes as u32),
+            ..Settings::default()
+        };
+        self.shared.write(&[settings.to_frame()])?;
+
+        // クライアントも、前置きの直後にSETTINGSを送ります
+        let first = Frame::read_from(reader, DEFAULT_MAX_FRAME_SIZE)?;
+        if first.kind != Kind::Settings || first.has(ACK) {
+            return Err(protocol("expected SETTINGS after the preface"));
+        }
+        self.handle(first, reader)?;
+
+        loop {
+            if self.going_away && self.shared.active() == 0 {
+                return Ok(());
+            }
+            match reader.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => {}
+                // 応答中のストリームがあるうちは、待ち続けます
+                Err(e) if is_timeout(&e) && self.shared.active() > 0 => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let frame = Frame::read_from(reader, DEFAULT_MAX_FRAME_SIZE)?;
+            match self.handle(frame, reader) {
+                Err(Error::Stream(id, code, reason)) => {
+                    debug!("HTTP/2 stream {} error {}: {}", id, code, reason);
+                    self.receiving.remove(&id);
+                    self.shared.reset(id, code);
+                }
+                result => result?,
+            }
+        }
+    }
+
+    fn handle<R: Read>(&mut self, frame: Frame, reader: &mut R) -> Result<(), Error> {
+        let id = frame.stream;
+        let on_connection = match frame.kind {
+            Kind::Settings | Kind::Ping | Kind::GoAway => true,
+            Kind::WindowUpdate | Kind::Unknown(_) => false,
+            _ if id == 0 => return Err(protocol("frame needs a stream")),
+            _ => false,
+        };
+        if on_connection && id != 0 {
+            return Err(protocol("frame on a stream"));
+        }
+
+        match frame.kind {
+            Kind::Data => self.on_data(frame),
+            Kind::Headers => self.on_headers(frame, reader),
+            Kind::Priority if frame.payload.len() != 5 => Err(Error::Stream(
+                id,
+                ErrorCode::FRAME_SIZE_ERROR,
+                "PRIORITY length not 5",
+            )),
+            // 優先度は使いません
+            Kind::Priority => Ok(()),
+            Kind::RstStream => {
+                if frame.payload.len() != 4 {
+                    return Err(frame_size("RST_STREAM length not 4"));
+                }
+                if id > self.last_stream {
+                    return Err(protocol("RST_STREAM on an idle stream"));
+                }
+                self.receiving.remove(&id);
+                self.shared.finish(id);
+                Ok(())
+            }
+            Kind::Settings => self.on_settings(frame),
+            Kind::Ping => {
+                let data: [u8; 8] = frame
+                    .payload
+                    .try_into()
+                    .map_err(|_| frame_size("PING length not 8"))?;
+                if frame.flags & ACK == 0 {
+                    self.shared.write(&[Frame::ping(data, true)])?;
+                }
+                Ok(())
+            }
+            Kind::GoAway => {
+                self.going_away = true;
+                Ok(())
+            }
+            Kind::WindowUpdate => self.on_window_update(frame),
+            Kind::PushPromise => Err(protocol("clients cannot push")),
+            Kind::Continuation => Err(protocol("CONTINUATION without HEADERS")),
+            Kind::Unknown(_) => Ok(()),
+        }
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.has(ACK) {
+            if !frame.payload.is_empty() {
+                return Err(frame_size("SETTINGS ACK with a payload"));
+            }
+            return Ok(());
+        }
+        let before = self.peer_settings;
+        self.peer_settings.apply(&frame.payload)?;
+        let settings = self.peer_settings;
+
+        if settings.header_table_size != before.header_table_size {
+            lock(&self.shared.writer)
+                .encoder
+                .set_max_table_size(settings.header_table_size as usize);
+        }
+        {
+            let mut flow = lock(&self.shared.flow);
+            flow.max_frame_size = settings.max_frame_size as usize;
+            // 初期値の変化は、開いているストリームの窓にも反映します (RFC 9113 6.9.2)
+            let delta = settings.initial_window_size as i64 - flow.initial_window;
+            flow.initial_window = settings.initial_window_size as i64;
+            for window in flow.streams.values_mut() {
+                *window += delta;
+                if *window > MAX_WINDOW as i64 {
+                    return Err(Error::Connection(
+                        ErrorCode::FLOW_CONTROL_ERROR,
+                        "window too large",
+                    ));
+                }
+            }
+        }
+        self.shared.window_changed.notify_all();
+        self.shared.write(&[Frame::settings_ack()])?;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        let id = frame.stream;
+        let bytes: [u8; 4] = frame
+            .payload
+            .try_into()
+            .map_err(|_| frame_size("WINDOW_UPDATE length not 4"))?;
+        let increment = (u32::from_be_bytes(bytes) & 0x7FFF_FFFF) as i64;
+        if increment == 0 && id == 0 {
+            return Err(protocol("zero window increment"));
+        }
+        if increment == 0 {
+            return Err(Error::Stream(
+                id,
+                ErrorCode::PROTOCOL_ERROR,
+                "zero window increment",
+            ));
+        }
+
+        let mut flow = lock(&self.shared.flow);
+        if id == 0 {
+            flow.window += increment;
+            if flow.window > MAX_WINDOW as i64 {
+                return Err(Error::Connection(
+                    ErrorCode::FLOW_CONTROL_ERROR,
+                    "window too large",
+                ));
+            }
+        } else if let Some(window) = flow.streams.get_mut(&id) {
+            *window += increment;
+            if *window > MAX_WINDOW as i64 {
+                return Err(Error::Stream(
+                    id,
+                    ErrorCode::FLOW_CONTROL_ERROR,
+                    "window too large",
+                ));
+            }
+        } else if id > self.last_stream {
+            return Err(protocol("WINDOW_UPDATE on an idle stream"));
+        }
+        drop(flow);
+        self.shared.window_changed.notify_all();
+        Ok(())
+    }
+
+    fn on_headers<R: Read>(&mut self, frame: Frame, reader: &mut R) -> Result<(), Error> {
+        let id = frame.stream;
+        let end_stream = frame.has(END_STREAM);
+        let mut block = frame.fragment()?.to_vec();
+        let mut end_headers = frame.has(END_HEADERS);
+        while !end_headers {
+            let next = Frame::read_from(reader, DEFAULT_MAX_FRAME_SIZE)?;
+            if next.kind != Kind::Continuation || next.stream != id {
+                return Err(protocol("expected CONTINUATION"));
+            }
+            block.extend_from_slice(&next.payload);
+            if block.len() > MAX_HEADER_BLOCK {
+                return Err(Error::Connection(
+                    ErrorCode::ENHANCE_YOUR_CALM,
+                    "header block too large",
+                ));
+            }
+            end_headers = next.has(END_HEADERS);
+        }
+
+        // 断るストリームのヘッダーも、表を揃えるために復号します
+        let headers = match self.decoder.decode(&block) {
+            Ok(headers) => Ok(headers),
+            Err(hpack::Error::ListTooLarge) => Err(()),
+            Err(hpack::Error::Compression(reason)) => {
+                return Err(Error::Connection(ErrorCode::COMPRESSION_ERROR, reason));
+            }
+        };
+
+        // 本文の後のトレーラーは、読み捨てます
+        if self.receiving.contains_key(&id) {
+            if !end_stream {
+                return Err(Error::Stream(
+                    id,
+                    ErrorCode::PROTOCOL_ERROR,
+                    "trailers without END_STREAM",
+                ));
+            }
+            return self.complete(id);
+        }
+        if id.is_multiple_of(2) {
+            return Err(protocol("even stream id"));
+        }
+        // リセットした後に届いたものかもしれないので、閉じたストリームへの
+        // フレームは無視します
+        if id <= self.last_stream {
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.shared.active() >= MAX_CONCURRENT_STREAMS as usize {
+            return Err(Error::Stream(
+                id,
+                ErrorCode::REFUSED_STREAM,
+                "too many streams",
+            ));
+        }
+
+        let Ok(headers) = headers else {
+            self.shared.open(id);
+            return self.refuse(id, 431, end_stream);
+        };
+        let request = request_from(headers, self.peer)
+            .map_err(|reason| Error::Stream(id, ErrorCode::PROTOCOL_ERROR, reason))?;
+        self.shared.open(id);
+        self.receiving.insert(id, request);
+        if end_stream {
+            self.complete(id)?;
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Error> {
+        let id = frame.stream;
+        // 本文はすぐに読み込むので、受け取った分の窓はすぐに返します
+        let len = frame.payload.len() as u32;
+        if len > 0 {
+            self.shared.write(&[Frame::window_update(0, len)])?;
+        }
+        let data = frame.fragment()?;
+        let Some(request) = self.receiving.get_mut(&id) else {
+            if id > self.last_stream {
+                return Err(protocol("DATA on an idle stream"));
+            }
+            return Ok(());
+        };
+
+        let end_stream = frame.has(END_STREAM);
+        if request.body.len() + data.len() > self.service.limits.request.max_body_bytes {
+            return self.refuse(id, 413, end_stream);
+        }
+        request.body.extend_from_slice(data);
+        if end_stream {
+            return self.complete(id);
+        }
+        if len > 0 {
+            self.shared.write(&[Frame::window_update(id, len)])?;
+        }
+        Ok(())
+    }
+
+    /// Answers stream `id` with `status` right away, without its request,
+    /// and discards the rest of the request.
+    fn refuse(&mut self, id: u32, status: u16, end_stream: bool) -> Result<(), Error> {
+        let fields = [(":status".to_string(), status.to_string())];
+        self.shared.write_headers(id, &fields, true)?;
+        log_access(self.peer, None, status, 0);
+        self.receiving.remove(&id);
+        if end_stream {
+            self.shared.finish(id);
+        } else {
+            // 応答は済んだので、残りの本文は送らないよう伝えます (RFC 9113 8.1)
+            self.shared.reset(id, ErrorCode::NO_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Dispatches the request of stream `id`, which is complete.
+    fn complete(&mut self, id: u32) -> Result<(), Error> {
+        let Some(request) = self.receiving.remove(&id) else {
+            return Ok(());
+        };
+        let shared = Arc::clone(&self.shared);
+        let service = Arc::clone(&self.service);
+        let peer = self.peer;
+        let job = move || answer(&shared, &service, peer, id, request);
+        if self.service.executor.try_execute(job).is_err() {
+            warn!("Thread pool is busy; refusing HTTP/2 stream {}.", id);
+            self.shared.reset(id, ErrorCode::REFUSED_STREAM);
+        }
+        Ok(())
+    }
+}
+
+/// Answers the request of stream `id` on a pool worker.
+fn answer(
+    shared: &Shared,
+    service: &Service,
+    peer: Option<SocketAddr>,
+    id: u32,
+    mut request: Request,
+) {
+    let options = Options {
+        keep_alive: &service.keep_alive,
+        compression: service.compression,
+        limits: &service.limits,
+        instruments: Some(&service.instruments),
+    };
+    let started = Instant::now();
+    let (mut response, _) = respond(&mut request, &service.router, &options, 1);
+    // プロトコルの切り替えは、HTTP/1.1でしかできません
+    if response.status == 101 || response.take_upgrade().is_some() {
+        shared.reset(id, ErrorCode::HTTP_1_1_REQUIRED);
+        return;
+    }
+    match send_response(shared, id, &request, &mut response) {
+        Ok(written) => {
+            record(peer, &request, &response, written, started, &options);
+            shared.finish(id);
+        }
+        Err(Error::Stream(_, code, reason)) => {
+            debug!("HTTP/2 stream {} error {}: {}", id, code, reason);
+            shared.reset(id, code);
+        }
+        Err(e) => {
+            debug!("HTTP/2 stream {} failed: {}", id, e);
+            shared.reset(id, ErrorCode::INTERNAL_ERROR);
+        }
+    }
+}
+
+/// Sends `response` on stream `id`, and returns the number of body bytes
+/// sent.
+fn send_response(
+    shared: &Shared,
+    id: u32,
+    request: &Request,
+    response: &mut Response,
+) -> Result<u64, Error> {
+    let has_body = response::has_body(response.status);
+    let mut fields = vec![(":status".to_string(), response.status.to_string())];
+    if let Some(len) = response.body.len()
+        && has_body
+        && !response.headers.contains("content-length")
+    {
+        fields.push(("content-length".to_string(), len.to_string()));
+    }
+    for (name, value) in response.headers.iter() {
+        let name = name.to_ascii_lowercase();
+        if !CONNECTION_SPECIFIC.contains(&name.as_str()) {
+            fields.push((name, value.to_string()));
+        }
+    }
+
+    let body = std::mem::replace(&mut response.body, Body::empty());
+    let expected = body.len();
+    if !has_body || request.method == Method::Head || expected == Some(0) {
+        shared.write_headers(id, &fields, true)?;
+        return Ok(0);
+    }
+    shared.write_headers(id, &fields, false)?;
+
+    // 最後の断片にEND_STREAMを付けるため、1つ先まで読みます
+    let mut written = 0;
+    let mut chunks = body.into_chunks().peekable();
+    while let Some(chunk) = chunks.next() {
+        let chunk = chunk?;
+        let last = chunks.peek().is_none();
+        if chunk.is_empty() && !last {
+            continue;
+        }
+        shared.write_data(id, &chunk, last)?;
+        written += chunk.len() as u64;
+    }
+    if expected.is_some_and(|len| len != written) {
+        return Err(
+            io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than its length").into(),
+        );
+    }
+    Ok(written)
+}
+
+/// Builds a request from the headers of a HEADERS frame, or says why they
+/// are malformed.
+fn request_from(
+    headers: Vec<(String, String)>,
+    peer: Option<SocketAddr>,
+) -> Result<Request, &'static str> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut fields = Headers::new();
+    let mut cookies = Vec::new();
+
+    for (name, value) in headers {
+        // CRやLFを通すと、HTTP/1.1に書き直したときに別のリクエストになりえます (RFC 9113 8.2.1)
+        if !request::is_field_value(&value)
+            || value.starts_with([' ', '\t'])
+            || value.ends_with([' ', '\t'])
+        {
+            return Err("invalid header value");
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !fields.is_empty() || !cookies.is_empty() {
+                return Err("pseudo-header after regular headers");
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err("unknown pseudo-header"),
+            };
+            if slot.replace(value).is_some() {
+                return Err("duplicate pseudo-header");
+            }
+            continue;
+        }
+        if !request::is_token(&name) {
+            return Err("invalid header name");
+        }
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err("uppercase header name");
+        }
+        if CONNECTION_SPECIFIC.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err("connection-specific header");
+        }
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            fields.append(&name, &value);
+        }
+    }
+
+    // Cookieは分けて送られることがあるので、1つにまとめます (RFC 9113 8.2.3)
+    if !cookies.is_empty() {
+        fields.append("cookie", &cookies.join("; "));
+    }
+    let method = method.ok_or("missing :method")?;
+    if !request::is_token(&method) {
+        return Err("invalid :method");
+    }
+    let method = Method::parse(&method);
+    if method == Method::Connect {
+        return Err("CONNECT is not supported");
+    }
+    let (Some(_), Some(target)) = (scheme, path) else {
+        return Err("missing :scheme or :path");
+    };
+    // `*`はOPTIONSにだけ使えます
+    let known_form = target.starts_with('/') || (target == "*" && method == Method::Options);
+    if !known_form || !request::is_request_target(&target) {
+        return Err("invalid :path");
+    }
+    if let Some(authority) = authority
+        && !fields.contains("host")
+    {
+        fields.insert("host", &authority);
+    }
+
+    Ok(Request {
+        method,
+        target,
+        version: Version::Http2,
+        headers: fields,
+        body: Vec::new(),
+        peer,
+    })
+}
+
+fn protocol(reason: &'static str) -> Error {
+    Error::Connection(ErrorCode::PROTOCOL_ERROR, reason)
+}
+
+fn frame_size(reason: &'static str) -> Error {
+    Error::Connection(ErrorCode::FRAME_SIZE_ERROR, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::request_from;
+    use crate::ThreadPool;
+    use crate::http2::frame::{ACK, END_STREAM};
+    use crate::http2::hpack::{Decoder, Encoder};
+    use crate::http2::{ErrorCode, Frame, Kind, PREFACE};
+    use crate::response::Response;
+    use crate::router::Router;
+    use crate::server::{Server, ShutdownHandle};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn start(event_loop: bool) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+        let router = Router::new()
+            .get("/:name", |_, p| {
+                Response::new(200).with_body(p.get("name").unwrap().to_string())
+            })
+            .post("/echo", |request, _| {
+                Response::new(200).with_body(request.body.clone())
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(listener, ThreadPool::new(4), router).with_http2(true);
+        #[cfg(target_os = "linux")]
+        if event_loop {
+            server = server.with_event_loop(1);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = event_loop;
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run().unwrap());
+        (addr, handle, running)
+    }
+
+    /// HTTP/2を話す、テスト用の最小限のクライアント。
+    struct Client {
+        stream: TcpStream,
+        encoder: Encoder,
+        decoder: Decoder,
+    }
+
+    impl Client {
+        /// Connects, sending the preface and `settings`.
+        fn connect(addr: SocketAddr, settings: &[(u16, u32)]) -> Client {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut client = Client {
+                stream,
+                encoder: Encoder::new(),
+                decoder: Decoder::new(),
+            };
+            client.stream.write_all(PREFACE).unwrap();
+            client.send(Frame::settings(settings));
+            client
+        }
+
+        fn send(&mut self, frame: Frame) {
+            frame.write_to(&mut self.stream).unwrap();
+        }
+
+        fn request(&mut self, id: u32, method: &str, path: &str, body: Option<&[u8]>) {
+            let headers = [
+                (":method", method),
+                (":scheme", "http"),
+                (":path", path),
+                (":authority", "localhost"),
+            ];
+            let mut block = Vec::new();
+            self.encoder.encode(headers, &mut block);
+            self.send(Frame::headers(id, block, body.is_none(), true));
+            if let Some(body) = body {
+                self.send(Frame::data(id, body.to_vec(), true));
+            }
+        }
+
+        /// Reads the next frame, answering the server's SETTINGS.
+        fn read(&mut self) -> Frame {
+            loop {
+                let frame = Frame::read_from(&mut self.stream, 1 << 24).unwrap();
+                if frame.kind == Kind::Settings {
+                    if !frame.has(ACK) {
+                        self.send(Frame::settings_ack());
+                    }
+                    continue;
+                }
+                return frame;
+            }
+        }
+
+        /// Reads frames until `count` streams have ended, and returns their
+        /// statuses and bodies.
+        fn responses(&mut self, count: usize) -> HashMap<u32, (String, Vec<u8>)> {
+            let mut responses: HashMap<u32, (String, Vec<u8>)> = HashMap::new();
+            let mut ended = 0;
+            while ended < count {
+                let frame = self.read();
+                let entry = responses.entry(frame.stream).or_default();
+                match frame.kind {
+                    Kind::Headers => {
+                        let headers = self.decoder.decode(&frame.payload).unwrap();
+                        entry.0 = headers[0].1.clone();
+                    }
+                    Kind::Data => entry.1.extend_from_slice(&frame.payload),
+                    _ => continue,
+                }
+                if frame.has(END_STREAM) {
+                    ended += 1;
+                }
+            }
+            responses
+        }
+    }
+
+    #[test]
+    fn answers_streams_on_one_connection() {
+        let (addr, handle, running) = start(false);
+        let mut client = Client::connect(addr, &[]);
+        client.request(1, "GET", "/hello", None);
+        client.request(3, "POST", "/echo", Some(b"ping pong"));
+        client.request(5, "HEAD", "/hello", None);
+
+        let responses = client.responses(3);
+        assert_eq!(responses[&1], ("200".to_string(), b"hello".to_vec()));
+        assert_eq!(responses[&3], ("200".to_string(), b"ping pong".to_vec()));
+        assert_eq!(responses[&5], ("200".to_string(), Vec::new()));
+
+        client.send(Frame::ping(*b"12345678", false));
+        let pong = client.read();
+        assert_eq!((pong.kind, pong.flags), (Kind::Ping, ACK));
+        assert_eq!(pong.payload, b"12345678");
+
+        // HTTP/1.1のクライアントも、同じ待ち受けで受け付けます
+        let mut http1 = TcpStream::connect(addr).unwrap();
+        http1
+            .write_all(b"GET /plain HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        http1.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK") && out.ends_with("plain"));
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn waits_for_the_client_to_open_its_window() {
+        let (addr, handle, running) = start(false);
+        // INITIAL_WINDOW_SIZE = 4
+        let mut client = Client::connect(addr, &[(0x4, 4)]);
+        client.request(1, "GET", "/abcdefghij", None);
+
+        let headers = client.read();
+        assert_eq!(headers.kind, Kind::Headers);
+        let data = client.read();
+        assert_eq!((data.kind, &data.payload[..]), (Kind::Data, &b"abcd"[..]));
+        assert!(!data.has(END_STREAM));
+
+        client.send(Frame::window_update(1, 100));
+        let mut body = Vec::new();
+        loop {
+            let frame = client.read();
+            body.extend_from_slice(&frame.payload);
+            if frame.has(END_STREAM) {
+                break;
+            }
+        }
+        assert_eq!(body, b"efghij");
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_headers_that_would_change_the_request() {
+        let parse = |extra: &[(&str, &str)]| {
+            let mut headers = vec![
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "localhost"),
+            ];
+            for (name, value) in extra {
+                match headers.iter_mut().find(|(n, _)| n == name) {
+                    Some(header) => header.1 = value,
+                    None => headers.push((name, value)),
+                }
+            }
+            let headers = headers
+                .into_iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect();
+            request_from(headers, None).err()
+        };
+
+        assert_eq!(parse(&[("x-ok", "a\tb")]), None);
+        assert_eq!(parse(&[(":method", "OPTIONS"), (":path", "*")]), None);
+
+        assert_eq!(parse(&[("x bad", "1")]), Some("invalid header name"));
+        assert_eq!(parse(&[("x:bad", "1")]), Some("invalid header name"));
+        assert_eq!(parse(&[("", "1")]), Some("invalid header name"));
+        for value in ["a\rb", "a\nb", "a\0b", " a", "a ", "\ta", "a\t"] {
+            assert_eq!(parse(&[("x-bad", value)]), Some("invalid header value"));
+        }
+        assert_eq!(
+            parse(&[(":authority", "localhost\r\nx: 1")]),
+            Some("invalid header value")
+        );
+        assert_eq!(
+            parse(&[(":method", "GET / HTTP/1.1")]),
+            Some("invalid :method")
+        );
+        assert_eq!(parse(&[(":method", "")]), Some("invalid :method"));
+        assert_eq!(parse(&[(":path", "")]), Some("invalid :path"));
+        assert_eq!(parse(&[(":path", "index.html")]), Some("invalid :path"));
+        assert_eq!(parse(&[(":path", "*")]), Some("invalid :path"));
+        assert_eq!(parse(&[(":path", "/a\tb")]), Some("invalid :path"));
+        assert_eq!(
+            parse(&[(":path", "/a\r\nGET /b")]),
+            Some("invalid header value")
+        );
+    }
+
+    #[test]
+    fn resets_malformed_streams_and_closes_on_protocol_errors() {
+        let (addr, handle, running) = start(false);
+        let mut client = Client::connect(addr, &[]);
+        let mut block = Vec::new();
+        client
+            .encoder
+            .encode([(":method", "GET"), (":scheme", "http")], &mut block);
+        client.send(Frame::headers(1, block, true, true));
+        let reset = client.read();
+        assert_eq!(reset.kind, Kind::RstStream);
+        assert_eq!(reset.error_code(), Some(ErrorCode::PROTOCOL_ERROR));
+
+        // 壊れたストリームの後も、接続は使えます
+        client.request(3, "GET", "/still-open", None);
+        assert_eq!(client.responses(1)[&3].1, b"still-open");
+
+        // クライアントは偶数のストリームを開けません
+        client.request(4, "GET", "/even", None);
+        let goaway = client.read();
+        assert_eq!(goaway.kind, Kind::GoAway);
+        assert_eq!(goaway.error_code(), Some(ErrorCode::PROTOCOL_ERROR));
+        assert_eq!(&goaway.payload[..4], 3u32.to_be_bytes());
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn event_loops_hand_http2_connections_to_workers() {
+        let (addr, handle, running) = start(true);
+        // 前置きが分かれて届いても、揃うまで待ちます
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&PREFACE[..10]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&PREFACE[10..]).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Client {
+            stream,
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+        };
+        client.send(Frame::settings(&[]));
+        client.request(1, "GET", "/looped", None);
+        assert_eq!(
+            client.responses(1)[&1],
+            ("200".to_string(), b"looped".to_vec())
+        );
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+}
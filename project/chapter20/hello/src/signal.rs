@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+/// 受け取れるシグナル。
+///
+/// The process signals the server reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGINT, e.g. Ctrl-C.
+    Interrupt,
+    /// SIGTERM.
+    Terminate,
+    /// SIGHUP.
+    Hangup,
+}
+
+const SIGNALS: [(Signal, libc::c_int); 3] = [
+    (Signal::Interrupt, libc::SIGINT),
+    (Signal::Terminate, libc::SIGTERM),
+    (Signal::Hangup, libc::SIGHUP),
+];
+
+// シグナルハンドラーの中ではフラグを立てるだけにします
+static PENDING: [AtomicBool; 3] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+type Subscriber = (Vec<Signal>, mpsc::Sender<Signal>);
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+static WATCHER: Once = Once::new();
+
+extern "C" fn on_signal(signum: libc::c_int) {
+    for (i, (_, n)) in SIGNALS.iter().enumerate() {
+        if *n == signum {
+            PENDING[i].store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// 指定したシグナルを受け取ったときに値が届くチャンネルを返します。
+///
+/// Installs handlers for `signals` and returns a channel that receives each
+/// one as it arrives.
+///
+/// The handler itself only sets a flag; a background thread notices the flag
+/// and forwards the signal, so receivers run in normal thread context.
+pub fn listen(signals: &[Signal]) -> mpsc::Receiver<Signal> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push((signals.to_vec(), sender));
+
+    for (signal, signum) in SIGNALS {
+        if signals.contains(&signal) {
+            // SAFETY: `on_signal` only touches atomics, which is
+            // async-signal-safe.
+            unsafe {
+                libc::signal(signum, on_signal as *const () as libc::sighandler_t);
+            }
+        }
+    }
+
+    WATCHER.call_once(|| {
+        thread::spawn(watch);
+    });
+
+    receiver
+}
+
+fn watch() {
+    loop {
+        for (i, (signal, _)) in SIGNALS.iter().enumerate() {
+            if PENDING[i].swap(false, Ordering::SeqCst) {
+                let mut subscribers = SUBSCRIBERS.lock().unwrap();
+                subscribers.retain(|(wanted, sender)| {
+                    !wanted.contains(signal) || sender.send(*signal).is_ok()
+                });
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
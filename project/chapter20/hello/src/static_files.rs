@@ -0,0 +1,728 @@
+//! ディレクトリの中のファイルを返すハンドラー。
+//!
+//! Serves the files under a root directory.
+
+use crate::date::DateTime;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use crate::router::Params;
+use std::collections::VecDeque;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// これより多い範囲を求めるRangeは無視して、ファイル全体を返します
+const MAX_RANGES: usize = 32;
+
+/// ルートディレクトリ以下のファイルを返すハンドラー。
+///
+/// リクエストのパスをルートからの相対パスとして扱います。`..`を含むパスと、
+/// シンボリックリンクでルートの外を指すパスは拒否します。
+///
+/// Serves files under a root directory, mapping the request path to a path
+/// relative to the root.
+///
+/// Paths with `..` segments are rejected, and so are paths that resolve
+/// outside the root through a symbolic link. A directory is answered with its
+/// index file if it has one, otherwise with a listing if listings are
+/// enabled.
+///
+/// Files are streamed from disk with `ETag` and `Last-Modified` validators.
+/// Conditional requests (`If-None-Match`, `If-Modified-Since`) are answered
+/// with 304, and `Range` requests with 206, using `multipart/byteranges` for
+/// several ranges.
+///
+/// ```no_run
+/// use hello::router::Router;
+/// use hello::static_files::StaticFiles;
+///
+/// let files = StaticFiles::new("public").unwrap().with_listing(true);
+/// let router = Router::new().get("/*path", files.handler("path"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    listing: bool,
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`, which must be a directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            index: Some("index.html".to_string()),
+            listing: false,
+        })
+    }
+
+    /// The file served for a directory; `index.html` by default.
+    pub fn with_index(mut self, index: Option<&str>) -> StaticFiles {
+        self.index = index.map(str::to_string);
+        self
+    }
+
+    /// Whether directories without an index file are answered with a
+    /// listing of their entries. Off by default.
+    pub fn with_listing(mut self, listing: bool) -> StaticFiles {
+        self.listing = listing;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns a route handler that serves the path captured by the
+    /// wildcard segment `param`.
+    pub fn handler(
+        self,
+        param: &str,
+    ) -> impl Fn(&Request, &Params) -> Response + Send + Sync + 'static {
+        let param = param.to_string();
+        move |request, params| self.serve(request, params.get(&param).unwrap_or(""))
+    }
+
+    /// Answers `request` with the file at `path`, relative to the root.
+    /// `path` is already percent-decoded.
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let file = match self.resolve(path) {
+            Ok(file) => file,
+            Err(response) => return response,
+        };
+
+        if file.is_dir() {
+            // 一覧の相対リンクが正しく解決されるよう、末尾に`/`を付けさせます
+            if !request.path().ends_with('/') {
+                // `//host`のような転送先は別のホストを指すので、先頭の`/`は1つにします
+                let path = request.path().trim_start_matches('/');
+                return Response::new(301)
+                    .with_header("Location", &format!("/{}/", path))
+                    .with_body("Moved Permanently");
+            }
+            if let Some(index) = &self.index {
+                let index = file.join(index);
+                if index.is_file() {
+                    return file_response(request, &index);
+                }
+            }
+            if self.listing {
+                return listing_response(&file, request.path(), file == self.root);
+            }
+            return not_found();
+        }
+
+        file_response(request, &file)
+    }
+
+    /// Maps `path` to a file under the root, or to the error response.
+    fn resolve(&self, path: &str) -> Result<PathBuf, Response> {
+        let mut relative = PathBuf::new();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(forbidden()),
+                // 区切り文字やドライブ名として解釈される名前も拒否します
+                s if s.contains(['\\', '\0']) => return Err(forbidden()),
+                s => {
+                    if !matches!(Path::new(s).components().next(), Some(Component::Normal(_))) {
+                        return Err(forbidden());
+                    }
+                    relative.push(s);
+                }
+            }
+        }
+
+        let file = match fs::canonicalize(self.root.join(relative)) {
+            Ok(file) => file,
+            Err(e) => return Err(error_response(&e)),
+        };
+        // シンボリックリンクを解決した結果がルートの外なら拒否します
+        if !file.starts_with(&self.root) {
+            return Err(forbidden());
+        }
+        Ok(file)
+    }
+}
+
+fn file_response(request: &Request, path: &Path) -> Response {
+    let opened = File::open(path).and_then(|file| {
+        let meta = file.metadata()?;
+        Ok((file, meta))
+    });
+    let (mut file, meta) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return error_response(&e),
+    };
+    let len = meta.len();
+    let etag = entity_tag(&meta);
+    // HTTPの日付は秒単位なので、比較も秒単位で行います
+    let modified = meta.modified().ok().map(DateTime::from_system_time);
+    let content_type = mime_type(path);
+
+    let mut response = if is_not_modified(request, &etag, modified.as_ref()) {
+        Response::new(304)
+    } else {
+        match requested_ranges(request, &etag, modified.as_ref(), len) {
+            None => Response::new(200)
+                .with_header("Content-Type", content_type)
+                .with_body(Body::from_reader(file, len)),
+            Some(ranges) if ranges.is_empty() => Response::new(416)
+                .with_header("Content-Range", &format!("bytes */{}", len))
+                .with_body("Range Not Satisfiable"),
+            Some(ranges) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                    return error_response(&e);
+                }
+                Response::new(206)
+                    .with_header("Content-Type", content_type)
+                    .with_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len))
+                    .with_body(Body::from_reader(file, end - start + 1))
+            }
+            Some(ranges) => {
+                let (boundary, body) = multipart_body(file, &ranges, len, content_type);
+                Response::new(206)
+                    .with_header(
+                        "Content-Type",
+                        &format!("multipart/byteranges; boundary={}", boundary),
+                    )
+                    .with_body(body)
+            }
+        }
+    };
+
+    response.headers.insert("ETag", &etag);
+    if let Some(modified) = &modified {
+        response
+            .headers
+            .insert("Last-Modified", &modified.http_date().to_string());
+    }
+    if response.status != 304 {
+        response.headers.insert("Accept-Ranges", "bytes");
+    }
+    response
+}
+
+/// A strong validator made of the file's length and modification time.
+fn entity_tag(meta: &Metadata) -> String {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "\"{:x}-{:x}.{:x}\"",
+        meta.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// `If-None-Match`, or without it `If-Modified-Since`, says the client's
+/// copy is current.
+fn is_not_modified(request: &Request, etag: &str, modified: Option<&DateTime>) -> bool {
+    if request.method != Method::Get && request.method != Method::Head {
+        return false;
+    }
+    if let Some(tags) = request.headers.get("if-none-match") {
+        // 304の判定は弱い比較で行います
+        let etag = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    match (request.headers.get("if-modified-since"), modified) {
+        (Some(since), Some(modified)) => {
+            DateTime::parse_http_date(since).is_some_and(|since| modified.unix() <= since.unix())
+        }
+        _ => false,
+    }
+}
+
+/// Returns the inclusive byte ranges asked for by `Range`, or `None` to
+/// send the whole file. An empty list means no range can be satisfied.
+fn requested_ranges(
+    request: &Request,
+    etag: &str,
+    modified: Option<&DateTime>,
+    len: u64,
+) -> Option<Vec<(u64, u64)>> {
+    if request.method != Method::Get {
+        return None;
+    }
+    let range = request.headers.get("range")?;
+
+    // If-Rangeが今のファイルと一致しなければ、ファイル全体を返します
+    if let Some(if_range) = request.headers.get("if-range") {
+        let current = if if_range.starts_with('"') {
+            if_range == etag
+        } else {
+            match (DateTime::parse_http_date(if_range), modified) {
+                (Some(date), Some(modified)) => date == *modified,
+                _ => false,
+            }
+        };
+        if !current {
+            return None;
+        }
+    }
+
+    parse_ranges(range, len)
+}
+
+/// Parses a `Range` header such as `bytes=0-99, 200-, -50` against a file
+/// of `len` bytes. Returns `None` if the header is malformed.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+            }
+            (start, "") => {
+                let start: u64 = start.parse().ok()?;
+                (start < len).then(|| (start, len - 1))
+            }
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end: u64 = end.parse().ok()?;
+                if start > end {
+                    return None;
+                }
+                (start < len).then(|| (start, end.min(len - 1)))
+            }
+        };
+        ranges.extend(range);
+        if ranges.len() > MAX_RANGES {
+            return None;
+        }
+    }
+    Some(ranges)
+}
+
+/// Builds a `multipart/byteranges` body that reads each range from `file`
+/// as it is written.
+fn multipart_body(
+    file: File,
+    ranges: &[(u64, u64)],
+    len: u64,
+    content_type: &str,
+) -> (String, Body) {
+    static NEXT_BOUNDARY: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let boundary = format!(
+        "hello-{:08x}{:08x}",
+        nanos,
+        NEXT_BOUNDARY.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let mut parts = VecDeque::new();
+    for &(start, end) in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, len
+        );
+        parts.push_back(Part::Bytes(io::Cursor::new(head.into_bytes())));
+        parts.push_back(Part::Range {
+            start,
+            remaining: end - start + 1,
+        });
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    parts.push_back(Part::Bytes(io::Cursor::new(tail.into_bytes())));
+
+    let total = parts
+        .iter()
+        .map(|part| match part {
+            Part::Bytes(bytes) => bytes.get_ref().len() as u64,
+            Part::Range { remaining, .. } => *remaining,
+        })
+        .sum();
+    (
+        boundary,
+        Body::from_reader(Multipart { file, parts }, total),
+    )
+}
+
+enum Part {
+    Bytes(io::Cursor<Vec<u8>>),
+    Range { start: u64, remaining: u64 },
+}
+
+/// Reads the parts of a `multipart/byteranges` body in order.
+struct Multipart {
+    file: File,
+    parts: VecDeque<Part>,
+}
+
+impl Read for Multipart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            match part {
+                Part::Bytes(bytes) => {
+                    let n = bytes.read(buf)?;
+                    if n > 0 {
+                        return Ok(n);
+                    }
+                }
+                Part::Range { start, remaining } if *remaining > 0 => {
+                    self.file.seek(SeekFrom::Start(*start))?;
+                    let max = (*remaining).min(buf.len() as u64) as usize;
+                    let n = self.file.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *start += n as u64;
+                    *remaining -= n as u64;
+                    return Ok(n);
+                }
+                Part::Range { .. } => {}
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+fn listing_response(dir: &Path, request_path: &str, is_root: bool) -> Response {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return error_response(&e),
+    };
+    // ディレクトリを先に、それぞれ名前順に並べます
+    let mut names: Vec<(bool, String)> = entries
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            (!is_dir, entry.file_name().to_string_lossy().into_owned())
+        })
+        .collect();
+    names.sort();
+
+    let title = html_escape(request_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+         <body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if !is_root {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in names {
+        let slash = if is_file { "" } else { "/" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode(&name),
+            slash,
+            html_escape(&name),
+            slash
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Response::new(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(html)
+}
+
+fn error_response(e: &io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => not_found(),
+        io::ErrorKind::PermissionDenied => forbidden(),
+        _ => {
+            error!("Failed to read a static file: {}", e);
+            Response::new(500).with_body("Internal Server Error")
+        }
+    }
+}
+
+fn not_found() -> Response {
+    Response::new(404).with_body("Not Found")
+}
+
+fn forbidden() -> Response {
+    Response::new(403).with_body("Forbidden")
+}
+
+/// 拡張子から`Content-Type`を推測します。
+///
+/// Guesses a `Content-Type` from the file extension, falling back to
+/// `application/octet-stream`.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestReader;
+    use crate::router::Router;
+    use crate::testing::TempDir;
+
+    fn get(router: &Router, target: &str) -> Response {
+        get_with(router, target, &[])
+    }
+
+    fn get_with(router: &Router, target: &str, headers: &[(&str, &str)]) -> Response {
+        let mut raw = format!("GET {} HTTP/1.1\r\nHost: test\r\n", target);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        let request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        router.handle(&request)
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        response.body.into_bytes().unwrap()
+    }
+
+    fn site() -> (TempDir, Router) {
+        let dir = TempDir::new();
+        let public = dir.path().join("public");
+        fs::create_dir_all(public.join("docs")).unwrap();
+        fs::write(public.join("style.css"), "body {}").unwrap();
+        let binary: Vec<u8> = (0..=255).collect();
+        fs::write(public.join("data.bin"), binary).unwrap();
+        fs::write(public.join("docs/a <b>.txt"), "text").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+
+        let files = StaticFiles::new(&public).unwrap().with_listing(true);
+        let router = Router::new().get("/*path", files.handler("path"));
+        (dir, router)
+    }
+
+    #[test]
+    fn serves_files_with_type_and_length() {
+        let (_dir, router) = site();
+        let response = get(&router, "/style.css");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.body.len(), Some(7));
+        assert_eq!(body(response), b"body {}");
+
+        assert_eq!(body(get(&router, "/docs/a%20%3Cb%3E.txt")), b"text");
+        assert_eq!(get(&router, "/missing.txt").status, 404);
+        assert_eq!(get(&router, "/style.css/x").status, 404);
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_root() {
+        let (_dir, router) = site();
+        assert_eq!(get(&router, "/../secret.txt").status, 403);
+        assert_eq!(get(&router, "/docs/%2e%2e/%2e%2e/secret.txt").status, 403);
+        assert_eq!(get(&router, "/docs%2f..%2f..%2fsecret.txt").status, 403);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_the_root() {
+        let (dir, router) = site();
+        let public = dir.path().join("public");
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), public.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(public.join("style.css"), public.join("inside.css")).unwrap();
+
+        assert_eq!(get(&router, "/link.txt").status, 403);
+        assert_eq!(get(&router, "/inside.css").status, 200);
+    }
+
+    #[test]
+    fn lists_directories() {
+        let (_dir, router) = site();
+        let response = get(&router, "/docs");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.headers.get("location"), Some("/docs/"));
+        let response = get(&router, "//docs");
+        assert_eq!(response.headers.get("location"), Some("/docs/"));
+
+        let response = get(&router, "/docs/");
+        let html = String::from_utf8(body(response)).unwrap();
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+
+        let html = String::from_utf8(body(get(&router, "/"))).unwrap();
+        assert!(html.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(!html.contains("../"));
+    }
+
+    #[test]
+    fn answers_conditional_requests_with_304() {
+        let (_dir, router) = site();
+        let response = get(&router, "/style.css");
+        let etag = response.headers.get("etag").unwrap().to_string();
+        let modified = response.headers.get("last-modified").unwrap().to_string();
+        assert!(DateTime::parse_http_date(&modified).is_some());
+
+        let response = get_with(&router, "/style.css", &[("If-None-Match", &etag)]);
+        assert_eq!(response.status, 304);
+        assert_eq!(response.headers.get("etag"), Some(etag.as_str()));
+        assert!(response.body.is_empty());
+
+        let response = get_with(&router, "/style.css", &[("If-Modified-Since", &modified)]);
+        assert_eq!(response.status, 304);
+
+        let response = get_with(
+            &router,
+            "/style.css",
+            &[
+                ("If-None-Match", "\"other\""),
+                ("If-Modified-Since", &modified),
+            ],
+        );
+        assert_eq!(response.status, 200);
+        let response = get_with(
+            &router,
+            "/style.css",
+            &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")],
+        );
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn serves_single_byte_ranges() {
+        let (_dir, router) = site();
+        let response = get_with(&router, "/data.bin", &[("Range", "bytes=10-19")]);
+        assert_eq!(response.status, 206);
+        assert_eq!(
+            response.headers.get("content-range"),
+            Some("bytes 10-19/256")
+        );
+        assert_eq!(response.body.len(), Some(10));
+        assert_eq!(body(response), (10..20).collect::<Vec<u8>>());
+
+        let response = get_with(&router, "/data.bin", &[("Range", "bytes=-6")]);
+        assert_eq!(
+            response.headers.get("content-range"),
+            Some("bytes 250-255/256")
+        );
+        assert_eq!(body(response), (250..=255).collect::<Vec<u8>>());
+
+        let response = get_with(&router, "/data.bin", &[("Range", "bytes=300-")]);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("content-range"), Some("bytes */256"));
+
+        // 壊れたRangeや古いIf-Rangeは無視して、全体を返します
+        let response = get_with(&router, "/data.bin", &[("Range", "bytes=9-1")]);
+        assert_eq!(response.status, 200);
+        let response = get_with(
+            &router,
+            "/data.bin",
+            &[("Range", "bytes=0-1"), ("If-Range", "\"stale\"")],
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(body(response).len(), 256);
+    }
+
+    #[test]
+    fn serves_multipart_byte_ranges() {
+        let (_dir, router) = site();
+        let response = get_with(&router, "/data.bin", &[("Range", "bytes=0-1, 254-")]);
+        assert_eq!(response.status, 206);
+        let content_type = response.headers.get("content-type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let length = response.body.len().unwrap() as usize;
+
+        let body = body(response);
+        assert_eq!(body.len(), length);
+        let mut expected = Vec::new();
+        for (range, bytes) in [("0-1", [0u8, 1]), ("254-255", [254, 255])] {
+            expected.extend(
+                format!(
+                    "\r\n--{}\r\nContent-Type: application/octet-stream\r\n\
+                     Content-Range: bytes {}/256\r\n\r\n",
+                    boundary, range
+                )
+                .into_bytes(),
+            );
+            expected.extend(bytes);
+        }
+        expected.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+        assert_eq!(body, expected);
+    }
+}
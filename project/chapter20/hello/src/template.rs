@@ -0,0 +1,800 @@
+//! HTMLを組み立てるための小さなテンプレートエンジン。
+//!
+//! A small template engine for HTML responses.
+//!
+//! ```text
+//! <h1>{{ title }}</h1>
+//! {# comments are dropped #}
+//! {% if user %}<p>Hello, {{ user.name }}!</p>{% else %}<p>Hello!</p>{% endif %}
+//! <ul>
+//! {% for item in items %}  <li>{{ loop.index }}. {{ item }}</li>
+//! {% endfor %}</ul>
+//! {{ footer | raw }}
+//! {% include "footer.html" %}
+//! ```
+//!
+//! `{{ name }}` prints a value with HTML special characters escaped, unless
+//! followed by `| raw`. Names may reach into maps with dots. `{% if %}`
+//! takes a name, optionally after `not`, and is true for non-empty strings,
+//! lists and maps, non-zero numbers and `true`; undefined names are false.
+//! `{% for %}` also defines `loop.index` (from 1), `loop.first` and
+//! `loop.last`. `{% include %}` renders another template of the same
+//! `Templates` with the same values.
+
+use crate::lock;
+use crate::response::Response;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// 自分自身を取り込むテンプレートで止まらないよう、取り込みの深さを制限します
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// テンプレートに渡す値。
+///
+/// A value a template can print, test or loop over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Str(s) => !s.is_empty(),
+            Value::Int(n) => *n != 0,
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(name),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context.vars)
+    }
+}
+
+/// テンプレートに渡す、名前の付いた値の集まり。
+///
+/// The named values a template is rendered with. A `Context` also makes a
+/// map value for nesting, e.g. `user.name`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    vars: BTreeMap<String, Value>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    pub fn with<V: Into<Value>>(mut self, name: &str, value: V) -> Context {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.vars.insert(name.to_string(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+}
+
+/// テンプレートを読み込めなかったか、描画できなかった理由。
+///
+/// Why a template could not be loaded or rendered.
+#[derive(Debug)]
+pub enum TemplateError {
+    /// The template file could not be read.
+    Io(String, io::Error),
+    /// The template is malformed.
+    Syntax {
+        name: String,
+        line: usize,
+        message: String,
+    },
+    /// The template used a value it cannot print or loop over, or an
+    /// include failed.
+    Render {
+        name: String,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Io(name, e) => write!(f, "cannot read template {}: {}", name, e),
+            TemplateError::Syntax {
+                name,
+                line,
+                message,
+            } => write!(f, "syntax error in {} line {}: {}", name, line, message),
+            TemplateError::Render {
+                name,
+                line,
+                message,
+            } => write!(f, "cannot render {} line {}: {}", name, line, message),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TemplateError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Print {
+        path: Vec<String>,
+        raw: bool,
+        line: usize,
+    },
+    If {
+        path: Vec<String>,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+        line: usize,
+    },
+    Include {
+        name: String,
+        line: usize,
+    },
+}
+
+/// 解析済みのテンプレート。
+///
+/// A parsed template.
+#[derive(Debug)]
+pub struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    /// Parses `source`; `name` is used in error messages and to resolve
+    /// includes.
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            name,
+            tokens: tokenize(name, source)?.into_iter(),
+        };
+        let (nodes, end) = parser.block()?;
+        if let Some((tag, line)) = end {
+            return Err(parser.syntax(line, format!("unexpected {{% {} %}}", tag)));
+        }
+        Ok(Template {
+            name: name.to_string(),
+            nodes,
+        })
+    }
+
+    /// Renders the template with `context`. Fails on includes, which need a
+    /// `Templates` to load from.
+    pub fn render(&self, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        self.render_into(&mut out, &mut Scope::new(context), None, 0)?;
+        Ok(out)
+    }
+
+    fn render_into(
+        &self,
+        out: &mut String,
+        scope: &mut Scope,
+        templates: Option<&Templates>,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        self.render_nodes(&self.nodes, out, scope, templates, depth)
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        out: &mut String,
+        scope: &mut Scope,
+        templates: Option<&Templates>,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Print { path, raw, line } => {
+                    let text = match scope.lookup(path) {
+                        Some(Value::Str(s)) => s.clone(),
+                        Some(Value::Int(n)) => n.to_string(),
+                        Some(Value::Bool(b)) => b.to_string(),
+                        Some(_) => {
+                            return Err(
+                                self.error(*line, format!("cannot print {}", path.join(".")))
+                            );
+                        }
+                        None => {
+                            return Err(
+                                self.error(*line, format!("{} is not defined", path.join(".")))
+                            );
+                        }
+                    };
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        escape_html(&text, out);
+                    }
+                }
+                Node::If {
+                    path,
+                    negate,
+                    then,
+                    otherwise,
+                } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render_nodes(branch, out, scope, templates, depth)?;
+                }
+                Node::For {
+                    var,
+                    path,
+                    body,
+                    line,
+                } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        None => Vec::new(),
+                        Some(_) => {
+                            return Err(
+                                self.error(*line, format!("{} is not a list", path.join(".")))
+                            );
+                        }
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let info = Context::new()
+                            .with("index", i + 1)
+                            .with("first", i == 0)
+                            .with("last", i + 1 == count);
+                        scope.push(vec![(var.clone(), item), ("loop".to_string(), info.into())]);
+                        let result = self.render_nodes(body, out, scope, templates, depth);
+                        scope.pop();
+                        result?;
+                    }
+                }
+                Node::Include { name, line } => {
+                    let Some(templates) = templates else {
+                        return Err(self.error(*line, "includes need a Templates directory"));
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(self.error(*line, "includes are nested too deeply"));
+                    }
+                    let included = templates
+                        .load(name)
+                        .map_err(|e| self.error(*line, e.to_string()))?;
+                    included.render_into(out, scope, Some(templates), depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        TemplateError::Render {
+            name: self.name.clone(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// The context plus the variables of the loops being rendered.
+struct Scope<'a> {
+    context: &'a Context,
+    frames: Vec<Vec<(String, Value)>>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(context: &'a Context) -> Scope<'a> {
+        Scope {
+            context,
+            frames: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, frame: Vec<(String, Value)>) {
+        self.frames.push(frame);
+    }
+
+    fn pop(&mut self) {
+        self.frames.pop();
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        // 内側のループの変数ほど優先します
+        let mut value = self
+            .frames
+            .iter()
+            .rev()
+            .flat_map(|frame| frame.iter())
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.get(first))?;
+        for name in rest {
+            value = value.field(name)?;
+        }
+        Some(value)
+    }
+}
+
+fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+enum Token {
+    Text(String),
+    /// The inside of `{{ }}`.
+    Print(String),
+    /// The inside of `{% %}`.
+    Tag(String),
+}
+
+/// Splits `source` into text and tags, each with the line it starts on.
+fn tokenize(name: &str, source: &str) -> Result<Vec<(Token, usize)>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+
+    while let Some(start) = rest.find('{') {
+        let close = match &rest[start..] {
+            s if s.starts_with("{{") => "}}",
+            s if s.starts_with("{%") => "%}",
+            s if s.starts_with("{#") => "#}",
+            _ => {
+                // ただの波括弧は、次の候補まで文字として扱います
+                let text = &rest[..start + 1];
+                push_text(&mut tokens, text, line);
+                line += text.matches('\n').count();
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        let text = &rest[..start];
+        push_text(&mut tokens, text, line);
+        line += text.matches('\n').count();
+
+        let inner_start = start + 2;
+        let Some(len) = rest[inner_start..].find(close) else {
+            return Err(TemplateError::Syntax {
+                name: name.to_string(),
+                line,
+                message: format!("missing {}", close),
+            });
+        };
+        let inner = &rest[inner_start..inner_start + len];
+        match close {
+            "}}" => tokens.push((Token::Print(inner.trim().to_string()), line)),
+            "%}" => tokens.push((Token::Tag(inner.trim().to_string()), line)),
+            _ => {}
+        }
+        line += inner.matches('\n').count();
+        rest = &rest[inner_start + len + 2..];
+    }
+    push_text(&mut tokens, rest, line);
+    Ok(tokens)
+}
+
+fn push_text(tokens: &mut Vec<(Token, usize)>, text: &str, line: usize) {
+    if text.is_empty() {
+        return;
+    }
+    // 続く文字は1つのノードにまとめます
+    if let Some((Token::Text(previous), _)) = tokens.last_mut() {
+        previous.push_str(text);
+    } else {
+        tokens.push((Token::Text(text.to_string()), line));
+    }
+}
+
+/// The closing tag a block stopped at, with its line.
+type End = Option<(String, usize)>;
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: std::vec::IntoIter<(Token, usize)>,
+}
+
+impl Parser<'_> {
+    /// Parses nodes up to the end of the input or a closing tag (`else`,
+    /// `endif` or `endfor`), which is returned with its line.
+    fn block(&mut self) -> Result<(Vec<Node>, End), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some((token, line)) = self.tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text)),
+                Token::Print(inner) => nodes.push(self.print(&inner, line)?),
+                Token::Tag(inner) => {
+                    let words: Vec<&str> = inner.split_whitespace().collect();
+                    match words.as_slice() {
+                        ["else"] | ["endif"] | ["endfor"] => {
+                            return Ok((nodes, Some((words[0].to_string(), line))));
+                        }
+                        ["if", cond @ ..] => nodes.push(self.if_block(cond, line)?),
+                        ["for", var, "in", path] => nodes.push(self.for_block(var, path, line)?),
+                        ["include", name] => nodes.push(Node::Include {
+                            name: self.string(name, line)?,
+                            line,
+                        }),
+                        _ => {
+                            return Err(self.syntax(line, format!("unknown tag {{% {} %}}", inner)));
+                        }
+                    }
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    fn print(&self, inner: &str, line: usize) -> Result<Node, TemplateError> {
+        let (expr, raw) = match inner.split_once('|') {
+            Some((expr, filter)) if filter.trim() == "raw" => (expr, true),
+            Some((_, filter)) => {
+                return Err(self.syntax(line, format!("unknown filter {}", filter.trim())));
+            }
+            None => (inner, false),
+        };
+        Ok(Node::Print {
+            path: self.path(expr.trim(), line)?,
+            raw,
+            line,
+        })
+    }
+
+    fn if_block(&mut self, cond: &[&str], line: usize) -> Result<Node, TemplateError> {
+        let (negate, path) = match cond {
+            ["not", path] => (true, *path),
+            [path] => (false, *path),
+            _ => return Err(self.syntax(line, "expected {% if [not] name %}")),
+        };
+        let path = self.path(path, line)?;
+        let (then, end) = self.block()?;
+        let otherwise = match end {
+            Some((tag, _)) if tag == "endif" => Vec::new(),
+            Some((tag, else_line)) if tag == "else" => match self.block()? {
+                (otherwise, Some((tag, _))) if tag == "endif" => otherwise,
+                _ => return Err(self.syntax(else_line, "{% else %} without {% endif %}")),
+            },
+            _ => return Err(self.syntax(line, "{% if %} without {% endif %}")),
+        };
+        Ok(Node::If {
+            path,
+            negate,
+            then,
+            otherwise,
+        })
+    }
+
+    fn for_block(&mut self, var: &str, path: &str, line: usize) -> Result<Node, TemplateError> {
+        let path = self.path(path, line)?;
+        if !is_name(var) || var == "loop" {
+            return Err(self.syntax(line, format!("invalid loop variable {}", var)));
+        }
+        match self.block()? {
+            (body, Some((tag, _))) if tag == "endfor" => Ok(Node::For {
+                var: var.to_string(),
+                path,
+                body,
+                line,
+            }),
+            _ => Err(self.syntax(line, "{% for %} without {% endfor %}")),
+        }
+    }
+
+    fn path(&self, expr: &str, line: usize) -> Result<Vec<String>, TemplateError> {
+        let path: Vec<String> = expr.split('.').map(str::to_string).collect();
+        if path.iter().all(|name| is_name(name)) {
+            Ok(path)
+        } else {
+            Err(self.syntax(line, format!("invalid name {:?}", expr)))
+        }
+    }
+
+    fn string(&self, literal: &str, line: usize) -> Result<String, TemplateError> {
+        literal
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| self.syntax(line, "expected a quoted template name"))
+    }
+
+    fn syntax(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        TemplateError::Syntax {
+            name: self.name.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// ディレクトリから読み込んだテンプレート。
+///
+/// Templates loaded from a directory by name, e.g. `errors/404.html`.
+///
+/// Each template is parsed the first time it is used and kept; later uses
+/// check the file's modification time and parse it again if it changed, so
+/// edits show up without a restart.
+#[derive(Debug)]
+pub struct Templates {
+    dir: PathBuf,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+impl Templates {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Templates {
+        Templates {
+            dir: dir.into(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Renders the template `name` with `context`.
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        self.load(name)?
+            .render_into(&mut out, &mut Scope::new(context), Some(self), 0)?;
+        Ok(out)
+    }
+
+    /// Renders the template `name` into an HTML response with `status`,
+    /// or logs why it could not and answers 500.
+    pub fn response(&self, status: u16, name: &str, context: &Context) -> Response {
+        match self.render(name, context) {
+            Ok(html) => Response::new(status)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(html),
+            Err(e) => {
+                error!("{}", e);
+                Response::new(500).with_body("Internal Server Error")
+            }
+        }
+    }
+
+    /// Returns the template `name`, parsing it again if its file changed.
+    fn load(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let path = self.path(name)?;
+        let modified = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map_err(|e| TemplateError::Io(name.to_string(), e))?;
+
+        if let Some(cached) = lock(&self.cache).get(name)
+            && cached.modified == Some(modified)
+        {
+            return Ok(Arc::clone(&cached.template));
+        }
+
+        // 解析している間は、他のテンプレートを待たせないようロックを外しておきます
+        let source =
+            fs::read_to_string(&path).map_err(|e| TemplateError::Io(name.to_string(), e))?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        debug!("Loaded template {}.", name);
+        lock(&self.cache).insert(
+            name.to_string(),
+            Cached {
+                template: Arc::clone(&template),
+                modified: Some(modified),
+            },
+        );
+        Ok(template)
+    }
+
+    /// The file of the template `name`, which must stay inside the
+    /// directory.
+    fn path(&self, name: &str) -> Result<PathBuf, TemplateError> {
+        let relative = Path::new(name);
+        if relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            Ok(self.dir.join(relative))
+        } else {
+            Err(TemplateError::Io(
+                name.to_string(),
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid template name"),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::thread;
+    use std::time::Duration;
+
+    fn render(source: &str, context: &Context) -> String {
+        Template::parse("test", source)
+            .unwrap()
+            .render(context)
+            .unwrap()
+    }
+
+    #[test]
+    fn interpolates_and_escapes() {
+        let context = Context::new()
+            .with("name", "<b>Tom & \"Jerry\"</b>")
+            .with("count", 3usize)
+            .with("user", Context::new().with("id", 7i64));
+        assert_eq!(
+            render("Hi {{ name }}! {{count}} {{ user.id }} {", &context),
+            "Hi &lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;! 3 7 {"
+        );
+        assert_eq!(
+            render("{{ name | raw }}{# note #}", &context),
+            "<b>Tom & \"Jerry\"</b>"
+        );
+    }
+
+    #[test]
+    fn renders_conditionals_and_loops() {
+        let context = Context::new()
+            .with("items", vec!["a", "<b>"])
+            .with("empty", Vec::<String>::new())
+            .with("admin", false);
+        let source = "{% for item in items %}{{ loop.index }}:{{ item }}\
+                      {% if not loop.last %},{% endif %}{% endfor %}\
+                      {% if empty %}full{% else %} none{% endif %}\
+                      {% if admin %} admin{% endif %}{% if missing %}?{% endif %}";
+        assert_eq!(render(source, &context), "1:a,2:&lt;b&gt; none");
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = |source: &str| match Template::parse("page.html", source) {
+            Err(TemplateError::Syntax { line, message, .. }) => (line, message),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(error("a\n{% if x %}b").0, 2);
+        assert_eq!(error("{{ x").1, "missing }}");
+        assert_eq!(error("\n\n{% endfor %}").0, 3);
+        assert!(error("{% while x %}").1.starts_with("unknown tag"));
+        assert!(error("{{ x | upper }}").1.starts_with("unknown filter"));
+
+        let template = Template::parse("page.html", "ok\n{{ missing }}").unwrap();
+        let e = template.render(&Context::new()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "cannot render page.html line 2: missing is not defined"
+        );
+    }
+
+    #[test]
+    fn loads_includes_and_reloads_changed_files() {
+        let temp = TempDir::new();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(
+            dir.join("page.html"),
+            "<p>{{ path }}</p>{% include \"parts/footer.html\" %}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("parts/footer.html"),
+            "<footer>{{ server }}</footer>",
+        )
+        .unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+
+        let templates = Templates::new(dir);
+        let context = Context::new().with("path", "/a").with("server", "hello");
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<p>/a</p><footer>hello</footer>"
+        );
+
+        // 更新時刻が変わったことが分かるよう、少し待ってから書き換えます
+        thread::sleep(Duration::from_millis(20));
+        fs::write(dir.join("parts/footer.html"), "<footer>v2</footer>").unwrap();
+        let file = fs::File::options()
+            .write(true)
+            .open(dir.join("parts/footer.html"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<p>/a</p><footer>v2</footer>"
+        );
+
+        assert!(templates.render("loop.html", &context).is_err());
+        assert!(templates.render("../page.html", &context).is_err());
+        let response = templates.response(200, "missing.html", &context);
+        assert_eq!(response.status, 500);
+    }
+}
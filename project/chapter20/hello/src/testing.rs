@@ -0,0 +1,425 @@
+//! サーバーを動かして試すための道具。
+//!
+//! Helpers for tests that run a `Server` in-process: `TestServer` starts one
+//! on an ephemeral port, `Client` is a minimal blocking HTTP/1.1 client, and
+//! `TestResponse` has assertions that print the response when they fail.
+//!
+//! ```
+//! use hello::response::Response;
+//! use hello::router::Router;
+//! use hello::testing::TestServer;
+//!
+//! let server = TestServer::start(Router::new().get("/", |_, _| Response::new(200).with_body("hi")));
+//! server
+//!     .get("/")
+//!     .assert_status(200)
+//!     .assert_body("hi");
+//! server.shutdown().unwrap();
+//! ```
+
+use crate::request::Headers;
+use crate::router::Router;
+use crate::server::{Server, ShutdownHandle};
+use crate::{ShutdownTimeout, ThreadPool};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// テストが止まったままにならないよう、読み書きはこの時間で諦めます
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 空いているポートで動かしたテスト用のサーバー。
+///
+/// A `Server` running on its own thread, listening on an ephemeral port of
+/// 127.0.0.1. It is shut down when dropped, or with `shutdown` to see
+/// whether it stopped cleanly.
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: ShutdownHandle,
+    running: Option<thread::JoinHandle<Result<(), ShutdownTimeout>>>,
+}
+
+impl TestServer {
+    /// Serves `router` with a pool of four workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no port can be bound.
+    pub fn start(router: Router) -> TestServer {
+        TestServer::run(Server::new(listener(), ThreadPool::new(4), router))
+    }
+
+    /// Runs `server`, e.g. one built on `listener()` with other settings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server's address cannot be read.
+    pub fn run(server: Server) -> TestServer {
+        let mut addr = server.local_addr().expect("the server has no address");
+        if addr.ip().is_unspecified() {
+            addr.set_ip([127, 0, 0, 1].into());
+        }
+        let handle = server
+            .shutdown_handle()
+            .expect("cannot set up the server's shutdown");
+        let running = thread::spawn(move || server.run());
+        TestServer {
+            addr,
+            handle,
+            running: Some(running),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Opens a new connection to the server.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server cannot be reached.
+    pub fn client(&self) -> Client {
+        Client::connect(self.addr).expect("cannot connect to the test server")
+    }
+
+    /// Sends `GET path` over a new connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the request fails.
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.client()
+            .request("GET", path, &[("Connection", "close")], b"")
+            .unwrap_or_else(|e| panic!("GET {} failed: {}", path, e))
+    }
+
+    /// Stops the server and waits for `Server::run` to return.
+    pub fn shutdown(mut self) -> Result<(), ShutdownTimeout> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), ShutdownTimeout> {
+        self.handle.shutdown();
+        match self.running.take().map(thread::JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(payload)) => std::panic::resume_unwind(payload),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // テストが失敗して巻き戻っている最中に、もう一度パニックしないようにします
+        if !thread::panicking() {
+            let _ = self.stop();
+        } else {
+            self.handle.shutdown();
+        }
+    }
+}
+
+/// Binds a listener to an ephemeral port of 127.0.0.1.
+///
+/// # Panics
+///
+/// Panics if no port can be bound.
+pub fn listener() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").expect("cannot bind an ephemeral port")
+}
+
+/// テスト用の、ブロックするHTTP/1.1クライアント。
+///
+/// A blocking HTTP/1.1 client over one connection. Requests are sent one at
+/// a time and each waits for its response; the connection is reused as long
+/// as the server keeps it open. Reads and writes time out after 10 seconds.
+pub struct Client {
+    stream: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(Client {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    pub fn get(&mut self, path: &str) -> io::Result<TestResponse> {
+        self.request("GET", path, &[], b"")
+    }
+
+    /// Sends a request with `headers` and `body`, adding `Host` and, for a
+    /// body, `Content-Length` unless `headers` has them.
+    pub fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<TestResponse> {
+        let has = |name: &str| headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+        let mut head = format!("{} {} HTTP/1.1\r\n", method, path);
+        if !has("host") {
+            head.push_str("Host: localhost\r\n");
+        }
+        if !body.is_empty() && !has("content-length") && !has("transfer-encoding") {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        let mut request = head.into_bytes();
+        request.extend_from_slice(body);
+        self.send(&request)?;
+        self.read_response(method == "HEAD")
+    }
+
+    /// Sends `bytes` as they are, e.g. a malformed request, and reads one
+    /// response.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> io::Result<TestResponse> {
+        self.send(bytes)?;
+        self.read_response(false)
+    }
+
+    /// Writes `bytes` without waiting for a response.
+    pub fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(bytes)?;
+        stream.flush()
+    }
+
+    /// Reads the next response. `head` says it answers a `HEAD` request,
+    /// so has no body.
+    pub fn read_response(&mut self, head: bool) -> io::Result<TestResponse> {
+        let status_line = self.read_line()?;
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid(format!("bad status line {:?}", status_line)))?;
+
+        let mut headers = Headers::new();
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("bad header line {:?}", line)))?;
+            headers.append(name.trim(), value.trim());
+        }
+
+        let mut body = Vec::new();
+        if head || status / 100 == 1 || status == 204 || status == 304 {
+            // 本文はありません
+        } else if headers.contains_token("transfer-encoding", "chunked") {
+            body = self.read_chunked()?;
+        } else if let Some(len) = headers.get("content-length") {
+            let len: usize = len
+                .parse()
+                .map_err(|_| invalid(format!("bad Content-Length {:?}", len)))?;
+            body.resize(len, 0);
+            self.stream.read_exact(&mut body)?;
+        } else {
+            self.stream.read_to_end(&mut body)?;
+        }
+
+        Ok(TestResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn read_chunked(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| invalid(format!("bad chunk size {:?}", line)))?;
+            if size == 0 {
+                // トレーラーは読み捨てます
+                while !self.read_line()?.is_empty() {}
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            self.stream.read_exact(&mut body[start..])?;
+            if !self.read_line()?.is_empty() {
+                return Err(invalid("chunk without CRLF".to_string()));
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    /// Whether the server has closed the connection, waiting up to `timeout`
+    /// for it to do so.
+    pub fn is_closed(&mut self, timeout: Duration) -> bool {
+        if self
+            .stream
+            .get_ref()
+            .set_read_timeout(Some(timeout))
+            .is_err()
+        {
+            return true;
+        }
+        let mut buf = [0; 1];
+        let closed = match self.stream.read(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+        };
+        let _ = self.stream.get_ref().set_read_timeout(Some(IO_TIMEOUT));
+        closed
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// テストで受け取ったレスポンス。
+///
+/// A response read by `Client`. The `assert_*` methods panic with the whole
+/// response in the message, and return the response so they can be chained.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    #[track_caller]
+    pub fn assert_status(&self, status: u16) -> &TestResponse {
+        assert!(
+            self.status == status,
+            "expected status {}, got:\n{:?}",
+            status,
+            self
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, value: &str) -> &TestResponse {
+        assert!(
+            self.header(name) == Some(value),
+            "expected {}: {}, got:\n{:?}",
+            name,
+            value,
+            self
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_no_header(&self, name: &str) -> &TestResponse {
+        assert!(
+            !self.headers.contains(name),
+            "expected no {} header, got:\n{:?}",
+            name,
+            self
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body<B: AsRef<[u8]>>(&self, body: B) -> &TestResponse {
+        assert!(
+            self.body == body.as_ref(),
+            "expected body {:?}, got:\n{:?}",
+            String::from_utf8_lossy(body.as_ref()),
+            self
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body_contains(&self, text: &str) -> &TestResponse {
+        assert!(
+            self.text().contains(text),
+            "expected the body to contain {:?}, got:\n{:?}",
+            text,
+            self
+        );
+        self
+    }
+}
+
+impl fmt::Debug for TestResponse {
+    /// Shows the response roughly as it was sent, with the body as text.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.status)?;
+        for (name, value) in self.headers.iter() {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.text())
+    }
+}
+
+/// 削除されるまでの間だけ使う、一時ディレクトリ。
+///
+/// A fresh directory under the system temp dir for a unit test's files,
+/// removed with its contents on drop.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "hello-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,149 @@
+//! TLSの終端。
+//!
+//! Terminates TLS for HTTPS listeners with rustls, from a PEM certificate
+//! chain and private key.
+
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// TLSで暗号化された接続。
+///
+/// A connection whose traffic is decrypted and encrypted by rustls. The
+/// handshake happens on the first read or write.
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// TLSの設定を読み込めなかった理由。
+#[derive(Debug)]
+pub enum TlsError {
+    /// A file could not be read.
+    Io(PathBuf, io::Error),
+    /// A file is not valid PEM.
+    Pem(PathBuf, pem::Error),
+    /// The certificate file holds no certificates.
+    NoCertificates,
+    /// rustls rejected the certificate or key, e.g. because they do not
+    /// match.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            TlsError::Pem(path, e) => write!(f, "invalid PEM in {}: {}", path.display(), e),
+            TlsError::NoCertificates => f.write_str("no certificates found"),
+            TlsError::Rustls(e) => write!(f, "invalid certificate or key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Io(_, e) => Some(e),
+            TlsError::Pem(_, e) => Some(e),
+            TlsError::NoCertificates => None,
+            TlsError::Rustls(e) => Some(e),
+        }
+    }
+}
+
+/// HTTPSの接続を受け付けるための設定。
+///
+/// The certificate and key a TLS listener presents. Cheap to clone.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Loads a certificate chain and a private key from PEM files. The chain
+    /// starts with the server's own certificate; the key may be PKCS#8,
+    /// PKCS#1 or SEC1.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<TlsConfig, TlsError> {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        let read = |path: &Path| fs::read(path).map_err(|e| TlsError::Io(path.to_owned(), e));
+        let cert_pem = read(cert_path)?;
+        let key_pem = read(key_path)?;
+
+        let certs = CertificateDer::pem_slice_iter(&cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TlsError::Pem(cert_path.to_owned(), e))?;
+        let key = PrivateKeyDer::from_pem_slice(&key_pem)
+            .map_err(|e| TlsError::Pem(key_path.to_owned(), e))?;
+        TlsConfig::new(certs, key)
+    }
+
+    /// Builds a configuration from DER-encoded certificates and key.
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<TlsConfig, TlsError> {
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates);
+        }
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(TlsError::Rustls)?;
+        // ALPNでHTTP/1.1だけを話すと伝えます
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Wraps an accepted connection. No I/O happens until the stream is
+    /// first read or written.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection =
+            ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn loads_pem_files() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = TempDir::new();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        assert!(TlsConfig::from_pem_files(&cert, &key).is_ok());
+        assert!(matches!(
+            TlsConfig::from_pem_files(&key, &key),
+            Err(TlsError::NoCertificates)
+        ));
+        assert!(matches!(
+            TlsConfig::from_pem_files(&cert, &cert),
+            Err(TlsError::Pem(..))
+        ));
+        assert!(matches!(
+            TlsConfig::from_pem_files("/nonexistent/cert.pem", &key),
+            Err(TlsError::Io(..))
+        ));
+    }
+}
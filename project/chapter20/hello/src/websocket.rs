@@ -0,0 +1,839 @@
+//! WebSocket (RFC 6455)。
+//!
+//! WebSocket connections: the opening handshake, the framing, and a
+//! message-level API for sessions.
+//!
+//! A session is an ordinary function that receives a `WebSocket` once the
+//! server has answered the handshake with `101 Switching Protocols`. It runs
+//! on the `ThreadPool` worker that served the handshake, so a server should
+//! have enough workers for the sessions it expects to hold open. A client
+//! that sends nothing, not even a ping, for the server's keep-alive idle
+//! timeout is dropped: `recv` fails, and the worker is free again.
+//!
+//! ```no_run
+//! use hello::router::Router;
+//! use hello::websocket::{self, Message};
+//!
+//! // `/echo` sends every message back, and `/shout` answers text in upper case.
+//! let router = Router::new()
+//!     .get("/echo", websocket::handler(websocket::echo))
+//!     .get(
+//!         "/shout",
+//!         websocket::handler(|mut ws| {
+//!             while let Ok(message) = ws.recv() {
+//!                 if let Message::Text(text) = message
+//!                     && ws.send(Message::Text(text.to_uppercase())).is_err()
+//!                 {
+//!                     break;
+//!                 }
+//!             }
+//!         }),
+//!     );
+//! ```
+
+use crate::request::{Request, Version};
+use crate::response::{Response, Upgraded};
+use crate::router::Params;
+use ring::digest;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+// Sec-WebSocket-Acceptを作るときに鍵の後ろに付ける値 (RFC 6455 1.3)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const DEFAULT_MAX_MESSAGE: usize = 16 * 1024 * 1024;
+const DEFAULT_FRAME_SIZE: usize = 64 * 1024;
+// 制御フレームの本文の上限
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Returns a route handler that accepts WebSocket handshakes and runs
+/// `session` on each connection; see `upgrade`.
+pub fn handler<F>(session: F) -> impl Fn(&Request, &Params) -> Response + Send + Sync + 'static
+where
+    F: Fn(WebSocket<'_>) + Send + Sync + 'static,
+{
+    let session = Arc::new(session);
+    move |request, _| {
+        let session = Arc::clone(&session);
+        upgrade(request, move |ws| session(ws))
+    }
+}
+
+/// ハンドシェイクに応答し、接続を`session`に引き渡します。
+///
+/// Answers the opening handshake in `request` with 101 and runs `session`
+/// on the connection once that response is written; the connection is
+/// closed when `session` returns. A request that is not a valid handshake
+/// is answered with 400, or with 426 and the supported version if it asks
+/// for a version other than 13.
+pub fn upgrade<F>(request: &Request, session: F) -> Response
+where
+    F: FnOnce(WebSocket<'_>) + Send + 'static,
+{
+    let key = match handshake_key(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key))
+        .with_upgrade(move |upgraded| session(WebSocket::new(upgraded)))
+}
+
+/// 受け取ったメッセージをそのまま送り返すセッション。
+///
+/// A session that sends every text and binary message back until the
+/// client closes the connection.
+pub fn echo(mut ws: WebSocket) {
+    while let Ok(message) = ws.recv() {
+        if let Message::Text(_) | Message::Binary(_) = message
+            && ws.send(message).is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Checks that `request` is a WebSocket handshake and returns its key.
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+    let bad_request = |reason: &str| Response::new(400).with_body(reason.to_string());
+    if request.version != Version::Http11
+        || !request.headers.contains_token("connection", "upgrade")
+        || !request.headers.contains_token("upgrade", "websocket")
+    {
+        return Err(bad_request("Expected a WebSocket handshake"));
+    }
+    if request.headers.get("sec-websocket-version") != Some("13") {
+        return Err(Response::new(426)
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_body("Unsupported WebSocket version"));
+    }
+    // 鍵は16バイトの値をbase64にしたものです
+    match request.headers.get("sec-websocket-key").map(str::trim) {
+        Some(key)
+            if key.len() == 24
+                && key.ends_with("==")
+                && key[..22].bytes().all(|b| BASE64.contains(&b)) =>
+        {
+            Ok(key)
+        }
+        _ => Err(bad_request("Invalid Sec-WebSocket-Key")),
+    }
+}
+
+/// The `Sec-WebSocket-Accept` value for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let hash = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, GUID).as_bytes(),
+    );
+    base64(hash.as_ref())
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// WebSocketで失敗した理由。
+///
+/// Why a WebSocket could not be read or written.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer broke the protocol; the connection is closed with 1002.
+    Protocol(&'static str),
+    /// A text message or close reason was not valid UTF-8 (1007).
+    InvalidUtf8,
+    /// A message was larger than the limit (1009).
+    TooLarge,
+    /// The connection has been closed; nothing more can be sent or
+    /// received.
+    Closed,
+}
+
+impl Error {
+    /// The status code to close the connection with.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            Error::Protocol(_) => Some(1002),
+            Error::InvalidUtf8 => Some(1007),
+            Error::TooLarge => Some(1009),
+            Error::Io(_) | Error::Closed => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::InvalidUtf8 => f.write_str("text is not valid UTF-8"),
+            Error::TooLarge => f.write_str("message too large"),
+            Error::Closed => f.write_str("connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// フレームの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// WebSocketのフレーム。
+///
+/// One WebSocket frame. `mask` is the masking key: a frame that was read
+/// keeps the key its payload was unmasked with, and a frame with a key is
+/// masked when it is written, as clients must do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// A final, unmasked frame.
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            mask: None,
+            payload,
+        }
+    }
+
+    /// Reads one frame and unmasks its payload. A frame whose payload is
+    /// longer than `max_payload` is refused before the payload is read.
+    pub fn read_from<R: Read>(r: &mut R, max_payload: usize) -> Result<Frame, Error> {
+        let mut head = [0; 2];
+        r.read_exact(&mut head)?;
+        if head[0] & 0x70 != 0 {
+            return Err(Error::Protocol("reserved bits set"));
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = Opcode::from_bits(head[0] & 0x0F).ok_or(Error::Protocol("unknown opcode"))?;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                r.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                r.read_exact(&mut len)?;
+                let len = u64::from_be_bytes(len);
+                if len >> 63 != 0 {
+                    return Err(Error::Protocol("payload length out of range"));
+                }
+                len
+            }
+            len => len as u64,
+        };
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(Error::Protocol("fragmented or oversized control frame"));
+        }
+        if len > max_payload as u64 {
+            return Err(Error::TooLarge);
+        }
+
+        let mask = if masked {
+            let mut key = [0; 4];
+            r.read_exact(&mut key)?;
+            Some(key)
+        } else {
+            None
+        };
+        let mut payload = vec![0; len as usize];
+        r.read_exact(&mut payload)?;
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            mask,
+            payload,
+        })
+    }
+
+    /// Writes the frame, masking the payload if it has a key.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(14 + len);
+        out.push(if self.fin { 0x80 } else { 0 } | self.opcode.bits());
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        if len < 126 {
+            out.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        let start = out.len();
+        match self.mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start + 4..], key);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        w.write_all(&out)
+    }
+}
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+/// WebSocketのメッセージ。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason, if the closing peer gave them.
+    Close(Option<(u16, String)>),
+}
+
+/// サーバー側のWebSocket接続。
+///
+/// The server's end of a WebSocket connection.
+///
+/// `recv` reassembles fragmented messages and answers pings with pongs by
+/// itself, still returning the ping. When the client closes the
+/// connection, `recv` answers its close frame and returns
+/// `Message::Close`; after that, both `recv` and `send` fail with
+/// `Error::Closed`. If the client breaks the protocol, the connection is
+/// closed with the matching status code and `recv` returns the error.
+pub struct WebSocket<'a> {
+    stream: Upgraded<'a>,
+    max_message: usize,
+    frame_size: usize,
+    /// The opcode and data of a fragmented message still arriving.
+    partial: Option<(Opcode, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(stream: Upgraded<'a>) -> WebSocket<'a> {
+        WebSocket {
+            stream,
+            max_message: DEFAULT_MAX_MESSAGE,
+            frame_size: DEFAULT_FRAME_SIZE,
+            partial: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// The largest message `recv` accepts, 16 MiB by default; larger ones
+    /// close the connection with 1009.
+    pub fn with_max_message_size(mut self, bytes: usize) -> WebSocket<'a> {
+        self.max_message = bytes;
+        self
+    }
+
+    /// Messages longer than `bytes` are sent in several frames; 64 KiB by
+    /// default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero.
+    pub fn with_frame_size(mut self, bytes: usize) -> WebSocket<'a> {
+        assert!(bytes > 0);
+        self.frame_size = bytes;
+        self
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&mut self) -> Result<Message, Error> {
+        if self.close_received || self.close_sent {
+            return Err(Error::Closed);
+        }
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    let _ = self.send_close(code, "");
+                }
+                self.close_received = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Message, Error> {
+        loop {
+            let frame = Frame::read_from(&mut self.stream, self.max_message)?;
+            if frame.mask.is_none() {
+                return Err(Error::Protocol("client frames must be masked"));
+            }
+            match frame.opcode {
+                Opcode::Ping => {
+                    self.write_frame(Frame::new(Opcode::Pong, frame.payload.clone()))?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let close = parse_close(&frame.payload)?;
+                    // 受け取った状態コードをそのまま返して閉じます
+                    if !self.close_sent {
+                        let (code, _) = close.as_ref().map_or((1000, ""), |(c, r)| (*c, r));
+                        self.send_close(code, "")?;
+                    }
+                    self.close_received = true;
+                    return Ok(Message::Close(close));
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.partial.is_some() {
+                        return Err(Error::Protocol("new message before the last one ended"));
+                    }
+                    if frame.fin {
+                        return complete(frame.opcode, frame.payload);
+                    }
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                Opcode::Continuation => {
+                    let Some((_, data)) = &mut self.partial else {
+                        return Err(Error::Protocol("continuation without a message"));
+                    };
+                    if data.len() + frame.payload.len() > self.max_message {
+                        return Err(Error::TooLarge);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = self.partial.take().unwrap();
+                        return complete(opcode, data);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends `message`, splitting text and binary messages into frames of
+    /// the frame size. Sending `Message::Close` starts the closing
+    /// handshake, like `close`, without waiting for the reply.
+    pub fn send(&mut self, message: Message) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(Error::Closed);
+        }
+        match message {
+            Message::Text(text) => self.send_data(Opcode::Text, text.as_bytes()),
+            Message::Binary(data) => self.send_data(Opcode::Binary, &data),
+            Message::Ping(data) => self.send_control(Opcode::Ping, data),
+            Message::Pong(data) => self.send_control(Opcode::Pong, data),
+            Message::Close(None) => self.send_close(1000, ""),
+            Message::Close(Some((code, reason))) => self.send_close(code, &reason),
+        }
+    }
+
+    /// Closes the connection with `code` and `reason`, and waits for the
+    /// client to acknowledge it, discarding any messages still arriving.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        if !self.close_sent {
+            self.send_close(code, reason)?;
+        }
+        while !self.close_received {
+            let frame = Frame::read_from(&mut self.stream, self.max_message)?;
+            self.close_received = frame.opcode == Opcode::Close;
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, opcode: Opcode, data: &[u8]) -> Result<(), Error> {
+        let mut chunks = data.chunks(self.frame_size).peekable();
+        let mut frame_opcode = opcode;
+        // 空のメッセージも、1つのフレームで送ります
+        if chunks.peek().is_none() {
+            return self.write_frame(Frame::new(opcode, Vec::new()));
+        }
+        while let Some(chunk) = chunks.next() {
+            let mut frame = Frame::new(frame_opcode, chunk.to_vec());
+            frame.fin = chunks.peek().is_none();
+            self.write_frame(frame)?;
+            frame_opcode = Opcode::Continuation;
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, opcode: Opcode, data: Vec<u8>) -> Result<(), Error> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Error::TooLarge);
+        }
+        self.write_frame(Frame::new(opcode, data))
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(MAX_CONTROL_PAYLOAD);
+        self.close_sent = true;
+        self.write_frame(Frame::new(Opcode::Close, payload))
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        frame.write_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+fn complete(opcode: Opcode, data: Vec<u8>) -> Result<Message, Error> {
+    match opcode {
+        Opcode::Text => String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| Error::InvalidUtf8),
+        _ => Ok(Message::Binary(data)),
+    }
+}
+
+/// Reads the status code and reason of a close frame.
+fn parse_close(payload: &[u8]) -> Result<Option<(u16, String)>, Error> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(Error::Protocol("close frame with a one-byte payload")),
+        [high, low, reason @ ..] => {
+            let code = u16::from_be_bytes([*high, *low]);
+            // 送ってはいけない状態コード (RFC 6455 7.4)
+            if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+                return Err(Error::Protocol("invalid close code"));
+            }
+            let reason = String::from_utf8(reason.to_vec()).map_err(|_| Error::InvalidUtf8)?;
+            Ok(Some((code, reason)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+    use crate::router::Router;
+    use crate::server::{KeepAlive, Server};
+    use std::io::BufRead;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start(event_loop: bool) -> (SocketAddr, crate::server::ShutdownHandle) {
+        start_with_keep_alive(event_loop, KeepAlive::default())
+    }
+
+    fn start_with_keep_alive(
+        event_loop: bool,
+        keep_alive: KeepAlive,
+    ) -> (SocketAddr, crate::server::ShutdownHandle) {
+        let router = Router::new().get("/echo", handler(echo));
+        let mut server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            ThreadPool::new(2),
+            router,
+        )
+        .with_keep_alive(keep_alive);
+        #[cfg(target_os = "linux")]
+        if event_loop {
+            server = server.with_event_loop(1);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = event_loop;
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        thread::spawn(move || server.run());
+        (addr, handle)
+    }
+
+    /// Performs the handshake, sending `first` right behind it, and
+    /// returns the connection with the response head read.
+    fn connect(addr: SocketAddr, first: &[u8]) -> io::BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut out = b"GET /echo HTTP/1.1\r\nHost: t\r\nUpgrade: websocket\r\n\
+                        Connection: keep-alive, Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        out.extend_from_slice(first);
+        stream.write_all(&out).unwrap();
+
+        let mut reader = io::BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0);
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        reader
+    }
+
+    fn masked(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let frame = Frame {
+            fin,
+            opcode,
+            mask: Some([0x37, 0xfa, 0x21, 0x3d]),
+            payload: payload.to_vec(),
+        };
+        let mut out = Vec::new();
+        frame.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn next_frame(reader: &mut io::BufReader<TcpStream>) -> Frame {
+        let frame = Frame::read_from(reader, usize::MAX).unwrap();
+        assert_eq!(frame.mask, None);
+        frame
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // RFC 6455 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let frame = Frame {
+                fin: len != 125,
+                opcode: Opcode::Binary,
+                mask: Some([1, 2, 3, 4]),
+                payload: (0..len).map(|i| i as u8).collect(),
+            };
+            let mut out = Vec::new();
+            frame.write_to(&mut out).unwrap();
+            assert_eq!(
+                Frame::read_from(&mut out.as_slice(), usize::MAX).unwrap(),
+                frame
+            );
+        }
+
+        let mut oversized = Vec::new();
+        Frame::new(Opcode::Text, vec![b'x'; 200])
+            .write_to(&mut oversized)
+            .unwrap();
+        assert!(matches!(
+            Frame::read_from(&mut oversized.as_slice(), 100),
+            Err(Error::TooLarge)
+        ));
+        assert!(matches!(
+            Frame::read_from(&mut [0xC1, 0x00].as_slice(), 100),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let (addr, handle) = start(false);
+        let ask = |extra: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "GET /echo HTTP/1.1\r\nHost: t\r\nConnection: close\r\n{}\r\n",
+                extra
+            )
+            .unwrap();
+            let mut out = String::new();
+            stream.read_to_string(&mut out).unwrap();
+            out
+        };
+        assert!(ask("").starts_with("HTTP/1.1 400 "));
+        let old = ask("Connection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 8\r\n");
+        assert!(old.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(old.contains("Sec-WebSocket-Version: 13\r\n"));
+        handle.shutdown();
+    }
+
+    fn echoes_messages(event_loop: bool) {
+        let (addr, handle) = start(event_loop);
+
+        // The first frame arrives together with the handshake.
+        let mut reader = connect(addr, &masked(true, Opcode::Text, b"hello"));
+        let frame = next_frame(&mut reader);
+        assert_eq!(
+            (frame.opcode, frame.payload.as_slice()),
+            (Opcode::Text, &b"hello"[..])
+        );
+
+        // A fragmented message with a ping in the middle of it.
+        let stream = reader.get_mut();
+        stream
+            .write_all(&masked(false, Opcode::Binary, b"frag"))
+            .unwrap();
+        stream
+            .write_all(&masked(true, Opcode::Ping, b"are you there"))
+            .unwrap();
+        stream
+            .write_all(&masked(false, Opcode::Continuation, b"men"))
+            .unwrap();
+        stream
+            .write_all(&masked(true, Opcode::Continuation, b"ted"))
+            .unwrap();
+        let pong = next_frame(&mut reader);
+        assert_eq!(
+            (pong.opcode, pong.payload.as_slice()),
+            (Opcode::Pong, &b"are you there"[..])
+        );
+        let echoed = next_frame(&mut reader);
+        assert_eq!(echoed.opcode, Opcode::Binary);
+        assert_eq!(echoed.payload, b"fragmented");
+
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        reader
+            .get_mut()
+            .write_all(&masked(true, Opcode::Close, &close))
+            .unwrap();
+        let reply = next_frame(&mut reader);
+        assert_eq!(
+            (reply.opcode, reply.payload.as_slice()),
+            (Opcode::Close, &[0x03, 0xe8][..])
+        );
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn echoes_messages_on_a_worker() {
+        echoes_messages(false);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn echoes_messages_from_the_event_loop() {
+        echoes_messages(true);
+    }
+
+    fn drops_idle_sessions(event_loop: bool) {
+        let keep_alive = KeepAlive {
+            idle_timeout: Duration::from_millis(200),
+            ..KeepAlive::default()
+        };
+        let (addr, handle) = start_with_keep_alive(event_loop, keep_alive);
+
+        let mut reader = connect(addr, b"");
+        let started = Instant::now();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn drops_idle_sessions_on_a_worker() {
+        drops_idle_sessions(false);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn drops_idle_sessions_from_the_event_loop() {
+        drops_idle_sessions(true);
+    }
+
+    #[test]
+    fn closes_on_protocol_errors() {
+        let (addr, handle) = start(false);
+
+        // Frames from clients must be masked.
+        let mut unmasked = Vec::new();
+        Frame::new(Opcode::Text, b"hi".to_vec())
+            .write_to(&mut unmasked)
+            .unwrap();
+        let mut reader = connect(addr, &unmasked);
+        let close = next_frame(&mut reader);
+        assert_eq!(
+            (close.opcode, close.payload.as_slice()),
+            (Opcode::Close, &[0x03, 0xea][..])
+        );
+
+        let mut reader = connect(addr, &masked(true, Opcode::Text, &[0xff, 0xfe]));
+        let close = next_frame(&mut reader);
+        assert_eq!(close.payload, 1007u16.to_be_bytes());
+
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,207 @@
+use hello::ThreadPool;
+use hello::pages::Pages;
+use hello::router::Router;
+use hello::server::Server;
+use hello::testing::{self, TestServer};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Serves the sample pages from the crate's directory, with `/sleep`
+/// waiting `sleep`, on a pool of `workers`.
+fn pages(workers: usize, sleep: Duration) -> TestServer {
+    let router = Pages::new(env!("CARGO_MANIFEST_DIR"))
+        .with_sleep(sleep)
+        .routes(Router::new());
+    TestServer::run(Server::new(
+        testing::listener(),
+        ThreadPool::new(workers),
+        router,
+    ))
+}
+
+#[test]
+fn root_serves_the_hello_page() {
+    let server = pages(2, Duration::ZERO);
+    server
+        .get("/")
+        .assert_status(200)
+        .assert_header("content-type", "text/html; charset=utf-8")
+        .assert_body_contains("<h1>Hello!</h1>");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn unknown_paths_get_the_404_page() {
+    let server = pages(2, Duration::ZERO);
+    server
+        .get("/no/such/page")
+        .assert_status(404)
+        .assert_body_contains("<h1>Oops!</h1>")
+        .assert_body_contains("<code>GET /no/such/page</code>")
+        .assert_body_contains(hello::SERVER_SOFTWARE);
+
+    let response = server.client().request("POST", "/", &[], b"x").unwrap();
+    response
+        .assert_status(405)
+        .assert_header("allow", "GET, HEAD");
+}
+
+#[test]
+fn connections_are_kept_alive() {
+    let server = pages(2, Duration::ZERO);
+    let mut client = server.client();
+    for _ in 0..3 {
+        client
+            .get("/")
+            .unwrap()
+            .assert_status(200)
+            .assert_no_header("connection");
+    }
+    let head = client.request("HEAD", "/", &[], b"").unwrap();
+    head.assert_status(200).assert_body("");
+    let last = client
+        .request("GET", "/", &[("Connection", "close")], b"")
+        .unwrap();
+    last.assert_header("connection", "close");
+    assert!(client.is_closed(Duration::from_secs(5)));
+}
+
+#[test]
+fn head_errors_keep_the_connection_in_sync() {
+    let server = pages(2, Duration::ZERO);
+    let mut client = server.client();
+    // 404の本文が送られると、次の応答の先頭として読まれてしまいます
+    let missing = client.request("HEAD", "/no/such/page", &[], b"").unwrap();
+    missing.assert_status(404).assert_body("");
+    assert!(
+        missing
+            .headers
+            .get("content-length")
+            .is_some_and(|len| len != "0")
+    );
+    client
+        .get("/")
+        .unwrap()
+        .assert_status(200)
+        .assert_body_contains("<h1>Hello!</h1>");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn unknown_methods_share_one_metrics_series() {
+    let server = pages(2, Duration::ZERO);
+    let mut client = server.client();
+    for method in ["FOO1", "FOO2"] {
+        client
+            .request(method, "/", &[], b"")
+            .unwrap()
+            .assert_status(405);
+    }
+    let metrics = client.get(hello::server::METRICS_PATH).unwrap();
+    let text = metrics.text();
+    let series: Vec<&str> = text
+        .lines()
+        .filter(|line| line.starts_with("http_requests_total{"))
+        .collect();
+    assert_eq!(
+        series,
+        ["http_requests_total{method=\"OTHER\",status=\"405\"} 2"]
+    );
+    assert!(!text.contains("FOO"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn sleeping_requests_run_concurrently() {
+    let sleep = Duration::from_millis(500);
+    let server = pages(4, sleep);
+    let started = Instant::now();
+
+    // 1つずつ処理すると2秒かかります
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            let mut client = server.client();
+            thread::spawn(move || client.get("/sleep").unwrap())
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap().assert_status(200);
+    }
+
+    let elapsed = started.elapsed();
+    assert!(elapsed >= sleep);
+    assert!(elapsed < sleep * 3, "took {:?}", elapsed);
+}
+
+#[test]
+fn a_sleeping_request_does_not_block_others() {
+    let server = pages(2, Duration::from_secs(2));
+    let mut sleeper = server.client();
+    let sleeping = thread::spawn(move || sleeper.get("/sleep").unwrap());
+    thread::sleep(Duration::from_millis(50));
+
+    let started = Instant::now();
+    server.get("/").assert_status(200);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    sleeping.join().unwrap().assert_status(200);
+}
+
+#[test]
+fn malformed_requests_are_rejected() {
+    let server = pages(2, Duration::ZERO);
+    let cases: [(&[u8], u16); 5] = [
+        (b"NONSENSE\r\n\r\n", 400),
+        (b"GET / HTTP/1.1\r\nno colon here\r\n\r\n", 400),
+        (
+            b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: ten\r\n\r\n",
+            400,
+        ),
+        (b"GET / HTTP/3.0\r\n\r\n", 505),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999\r\n\r\n",
+            413,
+        ),
+    ];
+    for (request, status) in cases {
+        let mut client = server.client();
+        client
+            .send_raw(request)
+            .unwrap()
+            .assert_status(status)
+            .assert_header("connection", "close");
+        assert!(client.is_closed(Duration::from_secs(5)));
+    }
+
+    // 壊れたリクエストの後も、サーバーは応答し続けます
+    server.get("/").assert_status(200);
+}
+
+#[test]
+fn shutdown_finishes_requests_in_flight() {
+    let server = pages(2, Duration::from_millis(300));
+    let addr = server.addr();
+    let mut client = server.client();
+    let in_flight = thread::spawn(move || client.get("/sleep").unwrap());
+    thread::sleep(Duration::from_millis(50));
+
+    server.shutdown().unwrap();
+    in_flight.join().unwrap().assert_status(200);
+    assert!(testing::Client::connect(addr).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_serves_the_same_pages() {
+    let router = Pages::new(env!("CARGO_MANIFEST_DIR")).routes(Router::new());
+    let server = TestServer::run(
+        Server::new(testing::listener(), ThreadPool::new(2), router).with_event_loop(1),
+    );
+    let mut client = server.client();
+    client.get("/").unwrap().assert_status(200);
+    client.get("/missing").unwrap().assert_status(404);
+    client
+        .send_raw(b"NONSENSE\r\n\r\n")
+        .unwrap()
+        .assert_status(400);
+    server.shutdown().unwrap();
+}